@@ -1,15 +1,20 @@
 use anyhow::Result;
 use dualshock_sys::{
-    input::InputReport, ConnectionType, DS4_REPORT_RATE, HID_PRODUCT_ID_NEW, HID_PRODUCT_ID_OLD,
-    HID_VENDOR_ID,
+    input::InputReport, ConnectionType, DS4_REPORT_DT, DS4_REPORT_RATE, HID_PRODUCT_ID_NEW,
+    HID_PRODUCT_ID_OLD, HID_VENDOR_ID,
+};
+use hid_gamepad_sys::{
+    GamepadDevice, GamepadDriver, JoyKey, KeyStatus, Motion, OrientationFilter, Report, StickFilter,
 };
-use hid_gamepad_sys::{GamepadDevice, GamepadDriver, JoyKey, KeyStatus, Motion, Report};
 use hidapi::{HidApi, HidDevice};
 
 pub struct DS4Driver;
 
 pub struct DS4 {
     device: HidDevice,
+    orientation: OrientationFilter,
+    left_stick: StickFilter,
+    right_stick: StickFilter,
 }
 
 impl GamepadDriver for DS4Driver {
@@ -23,6 +28,9 @@ impl GamepadDriver for DS4Driver {
         {
             Ok(Some(Box::new(DS4 {
                 device: device_info.open_device(api)?,
+                orientation: OrientationFilter::new(),
+                left_stick: StickFilter::new(),
+                right_stick: StickFilter::new(),
             })))
         } else {
             Ok(None)
@@ -41,12 +49,16 @@ impl GamepadDevice for DS4 {
         };
         let b = &full.base.buttons;
         let rot = full.gyro.normalize();
+        let accel = full.accel.normalize();
+        let orientation = self.orientation.update(rot, accel, DS4_REPORT_DT);
         Ok(Report {
-            left_joystick: full.base.left_stick.normalize(),
-            right_joystick: full.base.right_stick.normalize(),
+            left_joystick: self.left_stick.apply(full.base.left_stick.normalize()),
+            right_joystick: self.right_stick.apply(full.base.right_stick.normalize()),
             motion: vec![Motion {
-                acceleration: full.accel.normalize().into(),
+                acceleration: accel.into(),
                 rotation_speed: rot.into(),
+                linear_acceleration: self.orientation.linear_acceleration(accel).into(),
+                orientation,
             }],
             keys: enum_map::enum_map! {
                 JoyKey::Up => b.dpad().up().into(),
@@ -74,6 +86,11 @@ impl GamepadDevice for DS4 {
         })
     }
 
+    fn set_stick_filters(&mut self, left: StickFilter, right: StickFilter) {
+        self.left_stick = left;
+        self.right_stick = right;
+    }
+
     fn as_any(&mut self) -> &mut dyn std::any::Any {
         self
     }
@@ -1,10 +1,11 @@
 use std::time::{Duration, Instant};
 
-use cgmath::{Deg, Euler, One, Quaternion};
+use cgmath::{Deg, Euler};
 use dualshock_sys::{
     input::InputReport, ConnectionType, DS4_REPORT_DT, HID_PRODUCT_ID_NEW, HID_PRODUCT_ID_OLD,
     HID_VENDOR_ID,
 };
+use hid_gamepad_sys::OrientationFilter;
 
 fn main() -> anyhow::Result<()> {
     let hidapi = hidapi::HidApi::new()?;
@@ -24,29 +25,18 @@ fn main() -> anyhow::Result<()> {
     let conn_type = InputReport::conn_type(nb_read);
 
     let mut now = Instant::now();
-    let mut orientation = Quaternion::one();
+    let mut filter = OrientationFilter::new();
     loop {
         let mut report = InputReport::new();
         let buffer = report.as_bytes_mut();
         let _nb_read = device.read(buffer)?;
-        let gyro_speed = match conn_type {
-            ConnectionType::Bluetooth => {
-                let report = report.bt_full().unwrap();
-                report.full.gyro.normalize()
-            }
-            ConnectionType::USB => {
-                let report = report.usb_full().unwrap();
-                report.full.gyro.normalize()
-            }
+        let full = match conn_type {
+            ConnectionType::Bluetooth => &report.bt_full().unwrap().full,
+            ConnectionType::USB => &report.usb_full().unwrap().full,
         };
 
-        let delta = Euler::new(
-            Deg(gyro_speed.x * DS4_REPORT_DT),
-            Deg(gyro_speed.y * DS4_REPORT_DT),
-            Deg(gyro_speed.z * DS4_REPORT_DT),
-        );
-
-        orientation = orientation * Quaternion::from(delta);
+        let orientation =
+            filter.update(full.gyro.normalize(), full.accel.normalize(), DS4_REPORT_DT);
         if now.elapsed() > Duration::from_millis(500) {
             let rot = Euler::from(orientation);
             dbg!(Deg::from(rot.x));
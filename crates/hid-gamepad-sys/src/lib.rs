@@ -10,5 +10,7 @@ pub trait GamepadDriver {
 
 pub trait GamepadDevice {
     fn recv(&mut self) -> anyhow::Result<Report>;
+    /// Processing applied to the left and right sticks of the next reports.
+    fn set_stick_filters(&mut self, left: StickFilter, right: StickFilter);
     fn as_any(&mut self) -> &mut dyn std::any::Any;
 }
@@ -1,7 +1,12 @@
+mod orientation;
+mod stick;
+
 use std::{ops::Mul, time::Duration};
 
-use cgmath::{vec3, Deg, Euler, Vector2, Vector3};
+use cgmath::{vec3, Deg, Euler, Quaternion, Vector2, Vector3};
 use enum_map::{Enum, EnumMap};
+pub use orientation::*;
+pub use stick::*;
 
 #[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum JoyKey {
@@ -62,6 +67,10 @@ pub struct Report {
 pub struct Motion {
     pub rotation_speed: RotationSpeed,
     pub acceleration: Acceleration,
+    /// Acceleration without the gravity.
+    pub linear_acceleration: Acceleration,
+    /// Uses the same axes as `rotation_speed`, see `OrientationFilter`.
+    pub orientation: Quaternion<f64>,
 }
 
 /// Uses the SDL convention.
@@ -0,0 +1,135 @@
+use cgmath::*;
+
+/// Orientation estimate from the gyroscope and the accelerometer.
+///
+/// Mahony complementary filter: the rotation speed is integrated, and the
+/// drift is corrected by pulling the estimated gravity towards the measured
+/// acceleration. `kp` sets how fast the tilt converges to the accelerometer
+/// and `ki` how fast a constant gyroscope bias is compensated. The yaw can't
+/// be observed by the accelerometer, so it slowly drifts.
+///
+/// The orientation rotates vectors from the controller frame to a world
+/// frame where +z is up.
+#[derive(Debug, Clone, Copy)]
+pub struct OrientationFilter {
+    kp: f64,
+    ki: f64,
+    orientation: Quaternion<f64>,
+    integral_error: Vector3<f64>,
+    initialized: bool,
+}
+
+impl OrientationFilter {
+    pub const DEFAULT_KP: f64 = 0.5;
+    pub const DEFAULT_KI: f64 = 0.;
+
+    pub fn new() -> OrientationFilter {
+        OrientationFilter::with_gains(Self::DEFAULT_KP, Self::DEFAULT_KI)
+    }
+
+    pub fn with_gains(kp: f64, ki: f64) -> OrientationFilter {
+        OrientationFilter {
+            kp,
+            ki,
+            orientation: Quaternion::one(),
+            integral_error: Vector3::zero(),
+            initialized: false,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f64, ki: f64) {
+        self.kp = kp;
+        self.ki = ki;
+    }
+
+    /// Adds a sample, with `gyro` in degrees per second, `accel` in g and
+    /// `dt` in seconds.
+    pub fn update(&mut self, gyro: Vector3<f64>, accel: Vector3<f64>, dt: f64) -> Quaternion<f64> {
+        let norm = accel.magnitude();
+        if !self.initialized && norm > 0. {
+            // Start from the measured tilt instead of slowly converging to it
+            self.orientation = Quaternion::from_arc(accel / norm, Vector3::unit_z(), None);
+            self.initialized = true;
+            return self.orientation;
+        }
+
+        let mut omega = gyro.map(f64::to_radians);
+        // No information on the tilt in free fall
+        if norm > 0. {
+            let error = (accel / norm).cross(self.gravity());
+            if self.ki > 0. {
+                self.integral_error += error * self.ki * dt;
+                omega += self.integral_error;
+            }
+            omega += error * self.kp;
+        }
+        let q = self.orientation;
+        self.orientation = (q + q * Quaternion::from_sv(0., omega) * (0.5 * dt)).normalize();
+        self.orientation
+    }
+
+    pub fn orientation(&self) -> Quaternion<f64> {
+        self.orientation
+    }
+
+    /// Unit vector of the acceleration measured at rest, in the controller
+    /// frame.
+    pub fn gravity(&self) -> Vector3<f64> {
+        self.orientation
+            .conjugate()
+            .rotate_vector(Vector3::unit_z())
+    }
+
+    /// `accel` without the gravity, in g.
+    pub fn linear_acceleration(&self, accel: Vector3<f64>) -> Vector3<f64> {
+        accel - self.gravity()
+    }
+
+    /// Sets the current heading as the zero yaw, keeping the tilt.
+    pub fn reset_yaw(&mut self) {
+        let q = self.orientation;
+        let yaw =
+            (2. * (q.s * q.v.z + q.v.x * q.v.y)).atan2(1. - 2. * (q.v.y * q.v.y + q.v.z * q.v.z));
+        self.orientation = (Quaternion::from_angle_z(Rad(-yaw)) * q).normalize();
+    }
+
+    pub fn reset(&mut self) {
+        *self = OrientationFilter::with_gains(self.kp, self.ki);
+    }
+}
+
+impl Default for OrientationFilter {
+    fn default() -> Self {
+        OrientationFilter::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn converges_to_tilt() {
+    let mut filter = OrientationFilter::new();
+    // Wrong initial estimate
+    filter.update(Vector3::zero(), Vector3::unit_z(), 0.005);
+    let accel = vec3(0., 1., 1.).normalize();
+    for _ in 0..5000 {
+        filter.update(Vector3::zero(), accel, 0.005);
+    }
+    assert!(filter.gravity().angle(accel) < Deg(1.).into());
+    assert!(filter.linear_acceleration(accel).magnitude() < 0.02);
+}
+
+#[cfg(test)]
+#[test]
+fn integrates_yaw() {
+    let mut filter = OrientationFilter::new();
+    filter.update(Vector3::zero(), Vector3::unit_z(), 0.005);
+    for _ in 0..200 {
+        filter.update(vec3(0., 0., 90.), Vector3::unit_z(), 0.005);
+    }
+    let heading = filter.orientation().rotate_vector(Vector3::unit_x());
+    assert!(heading.angle(Vector3::unit_y()) < Deg(1.).into());
+
+    filter.reset_yaw();
+    let heading = filter.orientation().rotate_vector(Vector3::unit_x());
+    assert!(heading.angle(Vector3::unit_x()) < Deg(0.1).into());
+}
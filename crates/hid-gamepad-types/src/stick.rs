@@ -0,0 +1,102 @@
+use cgmath::*;
+
+/// How the deadzone is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadzoneShape {
+    /// Circle around the center, best to keep the direction.
+    Radial,
+    /// Band along each axis, for inputs that should snap to an axis.
+    Axial,
+}
+
+/// Processing of a calibrated stick position.
+///
+/// Distances are relative to the full range of the stick, from 0 at the
+/// center to 1 at the edge. The default settings don't change the position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StickFilter {
+    pub shape: DeadzoneShape,
+    /// Positions closer to the center are reported as the center.
+    pub deadzone: f64,
+    /// Smallest output out of the deadzone, to compensate the deadzone of
+    /// a game.
+    pub anti_deadzone: f64,
+    /// Positions further than this are reported as the edge.
+    pub outer_threshold: f64,
+    /// Response curve, 1 is linear and higher values give more precision
+    /// near the center.
+    pub exponent: f64,
+}
+
+impl StickFilter {
+    pub fn new() -> StickFilter {
+        StickFilter {
+            shape: DeadzoneShape::Radial,
+            deadzone: 0.,
+            anti_deadzone: 0.,
+            outer_threshold: 1.,
+            exponent: 1.,
+        }
+    }
+
+    pub fn apply(&self, position: Vector2<f64>) -> Vector2<f64> {
+        match self.shape {
+            DeadzoneShape::Radial => {
+                let distance = position.magnitude();
+                if distance > 0. {
+                    position * (self.response(distance) / distance)
+                } else {
+                    position
+                }
+            }
+            DeadzoneShape::Axial => position.map(|x| self.response(x.abs()).copysign(x)),
+        }
+    }
+
+    /// Output distance from the center for an input distance.
+    fn response(&self, distance: f64) -> f64 {
+        if distance <= self.deadzone {
+            return 0.;
+        }
+        let range = self.outer_threshold - self.deadzone;
+        let scaled = if range > 0. {
+            ((distance - self.deadzone) / range).min(1.)
+        } else {
+            1.
+        };
+        self.anti_deadzone + (1. - self.anti_deadzone) * scaled.powf(self.exponent)
+    }
+}
+
+impl Default for StickFilter {
+    fn default() -> Self {
+        StickFilter::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn stick_filter() {
+    let close = |a: Vector2<f64>, b: Vector2<f64>| (a - b).magnitude() < 1e-9;
+    let position = vec2(0.3, -0.4);
+    assert!(close(StickFilter::new().apply(position), position));
+
+    let filter = StickFilter {
+        deadzone: 0.1,
+        outer_threshold: 0.9,
+        ..StickFilter::new()
+    };
+    assert!(close(filter.apply(vec2(0.05, 0.05)), vec2(0., 0.)));
+    assert!(close(filter.apply(vec2(0., -0.5)), vec2(0., -0.5)));
+    assert!(close(filter.apply(vec2(0.95, 0.)), vec2(1., 0.)));
+
+    let filter = StickFilter {
+        shape: DeadzoneShape::Axial,
+        deadzone: 0.1,
+        anti_deadzone: 0.2,
+        exponent: 2.,
+        ..StickFilter::new()
+    };
+    let out = filter.apply(vec2(0.05, -0.55));
+    assert!(close(out, vec2(0., -(0.2 + 0.8 * 0.25))));
+}
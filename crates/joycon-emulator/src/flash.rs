@@ -0,0 +1,172 @@
+//! Emulated SPI flash.
+//!
+//! <https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering/blob/master/spi_flash_notes.md>
+
+use anyhow::{ensure, Context, Result};
+use joycon_sys::{
+    input::WhichController,
+    spi::{
+        LeftStickCalibration, RightStickCalibration, SPIWriteRequest, SticksCalibration,
+        SPI_FLASH_SIZE,
+    },
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const FLASH_SIZE: usize = SPI_FLASH_SIZE as usize;
+
+/// 512KB flash image, optionally backed by a file.
+///
+/// Writes to a file-backed image are persisted immediately.
+pub struct Flash {
+    data: Vec<u8>,
+    file: Option<File>,
+}
+
+impl Flash {
+    /// Wraps an in-memory flash image.
+    pub fn new(data: Vec<u8>) -> Result<Flash> {
+        ensure!(
+            data.len() == FLASH_SIZE,
+            "invalid flash size 0x{:x}, expected 0x{:x}",
+            data.len(),
+            FLASH_SIZE
+        );
+        Ok(Flash { data, file: None })
+    }
+
+    /// Flash content of a controller fresh from the factory, without any
+    /// user calibration.
+    pub fn factory(device_type: WhichController) -> Flash {
+        let mut data = vec![0xff; FLASH_SIZE];
+        let (serial, body, buttons): (&[u8], _, _) = match device_type {
+            WhichController::LeftJoyCon => (b"XEW70000000001", 0x0ab9e6, 0x001e1e),
+            WhichController::RightJoyCon => (b"XEW70000000002", 0xff3c28, 0x1e0a0a),
+            WhichController::ProController => (b"XEW70000000003", 0x323232, 0xffffff),
+        };
+        data[0x6000..0x6010].copy_from_slice(&[0; 16]);
+        data[0x6000..0x6000 + serial.len()].copy_from_slice(serial);
+        data[0x6012] = device_type as u8;
+        data[0x601b] = if device_type == WhichController::ProController {
+            1
+        } else {
+            0
+        };
+
+        // Sensor calibration: zero offsets, default sensitivities.
+        let mut sensor = Vec::with_capacity(24);
+        for &v in &[
+            0, 0, 0, 0x4000, 0x4000, 0x4000, 0, 0, 0, 0x343b, 0x343b, 0x343b,
+        ] {
+            sensor.extend_from_slice(&u16::to_le_bytes(v));
+        }
+        data[0x6020..0x6038].copy_from_slice(&sensor);
+
+        // Stick calibration, centered with a symmetrical range.
+        let (min, center, max) = ((0x200, 0x200), (0x800, 0x800), (0xe00, 0xe00));
+        write_request(
+            &mut data,
+            SticksCalibration {
+                left: LeftStickCalibration::new(min, center, max),
+                right: RightStickCalibration::new(min, center, max),
+            }
+            .into(),
+        );
+
+        data[0x6050..0x6053].copy_from_slice(&u32::to_be_bytes(body)[1..]);
+        data[0x6053..0x6056].copy_from_slice(&u32::to_be_bytes(buttons)[1..]);
+        data[0x6056..0x6059].copy_from_slice(&u32::to_be_bytes(buttons)[1..]);
+        data[0x6059..0x605c].copy_from_slice(&u32::to_be_bytes(buttons)[1..]);
+
+        // 6-axis horizontal offsets and stick parameters.
+        data[0x6080..0x6086].copy_from_slice(&[0x50, 0xfd, 0x00, 0x00, 0xc6, 0x0f]);
+        let stick_params = [
+            0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14, 0x54, 0x41, 0x15, 0x54, 0xc7, 0x79,
+            0x9c, 0x33, 0x36, 0x63,
+        ];
+        data[0x6086..0x6098].copy_from_slice(&stick_params);
+        data[0x6098..0x60aa].copy_from_slice(&stick_params);
+
+        Flash { data, file: None }
+    }
+
+    /// Uses the flash image stored at `path`.
+    ///
+    /// If the file doesn't exist, it is created with the factory content
+    /// for `device_type`.
+    pub fn open(path: impl AsRef<Path>, device_type: WhichController) -> Result<Flash> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("opening flash image {}", path.display()))?;
+        let mut data = Vec::with_capacity(FLASH_SIZE);
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            data = Flash::factory(device_type).data;
+            file.write_all(&data)?;
+        }
+        let mut flash = Flash::new(data).with_context(|| path.display().to_string())?;
+        flash.file = Some(file);
+        Ok(flash)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn read(&self, offset: u32, size: u8) -> Option<&[u8]> {
+        let start = offset as usize;
+        self.data.get(start..start + size as usize)
+    }
+
+    /// Returns `false` if the range is out of the flash.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<bool> {
+        let start = offset as usize;
+        let dest = match self.data.get_mut(start..start + data.len()) {
+            Some(dest) => dest,
+            None => return Ok(false),
+        };
+        dest.copy_from_slice(data);
+        if let Some(ref mut file) = self.file {
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(data)?;
+            file.flush()?;
+        }
+        Ok(true)
+    }
+}
+
+fn write_request(data: &mut [u8], request: SPIWriteRequest) {
+    let start = request.range().offset() as usize;
+    data[start..start + request.data().len()].copy_from_slice(request.data());
+}
+
+#[cfg(test)]
+#[test]
+fn factory_calibration() {
+    use joycon_sys::spi::{LeftStickCalibration, SensorCalibration};
+
+    let flash = Flash::factory(WhichController::LeftJoyCon);
+    let raw = flash.read(0x603d, 9).unwrap();
+    let mut calib = LeftStickCalibration::default();
+    unsafe {
+        std::slice::from_raw_parts_mut(&mut calib as *mut _ as *mut u8, 9).copy_from_slice(raw);
+    }
+    assert_eq!((0x800, 0x800), calib.center());
+    assert_eq!((0x200, 0x200), calib.min());
+    assert_eq!((0xe00, 0xe00), calib.max());
+
+    let raw = flash.read(0x6020, 24).unwrap();
+    let mut sensor = SensorCalibration::reset();
+    unsafe {
+        std::slice::from_raw_parts_mut(&mut sensor as *mut _ as *mut u8, 24).copy_from_slice(raw);
+    }
+    assert_eq!(0x4000 as f64, sensor.acc_factor().x);
+}
@@ -0,0 +1,554 @@
+//! Virtual JoyCon and Pro Controller.
+//!
+//! The emulator consumes the output reports sent by the host and produces the
+//! input reports a real controller would send, with the same pacing. It
+//! allows testing the `joycon` crate and `joytk` without any hardware.
+//!
+//! ```no_run
+//! use joycon_emulator::Emulator;
+//! use joycon_sys::input::WhichController;
+//!
+//! let mut emulator = Emulator::new(WhichController::ProController);
+//! emulator.state_mut().buttons.right.set_a(true);
+//! let mut buf = [0; 362];
+//! emulator.read_timeout(&mut buf, 100).unwrap();
+//! ```
+
+use anyhow::Result;
+use cgmath::{vec3, Vector3};
+use joycon_sys::{
+    accessory::AccessoryResponse, common::*, imu, input::*, mcu::nfc::NTAG215_SIZE, output::*,
+    spi::*,
+};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+pub use flash::Flash;
+
+mod flash;
+mod mcu;
+
+/// Time between two input reports in the standard modes.
+pub const REPORT_PERIOD: Duration = Duration::from_millis(15);
+
+const FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion([0x04, 0x07]);
+
+/// Physical state of the emulated controller.
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerState {
+    pub buttons: ButtonsStatus,
+    /// Raw 12 bits values.
+    pub left_stick: (u16, u16),
+    /// Raw 12 bits values.
+    pub right_stick: (u16, u16),
+    /// Raw accelerometer value at the default sensitivity, scaled to the
+    /// configured one in each IMU frame.
+    pub accel: Vector3<f64>,
+    /// Raw gyroscope value at the default sensitivity, scaled to the
+    /// configured one in each IMU frame.
+    pub gyro: Vector3<f64>,
+    pub battery: BatteryLevel,
+    pub charging: bool,
+}
+
+impl Default for ControllerState {
+    fn default() -> Self {
+        ControllerState {
+            buttons: ButtonsStatus::default(),
+            left_stick: (0x800, 0x800),
+            right_stick: (0x800, 0x800),
+            // Lying flat on a table
+            accel: vec3(0., 0., 4096.),
+            gyro: vec3(0., 0., 0.),
+            battery: BatteryLevel::Full,
+            charging: false,
+        }
+    }
+}
+
+pub struct Emulator {
+    device_type: WhichController,
+    mac_address: MACAddress,
+    flash: Flash,
+    state: ControllerState,
+    state_changed: bool,
+    report_mode: InputReportId,
+    imu_mode: imu::IMUMode,
+    imu_sensitivity: Option<imu::Sensitivity>,
+    vibration: bool,
+    mcu: mcu::MCUState,
+    replies: VecDeque<SubcommandReply>,
+    realtime: bool,
+    next_slot: Instant,
+    timer: u8,
+}
+
+impl Emulator {
+    pub fn new(device_type: WhichController) -> Emulator {
+        Emulator::with_flash(device_type, Flash::factory(device_type))
+    }
+
+    pub fn with_flash(device_type: WhichController, flash: Flash) -> Emulator {
+        Emulator {
+            device_type,
+            mac_address: MACAddress([0x98, 0xb6, 0xe9, 0x00, 0x00, device_type as u8]),
+            flash,
+            state: ControllerState::default(),
+            state_changed: false,
+            report_mode: InputReportId::Normal,
+            imu_mode: imu::IMUMode::Disabled,
+            imu_sensitivity: None,
+            vibration: false,
+            mcu: mcu::MCUState::new(),
+            replies: VecDeque::new(),
+            realtime: true,
+            next_slot: Instant::now(),
+            timer: 0,
+        }
+    }
+
+    /// When `false`, reports are generated as fast as they are read instead
+    /// of every `REPORT_PERIOD`.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+        self.next_slot = Instant::now();
+    }
+
+    pub fn device_type(&self) -> WhichController {
+        self.device_type
+    }
+
+    pub fn flash(&self) -> &Flash {
+        &self.flash
+    }
+
+    pub fn state(&self) -> &ControllerState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut ControllerState {
+        self.state_changed = true;
+        &mut self.state
+    }
+
+    pub fn imu_sensitivity(&self) -> Option<imu::Sensitivity> {
+        self.imu_sensitivity
+    }
+
+    pub fn vibration_enabled(&self) -> bool {
+        self.vibration
+    }
+
+    /// Raw pixels sent by the IR camera, defaults to a gradient.
+    ///
+    /// The image should match the resolution set by the host.
+    pub fn set_ir_image(&mut self, image: Vec<u8>) {
+        self.mcu.set_image(image);
+    }
+
+    /// Places a NTAG215 with the content `dump` on the NFC reader, or removes
+    /// it with `None`.
+    pub fn set_nfc_tag(&mut self, dump: Option<[u8; NTAG215_SIZE]>) {
+        self.mcu.set_tag(dump);
+    }
+
+    /// Content of the NTAG215 on the NFC reader, including the writes of the host.
+    pub fn nfc_tag(&self) -> Option<&[u8; NTAG215_SIZE]> {
+        self.mcu.tag()
+    }
+
+    /// Acknowledges the writes to the NFC tag without applying them, like a
+    /// locked tag would.
+    pub fn set_nfc_ignore_writes(&mut self, ignore_writes: bool) {
+        self.mcu.set_ignore_writes(ignore_writes);
+    }
+
+    /// Same semantic as `hidapi::HidDevice::write`.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let mut report = OutputReport::new();
+        let raw = report.as_bytes_mut();
+        let len = raw.len().min(data.len());
+        raw[..len].copy_from_slice(&data[..len]);
+
+        match OutputReportEnum::try_from(report) {
+            Ok(OutputReportEnum::RumbleAndSubcmd(subcmd)) => {
+                if let Some(reply) = self.handle_subcmd(subcmd)? {
+                    self.replies.push_back(reply);
+                }
+            }
+            Ok(OutputReportEnum::RequestMCUData(request)) => self.mcu.handle_request(request),
+            Ok(OutputReportEnum::RumbleOnly(())) => {}
+            Ok(OutputReportEnum::MCUFwUpdate(())) => warn!("MCU firmware update is not emulated"),
+            Err(report) => warn!("unknown output report {:?}", report.id()),
+        }
+        Ok(data.len())
+    }
+
+    /// Same semantic as `hidapi::HidDevice::read_timeout`, a negative
+    /// timeout blocks until a report is available.
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        let deadline = if timeout < 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        };
+        loop {
+            if self.realtime {
+                let now = Instant::now();
+                if let Some(deadline) = deadline {
+                    if deadline < self.next_slot {
+                        sleep(deadline.saturating_duration_since(now));
+                        return Ok(0);
+                    }
+                }
+                sleep(self.next_slot.saturating_duration_since(now));
+                // Don't try to catch up after a long pause of the reader.
+                self.next_slot = self.next_slot.max(now) + REPORT_PERIOD;
+            }
+            self.timer = self.timer.wrapping_add(3);
+
+            if let Some(report) = self.next_report() {
+                let bytes = report.as_bytes();
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                return Ok(len);
+            }
+            if !self.realtime {
+                return Ok(0);
+            }
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read_timeout(buf, -1)
+    }
+
+    fn next_report(&mut self) -> Option<InputReport> {
+        if let Some(reply) = self.replies.pop_front() {
+            return Some(InputReportEnum::StandardAndSubcmd((self.standard(), reply)).into());
+        }
+        match self.report_mode {
+            InputReportId::StandardFull => {
+                Some(InputReportEnum::StandardFull((self.standard(), self.imu_frames())).into())
+            }
+            InputReportId::StandardFullMCU => Some(
+                InputReportEnum::StandardFullMCU((
+                    self.standard(),
+                    self.imu_frames(),
+                    self.mcu.next_report(),
+                ))
+                .into(),
+            ),
+            // Only sent when a button changes.
+            _ if self.state_changed => {
+                self.state_changed = false;
+                Some(InputReportEnum::Normal(self.normal()).into())
+            }
+            _ => None,
+        }
+    }
+
+    fn standard(&self) -> StandardInputReport {
+        let mut info = DeviceStatus::default();
+        info.set_connected(false);
+        info.set_device_type(match self.device_type {
+            WhichController::ProController => DeviceType::ProController as u8,
+            _ => DeviceType::Joycon as u8,
+        });
+        info.set_charging(self.state.charging);
+        info.set_battery_level(self.state.battery as u8);
+
+        let (left_stick, right_stick) = match self.device_type {
+            WhichController::LeftJoyCon => (self.state.left_stick, (0, 0)),
+            WhichController::RightJoyCon => ((0, 0), self.state.right_stick),
+            WhichController::ProController => (self.state.left_stick, self.state.right_stick),
+        };
+        StandardInputReport {
+            timer: self.timer,
+            info,
+            buttons: self.state.buttons,
+            left_stick: Stick::new(left_stick.0, left_stick.1),
+            right_stick: Stick::new(right_stick.0, right_stick.1),
+            vibrator: if self.vibration { 0x0c } else { 0 },
+        }
+    }
+
+    fn imu_frames(&self) -> [imu::Frame; 3] {
+        let frame = if self.imu_mode == imu::IMUMode::Disabled {
+            imu::Frame::new(vec3(0., 0., 0.), vec3(0., 0., 0.))
+        } else {
+            let (acc_scale, gyro_scale) = self.imu_scales();
+            imu::Frame::new(self.state.accel * acc_scale, self.state.gyro * gyro_scale)
+        };
+        [frame; 3]
+    }
+
+    /// Ratios between the raw values at the configured sensitivity and at the
+    /// default one.
+    fn imu_scales(&self) -> (f64, f64) {
+        let sens = match self.imu_sensitivity {
+            Some(sens) => sens,
+            None => return (1., 1.),
+        };
+        let acc_scale = match sens.acc_sens.try_into() {
+            Some(acc_sens) => {
+                imu::AccSens::default().range_g() as f64 / imu::AccSens::range_g(acc_sens) as f64
+            }
+            None => 1.,
+        };
+        let gyro_scale = match sens.gyro_sens.try_into() {
+            Some(gyro_sens) => {
+                imu::GyroSens::default().range_dps() as f64
+                    / imu::GyroSens::range_dps(gyro_sens) as f64
+            }
+            None => 1.,
+        };
+        (acc_scale, gyro_scale)
+    }
+
+    /// Uses the Pro Controller layout for every controller type.
+    fn normal(&self) -> NormalInputReport {
+        let b = self.state.buttons;
+        let mut report = NormalInputReport::default();
+        report.buttons = [
+            b.right.b() as u8
+                | (b.right.a() as u8) << 1
+                | (b.right.y() as u8) << 2
+                | (b.right.x() as u8) << 3
+                | (b.left.l() as u8) << 4
+                | (b.right.r() as u8) << 5
+                | (b.left.zl() as u8) << 6
+                | (b.right.zr() as u8) << 7,
+            b.middle.minus() as u8
+                | (b.middle.plus() as u8) << 1
+                | (b.middle.lstick() as u8) << 2
+                | (b.middle.rstick() as u8) << 3
+                | (b.middle.home() as u8) << 4
+                | (b.middle.capture() as u8) << 5,
+        ];
+        report.stick = match (b.left.up(), b.left.right(), b.left.down(), b.left.left()) {
+            (true, false, _, false) => 0,
+            (true, true, _, _) => 1,
+            (false, true, false, _) => 2,
+            (_, true, true, _) => 3,
+            (_, false, true, false) => 4,
+            (_, _, true, true) => 5,
+            (false, _, false, true) => 6,
+            (true, _, _, true) => 7,
+            _ => 8,
+        };
+        report
+    }
+
+    fn has_mcu(&self) -> bool {
+        self.device_type != WhichController::LeftJoyCon
+    }
+
+    fn handle_subcmd(&mut self, subcmd: SubcommandRequest) -> Result<Option<SubcommandReply>> {
+        debug!("subcommand {:?}", subcmd);
+        let (ack, reply) = match SubcommandRequestEnum::try_from(subcmd) {
+            Ok(SubcommandRequestEnum::GetOnlyControllerState(())) => {
+                (0x80, SubcommandReplyEnum::GetOnlyControllerState(()))
+            }
+            Ok(SubcommandRequestEnum::BluetoothManualPairing(())) => {
+                (0x81, SubcommandReplyEnum::BluetoothManualPairing(()))
+            }
+            Ok(SubcommandRequestEnum::RequestDeviceInfo(())) => {
+                let info = DeviceInfo::new(
+                    FIRMWARE_VERSION,
+                    self.device_type,
+                    self.mac_address,
+                    RawId::new(self.flash.as_bytes()[0x601b]),
+                );
+                (0x82, SubcommandReplyEnum::RequestDeviceInfo(info))
+            }
+            Ok(SubcommandRequestEnum::SetInputReportMode(mode)) => match mode.try_into() {
+                Some(InputReportId::StandardFullMCU) if !self.has_mcu() => {
+                    return Ok(Some(SubcommandReply::nack(subcmd.id())))
+                }
+                Some(mode @ InputReportId::Normal)
+                | Some(mode @ InputReportId::StandardFull)
+                | Some(mode @ InputReportId::StandardFullMCU) => {
+                    self.report_mode = mode;
+                    (0x80, SubcommandReplyEnum::SetInputReportMode(()))
+                }
+                _ => return Ok(Some(SubcommandReply::nack(subcmd.id()))),
+            },
+            Ok(SubcommandRequestEnum::GetTriggerButtonsElapsedTime(())) => (
+                0x83,
+                SubcommandReplyEnum::GetTriggerButtonsElapsedTime([U16LE::from(0); 7]),
+            ),
+            Ok(SubcommandRequestEnum::SetShipmentMode(_)) => {
+                (0x80, SubcommandReplyEnum::SetShipmentMode(()))
+            }
+            Ok(SubcommandRequestEnum::SPIRead(request)) => {
+                let range = request.range();
+                match self.flash.read(range.offset(), range.size()) {
+                    Some(data) if range.size() <= 0x1d => (
+                        0x90,
+                        SubcommandReplyEnum::SPIRead(SPIReadResult::new(range, data)),
+                    ),
+                    _ => return Ok(Some(SubcommandReply::nack(subcmd.id()))),
+                }
+            }
+            Ok(SubcommandRequestEnum::SPIWrite(request)) => {
+                let range = request.range();
+                let success = self.flash.write(range.offset(), request.data())?;
+                (
+                    0x80,
+                    SubcommandReplyEnum::SPIWrite(SPIWriteResult::new(success)),
+                )
+            }
+            Ok(SubcommandRequestEnum::SetMCUConf(cmd)) => {
+                if !self.has_mcu() {
+                    return Ok(Some(SubcommandReply::nack(subcmd.id())));
+                }
+                (
+                    0xa0,
+                    SubcommandReplyEnum::SetMCUConf(self.mcu.handle_command(&cmd)),
+                )
+            }
+            Ok(SubcommandRequestEnum::SetMCUState(state)) => match state.try_into() {
+                Some(state) if self.has_mcu() => {
+                    self.mcu.set_state(state);
+                    (0x80, SubcommandReplyEnum::SetMCUState(()))
+                }
+                _ => return Ok(Some(SubcommandReply::nack(subcmd.id()))),
+            },
+            Ok(SubcommandRequestEnum::SetUnknownData(_)) => {
+                (0x80, SubcommandReplyEnum::SetUnknownData(()))
+            }
+            Ok(SubcommandRequestEnum::ResetUnknownData(())) => {
+                (0x80, SubcommandReplyEnum::ResetUnknownData(()))
+            }
+            Ok(SubcommandRequestEnum::SetPlayerLights(_)) => {
+                (0x80, SubcommandReplyEnum::SetPlayerLights(()))
+            }
+            Ok(SubcommandRequestEnum::SetHomeLight(_)) => {
+                (0x80, SubcommandReplyEnum::SetHomeLight(()))
+            }
+            Ok(SubcommandRequestEnum::SetIMUMode(mode)) => match mode.try_into() {
+                Some(mode) => {
+                    self.imu_mode = mode;
+                    (0x80, SubcommandReplyEnum::SetIMUMode(()))
+                }
+                None => return Ok(Some(SubcommandReply::nack(subcmd.id()))),
+            },
+            Ok(SubcommandRequestEnum::SetIMUSens(sens)) => {
+                self.imu_sensitivity = Some(sens);
+                (0x80, SubcommandReplyEnum::SetIMUSens(()))
+            }
+            Ok(SubcommandRequestEnum::EnableVibration(enabled)) => {
+                self.vibration = matches!(enabled.try_into(), Some(Bool::True));
+                (0x80, SubcommandReplyEnum::EnableVibration(()))
+            }
+            Ok(SubcommandRequestEnum::MaybeAccessory(_)) => (
+                0xa0,
+                SubcommandReplyEnum::MaybeAccessory(AccessoryResponse::no_accessory()),
+            ),
+            Ok(SubcommandRequestEnum::Unknown0x59(())) => {
+                (0x80, SubcommandReplyEnum::Unknown0x59(()))
+            }
+            Ok(SubcommandRequestEnum::Unknown0x5a(_)) => {
+                (0x80, SubcommandReplyEnum::Unknown0x5a(()))
+            }
+            Ok(SubcommandRequestEnum::Unknown0x5b(())) => {
+                (0x80, SubcommandReplyEnum::Unknown0x5b(()))
+            }
+            Ok(SubcommandRequestEnum::Unknown0x5c(_)) => {
+                (0x80, SubcommandReplyEnum::Unknown0x5c(()))
+            }
+            Err(subcmd) => {
+                warn!("unknown subcommand {:?}", subcmd.id());
+                return Ok(Some(SubcommandReply::nack(subcmd.id())));
+            }
+        };
+        let mut reply = SubcommandReply::from(reply);
+        *reply.ack_mut() = Ack::new(ack);
+        Ok(Some(reply))
+    }
+}
+
+#[cfg(test)]
+fn call_subcmd(emulator: &mut Emulator, subcmd: impl Into<SubcommandRequest>) -> SubcommandReply {
+    let report = OutputReport::from(subcmd.into());
+    emulator.write(report.as_bytes()).unwrap();
+    let mut input = InputReport::new();
+    emulator.read(input.as_bytes_mut()).unwrap();
+    *input.subcmd_reply().expect("subcommand reply")
+}
+
+#[cfg(test)]
+#[test]
+fn handshake() {
+    let mut emulator = Emulator::new(WhichController::RightJoyCon);
+    emulator.set_realtime(false);
+
+    let reply = call_subcmd(&mut emulator, SubcommandRequestEnum::RequestDeviceInfo(()));
+    assert!(reply.ack().is_ok());
+    let info = reply.device_info().unwrap();
+    assert!(info.which_controller == WhichController::RightJoyCon);
+
+    let reply = call_subcmd(
+        &mut emulator,
+        SPIReadRequest::new(SticksCalibration::range()),
+    );
+    let calib = SticksCalibration::try_from(*reply.spi_read_result().unwrap()).unwrap();
+    assert_eq!((0x800, 0x800), calib.right.center());
+
+    let reply = call_subcmd(
+        &mut emulator,
+        SubcommandRequestEnum::SetInputReportMode(InputReportId::StandardFull.into()),
+    );
+    assert!(reply.ack().is_ok());
+    let mut input = InputReport::new();
+    emulator.read(input.as_bytes_mut()).unwrap();
+    assert!(input.imu_frames().is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn spi_write() {
+    let mut emulator = Emulator::new(WhichController::ProController);
+    emulator.set_realtime(false);
+    let range = unsafe { SPIRange::new(0x8010, 2) };
+    let reply = call_subcmd(&mut emulator, unsafe {
+        SPIWriteRequest::new(range, &[0xb2, 0xa1])
+    });
+    assert_eq!(Some(true), reply.is_spi_write_success());
+    assert_eq!(Some(&[0xb2, 0xa1][..]), emulator.flash().read(0x8010, 2));
+}
+
+#[cfg(test)]
+#[test]
+fn mcu_status() {
+    let mut emulator = Emulator::new(WhichController::RightJoyCon);
+    emulator.set_realtime(false);
+    call_subcmd(
+        &mut emulator,
+        SubcommandRequestEnum::SetInputReportMode(InputReportId::StandardFullMCU.into()),
+    );
+    call_subcmd(
+        &mut emulator,
+        SubcommandRequestEnum::SetMCUState(joycon_sys::mcu::MCUMode::Standby.into()),
+    );
+
+    let request = OutputReport::from(joycon_sys::mcu::MCURequest::from(
+        joycon_sys::mcu::MCURequestEnum::GetMCUStatus(()),
+    ));
+    emulator.write(request.as_bytes()).unwrap();
+    let mut input = InputReport::new();
+    let status = loop {
+        emulator.read(input.as_bytes_mut()).unwrap();
+        let mcu_report = input.mcu_report().unwrap();
+        if !mcu_report.is_busy_init() {
+            break *mcu_report.state_report().unwrap();
+        }
+    };
+    assert!(status.state == joycon_sys::mcu::MCUMode::Standby);
+}
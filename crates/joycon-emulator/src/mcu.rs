@@ -0,0 +1,374 @@
+//! Emulation of the MCU handling the IR camera and the NFC reader.
+//!
+//! <https://github.com/CTCaer/Nintendo_Switch_Reverse_Engineering/blob/ir-nfc/mcu_ir_nfc_notes.md>
+
+use joycon_sys::{
+    common::{Bool, U16LE},
+    mcu::{ir::*, nfc::*, *},
+};
+use std::{collections::HashMap, convert::TryFrom};
+use tracing::warn;
+
+/// Version reported in the status, required when configuring the IR camera.
+const FW_VERSION: (u16, u16) = (0x0008, 0x001b);
+
+/// Number of `BusyInitializing` reports sent after a mode change.
+const INIT_REPORTS: u8 = 3;
+
+const FRAGMENT_SIZE: usize = 300;
+
+pub(crate) struct MCUState {
+    mode: MCUMode,
+    busy: u8,
+    ir_mode: MCUIRMode,
+    /// Number of the last fragment of an image.
+    max_fragment: u8,
+    next_fragment: u8,
+    registers: HashMap<(u8, u8), u8>,
+    image: Option<Vec<u8>>,
+    request: Option<MCURequest>,
+    /// Reply to a request already handled.
+    reply: Option<MCUReport>,
+    nfc_state: NFCState,
+    /// Content of the NTAG215 placed on the reader.
+    tag: Option<[u8; NTAG215_SIZE]>,
+    /// Acknowledge the writes to the tag without applying them.
+    ignore_writes: bool,
+    /// Packet of the pending tag read waiting for an ack.
+    read_packet: Option<u8>,
+    /// Payload of the tag write packets received so far.
+    write_payload: Vec<u8>,
+}
+
+impl MCUState {
+    pub fn new() -> MCUState {
+        MCUState {
+            mode: MCUMode::Suspend,
+            busy: 0,
+            ir_mode: MCUIRMode::IRSensorReset,
+            max_fragment: 0,
+            next_fragment: 0,
+            registers: HashMap::new(),
+            image: None,
+            request: None,
+            reply: None,
+            nfc_state: NFCState::None,
+            tag: None,
+            ignore_writes: false,
+            read_packet: None,
+            write_payload: vec![],
+        }
+    }
+
+    pub fn set_image(&mut self, image: Vec<u8>) {
+        self.image = Some(image);
+    }
+
+    pub fn set_tag(&mut self, tag: Option<[u8; NTAG215_SIZE]>) {
+        self.tag = tag;
+    }
+
+    pub fn tag(&self) -> Option<&[u8; NTAG215_SIZE]> {
+        self.tag.as_ref()
+    }
+
+    pub fn set_ignore_writes(&mut self, ignore_writes: bool) {
+        self.ignore_writes = ignore_writes;
+    }
+
+    /// Subcommand 0x22.
+    pub fn set_state(&mut self, state: MCUMode) {
+        match state {
+            MCUMode::Suspend => {
+                *self = MCUState {
+                    image: self.image.take(),
+                    tag: self.tag.take(),
+                    ignore_writes: self.ignore_writes,
+                    ..MCUState::new()
+                }
+            }
+            _ => self.set_mode(MCUMode::Standby),
+        }
+    }
+
+    fn set_mode(&mut self, mode: MCUMode) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.busy = INIT_REPORTS;
+            self.nfc_state = NFCState::None;
+            self.read_packet = None;
+        }
+    }
+
+    /// Subcommand 0x21, the returned report is sent in the subcommand reply.
+    pub fn handle_command(&mut self, cmd: &MCUCommand) -> MCUReport {
+        if let Some(mode) = cmd.mcu_mode() {
+            match mode.try_into() {
+                Some(mode) if self.mode != MCUMode::Suspend => self.set_mode(mode),
+                Some(_) => warn!("MCU command while suspended"),
+                None => warn!("unknown MCU mode {:?}", mode),
+            }
+        } else if let Some(conf) = cmd.ir_mode() {
+            match conf.ir_mode.try_into() {
+                Some(ir_mode) => {
+                    self.ir_mode = match ir_mode {
+                        MCUIRMode::IRSensorReset => MCUIRMode::WaitingForConfigurationMaybe,
+                        ir_mode => ir_mode,
+                    };
+                    self.max_fragment = conf.no_of_frags;
+                    self.next_fragment = 0;
+                }
+                None => warn!("unknown IR mode {:?}", conf.ir_mode),
+            }
+        } else if let Some(regs) = cmd.ir_registers() {
+            for reg in regs.regs.iter().take(regs.len as usize) {
+                self.registers
+                    .insert((reg.page(), reg.offset()), reg.value());
+            }
+        } else {
+            warn!(
+                "unknown MCU command {:?} {:?}",
+                cmd.cmd_id(),
+                cmd.subcmd_id()
+            );
+        }
+        self.status()
+    }
+
+    /// Output report 0x11, the reply is sent in a following 0x31 input report.
+    pub fn handle_request(&mut self, request: MCURequest) {
+        match MCURequestEnum::try_from(request) {
+            Ok(MCURequestEnum::GetIRData(ir_request)) => {
+                if let Ok(IRRequestEnum::GetSensorData(ack)) = IRRequestEnum::try_from(ir_request) {
+                    // Acks are handled right away so that they don't get lost
+                    // while another request is pending.
+                    self.ack_fragment(ack);
+                    return;
+                }
+            }
+            Ok(MCURequestEnum::GetNFCData(nfc_request)) => {
+                // Same for the NFC requests, the host usually asks for the
+                // status right after starting a poll or sending a write packet.
+                self.request = None;
+                self.reply = self.reply_nfc(nfc_request);
+                return;
+            }
+            _ => {}
+        }
+        self.reply = None;
+        self.request = Some(request);
+    }
+
+    fn reply(&mut self, request: MCURequest) -> Option<MCUReport> {
+        match MCURequestEnum::try_from(request) {
+            Ok(MCURequestEnum::GetMCUStatus(())) => Some(self.status()),
+            Ok(MCURequestEnum::GetIRData(ir_request)) => self.reply_ir(ir_request),
+            Ok(MCURequestEnum::GetNFCData(nfc_request)) => self.reply_nfc(nfc_request),
+            Err(request) => {
+                warn!("unknown MCU request {:?}", request.id());
+                None
+            }
+        }
+    }
+
+    fn reply_ir(&mut self, request: IRRequest) -> Option<MCUReport> {
+        if self.mode != MCUMode::IR {
+            return None;
+        }
+        match IRRequestEnum::try_from(request) {
+            Ok(IRRequestEnum::GetState(())) => {
+                let status = IRStatus::new(self.ir_mode, fw_version());
+                Some(MCUReportEnum::IRStatus(status).into())
+            }
+            Ok(IRRequestEnum::ReadRegister(read)) => {
+                let values: Vec<u8> = (0..read.nb_registers.min(0x7f))
+                    .map(|i| {
+                        let offset = read.offset.wrapping_add(i);
+                        *self.registers.get(&(read.page, offset)).unwrap_or(&0)
+                    })
+                    .collect();
+                let slice = IRRegistersSlice::new(read.page, read.offset, &values);
+                Some(MCUReportEnum::IRRegisters(slice).into())
+            }
+            Ok(IRRequestEnum::GetSensorData(ack)) => {
+                self.ack_fragment(ack);
+                None
+            }
+            Err(request) => {
+                warn!("unknown IR request {:?}", request.id());
+                None
+            }
+        }
+    }
+
+    fn reply_nfc(&mut self, request: NFCRequest) -> Option<MCUReport> {
+        if self.mode != MCUMode::NFC {
+            return None;
+        }
+        let header = *request.header();
+        match NFCRequestEnum::try_from(request) {
+            Ok(NFCRequestEnum::StartPolling(_)) => {
+                self.nfc_state = NFCState::Polling;
+                self.read_packet = None;
+            }
+            Ok(NFCRequestEnum::StopPolling(())) => {
+                self.nfc_state = NFCState::None;
+                self.read_packet = None;
+            }
+            Ok(NFCRequestEnum::GetStatus(())) => match self.read_packet {
+                Some(1) if header.ack_number == 1 => return self.read_data(2),
+                Some(2) if header.ack_number == 2 => {
+                    self.read_packet = None;
+                    self.nfc_state = NFCState::ReadFinished;
+                }
+                // Not acked yet, send it again.
+                Some(packet) => return self.read_data(packet),
+                None => {
+                    if self.nfc_state == NFCState::Polling && self.tag.is_some() {
+                        self.nfc_state = NFCState::TagDetected;
+                    }
+                }
+            },
+            Ok(NFCRequestEnum::ReadNTAG(args)) => {
+                let uid = self.tag_info().map(|info| info.uid().to_vec());
+                match (uid, args.checked_uid()) {
+                    (Some(ref uid), Some(ref expected)) if uid != expected => {
+                        self.nfc_state = NFCState::Error
+                    }
+                    (Some(_), _) => {
+                        self.nfc_state = NFCState::PendingRead;
+                        return self.read_data(1);
+                    }
+                    (None, _) => self.nfc_state = NFCState::Error,
+                }
+            }
+            Ok(NFCRequestEnum::WriteNTAG(data)) => {
+                if header.packet_number == 0 {
+                    self.write_payload.clear();
+                }
+                let len = (header.data_len as usize).min(data.len());
+                self.write_payload.extend_from_slice(&data[..len]);
+                self.nfc_state = if !header.is_last_packet() {
+                    NFCState::Writing
+                } else if self.write_tag() {
+                    NFCState::WriteFinished
+                } else {
+                    NFCState::Error
+                };
+            }
+            Err(request) => {
+                warn!("unknown NFC request {:?}", request.id());
+                return None;
+            }
+        }
+        let tag = match self.nfc_state {
+            NFCState::None | NFCState::Polling => None,
+            _ => self.tag_info(),
+        };
+        let status = NFCStatus::new(self.nfc_state, tag.unwrap_or_else(NFCTagInfo::none));
+        Some(MCUReportEnum::NFCState(status).into())
+    }
+
+    /// Applies the reassembled write, returns `false` if it is invalid.
+    fn write_tag(&mut self) -> bool {
+        let payload = std::mem::take(&mut self.write_payload);
+        let (header, data) = match NTAGWriteHeader::parse(&payload) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        let info = match self.tag_info() {
+            Some(info) => info,
+            None => return false,
+        };
+        if matches!(header.check_uid.try_into(), Some(Bool::True)) && info.uid() != header.uid {
+            return false;
+        }
+        let start = header.start_page as usize * NTAG_PAGE_SIZE;
+        let len = header.nb_pages as usize * NTAG_PAGE_SIZE;
+        let (tag, data) = match (self.tag.as_mut(), data.get(..len)) {
+            (Some(tag), Some(data)) if start + len <= NTAG215_SIZE => (tag, data),
+            _ => return false,
+        };
+        if !self.ignore_writes {
+            tag[start..start + len].copy_from_slice(data);
+        }
+        true
+    }
+
+    fn read_data(&mut self, packet: u8) -> Option<MCUReport> {
+        let (info, tag) = (self.tag_info()?, self.tag.as_ref()?);
+        self.read_packet = Some(packet);
+        Some(MCUReportEnum::NFCReadData(NFCReadData::new(packet, info, tag)).into())
+    }
+
+    /// The 7 bytes UID is stored in the first two pages, each part followed
+    /// by a check byte.
+    fn tag_info(&self) -> Option<NFCTagInfo> {
+        let tag = self.tag.as_ref()?;
+        let uid = [tag[0], tag[1], tag[2], tag[4], tag[5], tag[6], tag[7]];
+        Some(NFCTagInfo::new(NFCTagType::NTAG, &uid))
+    }
+
+    fn ack_fragment(&mut self, ack: IRAckRequestPacket) {
+        self.next_fragment = if matches!(ack.packet_missing.try_into(), Some(Bool::True)) {
+            ack.missed_packet_id
+        } else if ack.ack_packet_id >= self.max_fragment {
+            0
+        } else {
+            ack.ack_packet_id + 1
+        };
+    }
+
+    /// MCU data of the next 0x31 input report.
+    pub fn next_report(&mut self) -> MCUReport {
+        if self.busy > 0 {
+            self.busy -= 1;
+            return MCUReportEnum::BusyInitializing(()).into();
+        }
+        let reply = match self.reply.take() {
+            Some(reply) => Some(reply),
+            None => self.request.take().and_then(|r| self.reply(r)),
+        };
+        if let Some(reply) = reply {
+            reply
+        } else if self.mode == MCUMode::IR && self.ir_mode == MCUIRMode::ImageTransfer {
+            MCUReportEnum::IRData(self.fragment(self.next_fragment)).into()
+        } else if self.mode == MCUMode::Suspend {
+            MCUReportEnum::Empty(()).into()
+        } else {
+            MCUReportEnum::EmptyAwaitingCmd(()).into()
+        }
+    }
+
+    fn status(&self) -> MCUReport {
+        let status = if self.busy > 0 {
+            MCUReportEnum::BusyInitializing(())
+        } else {
+            MCUReportEnum::StateReport(MCUStatus::new(fw_version(), self.mode))
+        };
+        status.into()
+    }
+
+    fn fragment(&self, number: u8) -> IRData {
+        let mut fragment = [0; FRAGMENT_SIZE];
+        let start = number as usize * FRAGMENT_SIZE;
+        match self.image {
+            Some(ref image) => {
+                if let Some(src) = image.get(start..start + FRAGMENT_SIZE) {
+                    fragment.copy_from_slice(src);
+                }
+            }
+            None => {
+                // Gradient pattern, makes misplaced fragments easy to spot.
+                for (i, pixel) in fragment.iter_mut().enumerate() {
+                    *pixel = ((start + i) / 64) as u8;
+                }
+            }
+        }
+        IRData::new(number, fragment)
+    }
+}
+
+fn fw_version() -> (U16LE, U16LE) {
+    (FW_VERSION.0.into(), FW_VERSION.1.into())
+}
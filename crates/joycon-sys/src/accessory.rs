@@ -88,6 +88,18 @@ impl AccessoryCommand {
             ],
         }
     }
+
+    pub fn id(&self) -> RawId<AccessoryCommandId> {
+        self.id
+    }
+
+    pub fn ty(&self) -> RawId<AccessoryType> {
+        self.ty
+    }
+
+    pub fn item(&self) -> RawId<RingconItemId> {
+        self.item
+    }
 }
 
 #[repr(packed)]
@@ -101,6 +113,15 @@ pub struct AccessoryResponse {
 }
 
 impl AccessoryResponse {
+    pub fn no_accessory() -> Self {
+        AccessoryResponse {
+            error: 254,
+            len: 0,
+            unknown_0x00: [0; 4],
+            u: AccessoryResponseUnion { raw: [0; 20] },
+        }
+    }
+
     fn check_error(&self) -> Result<(), Error> {
         match self.error {
             0 => Ok(()),
@@ -43,6 +43,8 @@ pub enum SubcommandId {
     SetMCUConf = 0x21,
     SetMCUState = 0x22,
     SetUnknownData = 0x24,
+    /// Reverts `SetUnknownData`.
+    ResetUnknownData = 0x25,
     SetPlayerLights = 0x30,
     SetHomeLight = 0x38,
     SetIMUMode = 0x40,
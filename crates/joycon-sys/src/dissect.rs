@@ -0,0 +1,272 @@
+//! Decoding of the packets for the Wireshark dissector of `wireshark-joycon`
+//!
+//! `dissect` only returns the fields and where they are in the packet, so it
+//! can be tested without the Wireshark headers.
+
+use crate::{
+    spi::SPIRange,
+    trace::{Direction, TraceEntry, TraceReport, INPUT_HEADER, OUTPUT_HEADER},
+};
+use std::{fmt::Debug, mem::size_of_val, time::Duration};
+
+/// Header fields of the dissector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    InputReport,
+    OutputReport,
+    Subcmd,
+    SubcmdAck,
+    SPIOffset,
+    SPISize,
+    SPIData,
+    SPIStatus,
+    MCURequest,
+    MCUReport,
+    MCUCommand,
+    MCUSubcmd,
+    MCUMode,
+    IRRequest,
+    AccessoryCommand,
+    AccessoryType,
+    AccessoryItem,
+}
+
+/// Part of a packet, always inside the captured bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub start: usize,
+    pub len: usize,
+    pub kind: ItemKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemKind {
+    Field(Field),
+    /// Decoded value, with the fields inside it.
+    Details {
+        text: String,
+        items: Vec<Item>,
+    },
+}
+
+/// Decoded packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// Summary for the info column.
+    pub info: String,
+    pub items: Vec<Item>,
+}
+
+/// Decodes a packet of the HID interrupt channel, including the HID header.
+///
+/// Returns `None` if it isn't a HID report.
+pub fn dissect(data: &[u8]) -> Option<Packet> {
+    let (direction, report) = match data.split_first() {
+        Some((&INPUT_HEADER, report)) => (Direction::Input, report),
+        Some((&OUTPUT_HEADER, report)) => (Direction::Output, report),
+        _ => return None,
+    };
+    let entry = TraceEntry::from_bytes(Duration::default(), direction, report).ok()?;
+    let mut tree = Tree::new(data);
+    let info = tree.report(&entry);
+    Some(Packet {
+        info,
+        items: tree.items,
+    })
+}
+
+/// Position in the packet of `field`, which is part of `report`.
+fn position<R, F>(report: &R, field: &F) -> usize {
+    // After the HID header.
+    1 + field as *const F as usize - report as *const R as usize
+}
+
+/// Items of a packet, which never go past the captured bytes.
+struct Tree<'a> {
+    data: &'a [u8],
+    items: Vec<Item>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(data: &'a [u8]) -> Tree<'a> {
+        Tree {
+            data,
+            items: vec![],
+        }
+    }
+
+    /// Name of the ID at `start`, or its value if unknown.
+    fn name<Id: Debug>(&self, id: Option<Id>, start: usize) -> String {
+        match (id, self.data.get(start)) {
+            (Some(id), _) => format!("{:?}", id),
+            (None, Some(raw)) => format!("0x{:02x}", raw),
+            (None, None) => "truncated".to_string(),
+        }
+    }
+
+    /// Adds a field read from the packet, if it was captured.
+    fn add(&mut self, field: Field, start: usize, len: usize) {
+        if start + len <= self.data.len() {
+            self.items.push(Item {
+                start,
+                len,
+                kind: ItemKind::Field(field),
+            });
+        }
+    }
+
+    /// Adds the decoded `value` and the fields of `inner`, on the captured
+    /// part of its bytes.
+    fn add_details<T: Debug>(&mut self, start: usize, value: &T, inner: Tree) {
+        if start < self.data.len() {
+            self.items.push(Item {
+                start,
+                len: size_of_val(value).min(self.data.len() - start),
+                kind: ItemKind::Details {
+                    text: format!("{:?}", value),
+                    items: inner.items,
+                },
+            });
+        }
+    }
+
+    /// Adds the range of a SPI read or write, and the data that follows it.
+    fn spi(&mut self, start: usize, range: SPIRange, with_data: bool) -> String {
+        self.add(Field::SPIOffset, start, 4);
+        self.add(Field::SPISize, start + 4, 1);
+        if with_data {
+            self.add(Field::SPIData, start + 5, range.size().into());
+        }
+        format!(" 0x{:05x}+0x{:02x}", range.offset(), range.size())
+    }
+
+    /// Adds the fields of the report, and returns its summary.
+    fn report(&mut self, entry: &TraceEntry) -> String {
+        match entry.report {
+            TraceReport::Input(ref report) => {
+                self.add(Field::InputReport, 1, 1);
+                let mut info = self.name(report.id().try_into(), 1);
+                if let Some(reply) = report.subcmd_reply() {
+                    let start = position(report, reply.ack());
+                    let mut tree = Tree::new(self.data);
+                    tree.add(Field::SubcmdAck, start, 1);
+                    tree.add(Field::Subcmd, start + 1, 1);
+                    info += &format!(", reply {}", tree.name(reply.id().try_into(), start + 1));
+                    if let Some(result) = reply.spi_read_result() {
+                        info += &tree.spi(position(report, result), result.range(), true);
+                    }
+                    if let Some(result) = reply.spi_write_result() {
+                        tree.add(Field::SPIStatus, position(report, result), 1);
+                    }
+                    if let Some(mcu) = reply.mcu_report() {
+                        tree.add(Field::MCUReport, position(report, mcu), 1);
+                    }
+                    self.add_details(start, reply, tree);
+                }
+                if let Some(mcu) = report.mcu_report() {
+                    let start = position(report, mcu);
+                    let mut tree = Tree::new(self.data);
+                    tree.add(Field::MCUReport, start, 1);
+                    self.add_details(start, mcu, tree);
+                    info += &format!(", {}", self.name(mcu.id().try_into(), start));
+                }
+                info
+            }
+            TraceReport::Output(ref report) => {
+                self.add(Field::OutputReport, 1, 1);
+                let mut info = self.name(report.id().try_into(), 1);
+                if let Some(request) = report.rumble_subcmd() {
+                    let start = position(report, request);
+                    let mut tree = Tree::new(self.data);
+                    tree.add(Field::Subcmd, start, 1);
+                    info += &format!(", {}", tree.name(request.id().try_into(), start));
+                    if let Some(read) = request.spi_read() {
+                        info += &tree.spi(position(report, read), read.range(), false);
+                    }
+                    if let Some(write) = request.spi_write() {
+                        info += &tree.spi(position(report, write), write.range(), true);
+                    }
+                    if let Some(command) = request.set_mcu_conf() {
+                        let start = position(report, command);
+                        tree.add(Field::MCUCommand, start, 1);
+                        tree.add(Field::MCUSubcmd, start + 1, 1);
+                        if command.mcu_mode().is_some() {
+                            tree.add(Field::MCUMode, start + 2, 1);
+                        }
+                    }
+                    if let Some(mode) = request.set_mcu_state() {
+                        tree.add(Field::MCUMode, position(report, mode), 1);
+                    }
+                    if let Some(command) = request.maybe_accessory() {
+                        let start = position(report, command);
+                        tree.add(Field::AccessoryCommand, start, 1);
+                        tree.add(Field::AccessoryType, start + 1, 1);
+                        tree.add(Field::AccessoryItem, start + 2, 1);
+                        info += &format!(
+                            " {} {}",
+                            tree.name(command.id().try_into(), start),
+                            tree.name(command.item().try_into(), start + 2)
+                        );
+                    }
+                    self.add_details(start, request, tree);
+                }
+                if let Some(request) = report.request_mcu_data() {
+                    let start = position(report, request);
+                    let mut tree = Tree::new(self.data);
+                    tree.add(Field::MCURequest, start, 1);
+                    info += &format!(", {}", tree.name(request.id().try_into(), start));
+                    if let Some(ir) = request.get_ir_data() {
+                        tree.add(Field::IRRequest, position(report, ir), 1);
+                    }
+                    self.add_details(start, request, tree);
+                }
+                info
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dissect_packets() {
+    let fields = |items: &[Item]| -> Vec<(Field, usize, usize)> {
+        items
+            .iter()
+            .filter_map(|item| match item.kind {
+                ItemKind::Field(field) => Some((field, item.start, item.len)),
+                ItemKind::Details { .. } => None,
+            })
+            .collect()
+    };
+
+    // SPI read of the serial number
+    let mut data = vec![0xa2, 0x01, 0x00];
+    data.extend_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+    data.extend_from_slice(&[0x10, 0x00, 0x60, 0x00, 0x00, 0x10]);
+    let packet = dissect(&data).unwrap();
+    assert_eq!(packet.info, "RumbleAndSubcmd, SPIRead 0x06000+0x10");
+    assert_eq!(fields(&packet.items), [(Field::OutputReport, 1, 1)]);
+    match packet.items[1].kind {
+        ItemKind::Details { ref items, .. } => assert_eq!(
+            fields(items),
+            [
+                (Field::Subcmd, 11, 1),
+                (Field::SPIOffset, 12, 4),
+                (Field::SPISize, 16, 1)
+            ]
+        ),
+        ref kind => panic!("unexpected item {:?}", kind),
+    }
+
+    // Truncated packets
+    let packet = dissect(&[0xa2, 0x11]).unwrap();
+    assert_eq!(packet.info, "RequestMCUData, truncated");
+    assert_eq!(fields(&packet.items), [(Field::OutputReport, 1, 1)]);
+    assert_eq!(packet.items.len(), 1);
+    let packet = dissect(&[0xa1, 0x21, 0x00]).unwrap();
+    assert!(packet.info.starts_with("StandardAndSubcmd"));
+    assert!(packet.items.iter().all(|item| item.start + item.len <= 3));
+
+    assert_eq!(dissect(&[0x52, 0x01]), None);
+    assert_eq!(dissect(&[]), None);
+}
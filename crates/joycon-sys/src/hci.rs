@@ -0,0 +1,528 @@
+//! Bluetooth HCI captures, converted to trace entries
+//!
+//! Supports the btsnoop files from Android and `btmon -w`, and the pcap files
+//! from Wireshark with the `DLT_BLUETOOTH_HCI_H4` or
+//! `DLT_BLUETOOTH_HCI_H4_WITH_PHDR` link types.
+//!
+//! The HID reports are taken from the L2CAP channels opened on the HID
+//! interrupt PSM. If the capture starts after the channels were opened, every
+//! dynamic channel is used instead.
+
+use crate::trace::{Direction, TraceEntry, TraceParseError, INPUT_HEADER, OUTPUT_HEADER};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Read},
+    time::Duration,
+};
+
+/// PSM of the L2CAP channel carrying the HID reports.
+pub const HID_INTERRUPT_PSM: u16 = 0x13;
+
+const BTSNOOP_MAGIC: &[u8; 8] = b"btsnoop\0";
+const BTSNOOP_H1: u32 = 1001;
+const BTSNOOP_H4: u32 = 1002;
+const BTSNOOP_MONITOR: u32 = 2001;
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const DLT_BLUETOOTH_HCI_H4: u32 = 187;
+const DLT_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+
+/// Bigger than any H4 ACL packet, even with the pcap pseudo-header.
+const MAX_PACKET_SIZE: usize = 0x10000 + 9;
+
+const H4_ACL: u8 = 0x02;
+const MONITOR_ACL_TX: u32 = 4;
+const MONITOR_ACL_RX: u32 = 5;
+const ACL_CONTINUATION: u8 = 0b01;
+const L2CAP_SIGNALING_CID: u16 = 0x0001;
+const L2CAP_FIRST_DYNAMIC_CID: u16 = 0x0040;
+const L2CAP_CONNECTION_REQUEST: u8 = 0x02;
+const L2CAP_CONNECTION_RESPONSE: u8 = 0x03;
+
+/// Whether `header`, the start of a file, is a capture supported by
+/// `HciReader`.
+pub fn is_hci_capture(header: &[u8]) -> bool {
+    header.starts_with(BTSNOOP_MAGIC)
+        || (header.len() >= 4
+            && [PCAP_MAGIC, PCAP_MAGIC_NANOS].iter().any(|magic| {
+                header[..4] == magic.to_le_bytes() || header[..4] == magic.to_be_bytes()
+            }))
+}
+
+#[derive(Debug)]
+pub enum HciError {
+    Io(io::Error),
+    UnknownFormat,
+    UnsupportedLinkType(u32),
+    /// The file ends in the middle of a packet.
+    Truncated,
+    /// The length of a packet is bigger than the capture allows.
+    TooLong {
+        packet: usize,
+        len: usize,
+    },
+    InvalidReport {
+        packet: usize,
+        error: TraceParseError,
+    },
+}
+
+impl std::error::Error for HciError {}
+
+impl fmt::Display for HciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HciError::Io(e) => e.fmt(f),
+            HciError::UnknownFormat => f.write_str("neither a btsnoop nor a pcap file"),
+            HciError::UnsupportedLinkType(link) => write!(f, "unsupported link type {}", link),
+            HciError::Truncated => f.write_str("truncated capture"),
+            HciError::TooLong { packet, len } => {
+                write!(f, "packet {}: too long ({} bytes)", packet, len)
+            }
+            HciError::InvalidReport { packet, error } => {
+                write!(f, "packet {}: {}", packet, error)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for HciError {
+    fn from(e: io::Error) -> Self {
+        HciError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Btsnoop {
+        datalink: u32,
+    },
+    Pcap {
+        big_endian: bool,
+        nanos: bool,
+        link_type: u32,
+        snaplen: usize,
+    },
+}
+
+/// ACL packet of a capture.
+struct Record {
+    /// Since an arbitrary epoch.
+    timestamp: Duration,
+    /// Whether the capturing host received it, `None` if unknown.
+    received: Option<bool>,
+    acl: Option<Vec<u8>>,
+}
+
+/// Iterator over the HID reports of a HCI capture.
+pub struct HciReader<R> {
+    input: R,
+    format: Format,
+    start: Option<Duration>,
+    packet: usize,
+    /// L2CAP packets being reassembled, by connection handle and direction.
+    partial: HashMap<(u16, Option<bool>), Vec<u8>>,
+    /// Connection handles with L2CAP signaling in the capture.
+    signaled: HashSet<u16>,
+    /// HID interrupt channels, as connection handle, direction and CID.
+    interrupt: HashSet<(u16, Option<bool>, u16)>,
+}
+
+impl<R: Read> HciReader<R> {
+    pub fn new(mut input: R) -> Result<HciReader<R>, HciError> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic[..4])?;
+        let format = if magic[..4] == BTSNOOP_MAGIC[..4] {
+            let mut header = [0; 12];
+            input.read_exact(&mut header)?;
+            if header[..4] != BTSNOOP_MAGIC[4..] {
+                return Err(HciError::UnknownFormat);
+            }
+            let datalink = be32(&header[8..]);
+            if ![BTSNOOP_H1, BTSNOOP_H4, BTSNOOP_MONITOR].contains(&datalink) {
+                return Err(HciError::UnsupportedLinkType(datalink));
+            }
+            Format::Btsnoop { datalink }
+        } else {
+            let (big_endian, nanos) = match (le32(&magic), be32(&magic)) {
+                (PCAP_MAGIC, _) => (false, false),
+                (PCAP_MAGIC_NANOS, _) => (false, true),
+                (_, PCAP_MAGIC) => (true, false),
+                (_, PCAP_MAGIC_NANOS) => (true, true),
+                _ => return Err(HciError::UnknownFormat),
+            };
+            let mut header = [0; 20];
+            input.read_exact(&mut header)?;
+            let snaplen = read32(&header[12..], big_endian) as usize;
+            let link_type = read32(&header[16..], big_endian);
+            if ![DLT_BLUETOOTH_HCI_H4, DLT_BLUETOOTH_HCI_H4_WITH_PHDR].contains(&link_type) {
+                return Err(HciError::UnsupportedLinkType(link_type));
+            }
+            Format::Pcap {
+                big_endian,
+                nanos,
+                link_type,
+                snaplen,
+            }
+        };
+        Ok(HciReader {
+            input,
+            format,
+            start: None,
+            packet: 0,
+            partial: HashMap::new(),
+            signaled: HashSet::new(),
+            interrupt: HashSet::new(),
+        })
+    }
+
+    /// Reads exactly `buf`, or returns `false` at the end of the file.
+    fn read_header(&mut self, buf: &mut [u8]) -> Result<bool, HciError> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.input.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(HciError::Truncated),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_data(&mut self, len: usize, max_len: usize) -> Result<Vec<u8>, HciError> {
+        if len > max_len.min(MAX_PACKET_SIZE) {
+            return Err(HciError::TooLong {
+                packet: self.packet + 1,
+                len,
+            });
+        }
+        let mut data = vec![0; len];
+        self.input.read_exact(&mut data).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                HciError::Truncated
+            } else {
+                e.into()
+            }
+        })?;
+        Ok(data)
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, HciError> {
+        match self.format {
+            Format::Btsnoop { datalink } => {
+                let mut header = [0; 24];
+                if !self.read_header(&mut header)? {
+                    return Ok(None);
+                }
+                let data = self.read_data(be32(&header[4..]) as usize, MAX_PACKET_SIZE)?;
+                let flags = be32(&header[8..]);
+                let micros = u64::from_be_bytes([
+                    header[16], header[17], header[18], header[19], header[20], header[21],
+                    header[22], header[23],
+                ]);
+                let (received, acl) = match datalink {
+                    BTSNOOP_H1 => (Some(flags & 1 == 1), Some(data).filter(|_| flags & 2 == 0)),
+                    BTSNOOP_H4 => (Some(flags & 1 == 1), h4_acl(data)),
+                    _ => match flags & 0xffff {
+                        MONITOR_ACL_TX => (Some(false), Some(data)),
+                        MONITOR_ACL_RX => (Some(true), Some(data)),
+                        _ => (None, None),
+                    },
+                };
+                Ok(Some(Record {
+                    timestamp: Duration::from_micros(micros),
+                    received,
+                    acl,
+                }))
+            }
+            Format::Pcap {
+                big_endian,
+                nanos,
+                link_type,
+                snaplen,
+            } => {
+                let mut header = [0; 16];
+                if !self.read_header(&mut header)? {
+                    return Ok(None);
+                }
+                let len = read32(&header[8..], big_endian) as usize;
+                let mut data = self.read_data(len, snaplen)?;
+                let secs = read32(&header, big_endian) as u64;
+                let fraction = read32(&header[4..], big_endian);
+                let timestamp = if nanos {
+                    Duration::new(secs, fraction)
+                } else {
+                    Duration::new(secs, 0) + Duration::from_micros(fraction as u64)
+                };
+                let mut received = None;
+                if link_type == DLT_BLUETOOTH_HCI_H4_WITH_PHDR {
+                    if data.len() < 4 {
+                        return Err(HciError::Truncated);
+                    }
+                    received = Some(be32(&data) == 1);
+                    data.drain(..4);
+                }
+                Ok(Some(Record {
+                    timestamp,
+                    received,
+                    acl: h4_acl(data),
+                }))
+            }
+        }
+    }
+
+    /// Adds an ACL fragment, and returns the connection handle, CID and
+    /// payload of the L2CAP packet it completes.
+    fn reassemble(&mut self, received: Option<bool>, acl: &[u8]) -> Option<(u16, u16, Vec<u8>)> {
+        if acl.len() < 4 {
+            return None;
+        }
+        let handle = le16(acl) & 0x0fff;
+        let data = &acl[4..(4 + le16(&acl[2..]) as usize).min(acl.len())];
+        let key = (handle, received);
+        if (acl[1] >> 4) & 0b11 == ACL_CONTINUATION {
+            self.partial.get_mut(&key)?.extend_from_slice(data);
+        } else {
+            self.partial.insert(key, data.to_vec());
+        }
+
+        let packet = &self.partial[&key];
+        if packet.len() < 4 || packet.len() < 4 + le16(packet) as usize {
+            return None;
+        }
+        let packet = self.partial.remove(&key)?;
+        let len = le16(&packet) as usize;
+        Some((handle, le16(&packet[2..]), packet[4..4 + len].to_vec()))
+    }
+
+    /// Returns the payload of the packets on the HID interrupt channels.
+    fn hid_payload(
+        &mut self,
+        handle: u16,
+        received: Option<bool>,
+        cid: u16,
+        payload: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if cid == L2CAP_SIGNALING_CID {
+            self.handle_signaling(handle, received, &payload);
+            None
+        } else if self.interrupt.contains(&(handle, received, cid))
+            || (!self.signaled.contains(&handle) && cid >= L2CAP_FIRST_DYNAMIC_CID)
+        {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+
+    /// Follows the connections to the HID interrupt PSM. Each side picks the
+    /// CID of the packets it receives, so a CID is only valid in one direction
+    /// when it is known.
+    fn handle_signaling(&mut self, handle: u16, received: Option<bool>, mut commands: &[u8]) {
+        let sent = received.map(|received| !received);
+        while commands.len() >= 4 {
+            let code = commands[0];
+            let len = (le16(&commands[2..]) as usize).min(commands.len() - 4);
+            let data = &commands[4..4 + len];
+            match code {
+                L2CAP_CONNECTION_REQUEST if data.len() >= 4 => {
+                    self.signaled.insert(handle);
+                    if le16(data) == HID_INTERRUPT_PSM {
+                        self.interrupt.insert((handle, sent, le16(&data[2..])));
+                    }
+                }
+                L2CAP_CONNECTION_RESPONSE if data.len() >= 6 => {
+                    let (dest, source, result) = (le16(data), le16(&data[2..]), le16(&data[4..]));
+                    if result == 0 && self.interrupt.contains(&(handle, received, source)) {
+                        self.interrupt.insert((handle, sent, dest));
+                    }
+                }
+                _ => {}
+            }
+            commands = &commands[4 + len..];
+        }
+    }
+}
+
+impl<R: Read> Iterator for HciReader<R> {
+    type Item = Result<TraceEntry, HciError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            self.packet += 1;
+            let start = *self.start.get_or_insert(record.timestamp);
+            let acl = match record.acl {
+                Some(acl) => acl,
+                None => continue,
+            };
+            let payload = match self.reassemble(record.received, &acl) {
+                Some((handle, cid, payload)) => {
+                    self.hid_payload(handle, record.received, cid, payload)
+                }
+                None => None,
+            };
+            let (direction, report) = match payload.as_ref().and_then(|p| p.split_first()) {
+                Some((&INPUT_HEADER, report)) => (Direction::Input, report),
+                Some((&OUTPUT_HEADER, report)) => (Direction::Output, report),
+                _ => continue,
+            };
+            let timestamp = record.timestamp.checked_sub(start).unwrap_or_default();
+            return Some(
+                TraceEntry::from_bytes(timestamp, direction, report).map_err(|error| {
+                    HciError::InvalidReport {
+                        packet: self.packet,
+                        error,
+                    }
+                }),
+            );
+        }
+    }
+}
+
+fn h4_acl(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    if data.first() == Some(&H4_ACL) {
+        data.remove(0);
+        Some(data)
+    } else {
+        None
+    }
+}
+
+fn le16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn le32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read32(data: &[u8], big_endian: bool) -> u32 {
+    if big_endian {
+        be32(data)
+    } else {
+        le32(data)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn read_captures() {
+    use crate::{output::OutputReportId, InputReportId};
+
+    fn acl(handle: u16, start: bool, data: &[u8]) -> Vec<u8> {
+        let flags = if start { 0x2000 } else { 0x1000 };
+        let mut acl = (handle | flags).to_le_bytes().to_vec();
+        acl.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        acl.extend_from_slice(data);
+        acl
+    }
+    fn l2cap(cid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = (payload.len() as u16).to_le_bytes().to_vec();
+        packet.extend_from_slice(&cid.to_le_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    let input = l2cap(
+        0x41,
+        &[0xa1, 0x3f, 0, 0, 8, 0, 0x80, 0, 0x80, 0, 0x80, 0, 0x80],
+    );
+    let packets = [
+        // Connection request from the host, and response from the controller
+        (
+            false,
+            acl(0xb, true, &l2cap(1, &[2, 1, 4, 0, 0x13, 0, 0x41, 0])),
+        ),
+        (
+            true,
+            acl(
+                0xb,
+                true,
+                &l2cap(1, &[3, 1, 8, 0, 0x45, 0, 0x41, 0, 0, 0, 0, 0]),
+            ),
+        ),
+        // Fragmented input report
+        (true, acl(0xb, true, &input[..9])),
+        (true, acl(0xb, false, &input[9..])),
+        // Control channel, and interrupt CID of the other direction
+        (false, acl(0xb, true, &l2cap(0x50, &[0xa2, 0x10]))),
+        (true, acl(0xb, true, &l2cap(0x45, &input[4..]))),
+        (
+            false,
+            acl(
+                0xb,
+                true,
+                &l2cap(0x45, &[0xa2, 0x10, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ),
+        ),
+    ];
+
+    let mut btsnoop = b"btsnoop\0\0\0\0\x01\0\0\x03\xea".to_vec();
+    for (i, (received, packet)) in packets.iter().enumerate() {
+        let mut data = vec![H4_ACL];
+        data.extend_from_slice(packet);
+        for field in [data.len() as u32, data.len() as u32, *received as u32, 0].iter() {
+            btsnoop.extend_from_slice(&field.to_be_bytes());
+        }
+        btsnoop.extend_from_slice(&(1_000_000 + i as u64 * 1000).to_be_bytes());
+        btsnoop.extend_from_slice(&data);
+    }
+    let entries: Vec<_> = HciReader::new(&btsnoop[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].timestamp, Duration::from_millis(3));
+    assert_eq!(entries[0].input().unwrap().id(), InputReportId::Normal);
+    assert_eq!(
+        entries[1].output().unwrap().id(),
+        OutputReportId::RumbleOnly
+    );
+    assert!(matches!(
+        HciReader::new(&btsnoop[..btsnoop.len() - 1])
+            .unwrap()
+            .last(),
+        Some(Err(HciError::Truncated))
+    ));
+    btsnoop[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(
+        HciReader::new(&btsnoop[..]).unwrap().next(),
+        Some(Err(HciError::TooLong { packet: 1, .. }))
+    ));
+
+    // Capture started after the connection
+    let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+    pcap.extend_from_slice(&[0; 8]);
+    pcap.extend_from_slice(&0xffffu32.to_le_bytes());
+    pcap.extend_from_slice(&DLT_BLUETOOTH_HCI_H4.to_le_bytes());
+    let mut data = vec![H4_ACL];
+    data.extend_from_slice(&acl(0xc, true, &input));
+    for field in [5, 0, data.len() as u32, data.len() as u32].iter() {
+        pcap.extend_from_slice(&field.to_le_bytes());
+    }
+    pcap.extend_from_slice(&data);
+    assert!(is_hci_capture(&pcap));
+    let entries: Vec<_> = HciReader::new(&pcap[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].direction(), Direction::Input);
+    pcap[16..20].copy_from_slice(&4u32.to_le_bytes());
+    assert!(matches!(
+        HciReader::new(&pcap[..]).unwrap().next(),
+        Some(Err(HciError::TooLong { packet: 1, .. }))
+    ));
+}
@@ -1,5 +1,5 @@
 use crate::common::*;
-use cgmath::{Array, ElementWise, Vector3};
+use cgmath::{ElementWise, Vector3};
 use std::fmt;
 
 pub const IMU_SAMPLE_DURATION: f64 = 0.005;
@@ -22,6 +22,13 @@ pub struct Frame {
 }
 
 impl Frame {
+    pub fn new(raw_accel: Vector3<f64>, raw_gyro: Vector3<f64>) -> Frame {
+        Frame {
+            raw_accel: raw_from_vector(raw_accel),
+            raw_gyro: raw_from_vector(raw_gyro),
+        }
+    }
+
     pub fn raw_ringcon(&self) -> u16 {
         let raw_self = unsafe {
             std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of_val(self))
@@ -37,18 +44,55 @@ impl Frame {
     }
 
     /// Calculation from <https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering/blob/master/imu_sensor_notes.md#accelerometer---acceleration-in-g>
-    pub fn accel_g(&self, offset: Vector3<f64>, _sens: AccSens) -> Vector3<f64> {
-        // TODO: handle sens
-        (self.raw_accel() * 4.).div_element_wise(Vector3::from_value(16383.) - offset)
+    ///
+    /// `offset` and `factor` come from the `SensorCalibration`, which is
+    /// measured at the ±8G range.
+    pub fn accel_g(
+        &self,
+        offset: Vector3<f64>,
+        factor: Vector3<f64>,
+        sens: AccSens,
+    ) -> Vector3<f64> {
+        let range = sens.range_g() as f64 / AccSens::G8.range_g() as f64;
+        self.raw_accel()
+            .mul_element_wise(calibration_coeff(offset, factor, ACC_DEFAULT_FACTOR))
+            * (4. * range)
     }
 
     /// The rotation described in this frame.
     /// <https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering/blob/master/imu_sensor_notes.md#gyroscope-calibrated---rotation-in-degreess---dps>
-    pub fn rotation_dps(&self, offset: Vector3<f64>, sens: GyroSens) -> Vector3<f64> {
-        (self.raw_gyro() - offset) * sens.factor()
+    ///
+    /// `offset` and `factor` come from the `SensorCalibration`, which is
+    /// measured at the ±2000DPS range.
+    pub fn rotation_dps(
+        &self,
+        offset: Vector3<f64>,
+        factor: Vector3<f64>,
+        sens: GyroSens,
+    ) -> Vector3<f64> {
+        let range = sens.range_dps() as f64 / GyroSens::DPS2000.range_dps() as f64;
+        (self.raw_gyro() - offset).mul_element_wise(calibration_coeff(
+            offset,
+            factor,
+            GYRO_DEFAULT_FACTOR,
+        )) * (936. * range)
     }
 }
 
+/// Usual accelerometer factor of the `SensorCalibration`.
+pub const ACC_DEFAULT_FACTOR: f64 = 16384.;
+/// Usual gyroscope factor of the `SensorCalibration`.
+pub const GYRO_DEFAULT_FACTOR: f64 = 13371.;
+
+/// `1 / (factor - offset)` for each axis, using `default` when the factor
+/// is missing from the calibration.
+fn calibration_coeff(offset: Vector3<f64>, factor: Vector3<f64>, default: f64) -> Vector3<f64> {
+    factor.zip(offset, |factor, offset| {
+        let factor = if factor > 0. { factor } else { default };
+        1. / (factor - offset)
+    })
+}
+
 impl fmt::Debug for Frame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("imu::Frame")
@@ -163,3 +207,56 @@ impl Default for AccAntiAliasing {
         AccAntiAliasing::Hz100
     }
 }
+
+#[cfg(test)]
+#[test]
+fn convert_frame() {
+    use cgmath::{vec3, InnerSpace};
+
+    let zero = vec3(0., 0., 0.);
+    let acc_factor = vec3(16384., 16384., 16384.);
+    let gyro_factor = vec3(13371., 13371., 13371.);
+    let close = |a: Vector3<f64>, b: Vector3<f64>| (a - b).magnitude() < 1e-3;
+
+    // At rest, 1G is 4096 at the ±8G range and 16384 at the ±2G range.
+    let frame = Frame::new(vec3(0., 0., 4096.), vec3(1000., -1000., 0.));
+    assert!(close(
+        frame.accel_g(zero, acc_factor, AccSens::G8),
+        vec3(0., 0., 1.)
+    ));
+    assert!(close(
+        frame.accel_g(zero, acc_factor, AccSens::G2),
+        vec3(0., 0., 0.25)
+    ));
+    assert!(close(
+        frame.accel_g(zero, zero, AccSens::G8),
+        vec3(0., 0., 1.)
+    ));
+    let frame = Frame::new(vec3(16384., 0., 0.), zero);
+    assert!(close(
+        frame.accel_g(zero, acc_factor, AccSens::G2),
+        vec3(1., 0., 0.)
+    ));
+    assert!(close(
+        frame.accel_g(vec3(384., 0., 0.), vec3(16384., 0., 0.), AccSens::G2),
+        vec3(1.024, 0., 0.)
+    ));
+
+    let frame = Frame::new(zero, vec3(1000., -1000., 0.));
+    assert!(close(
+        frame.rotation_dps(zero, gyro_factor, GyroSens::DPS2000),
+        vec3(70.0022, -70.0022, 0.)
+    ));
+    assert!(close(
+        frame.rotation_dps(zero, gyro_factor, GyroSens::DPS250),
+        vec3(8.7503, -8.7503, 0.)
+    ));
+    assert!(close(
+        frame.rotation_dps(
+            vec3(10., 10., 10.),
+            vec3(13381., 13381., 0.),
+            GyroSens::DPS2000
+        ),
+        vec3(69.3022, -70.7023, -0.7005)
+    ));
+}
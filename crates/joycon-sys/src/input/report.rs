@@ -65,18 +65,17 @@ impl InputReport {
         unsafe { std::slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of_val(self)) }
     }
 
-    pub fn validate(&self) {
-        match self.id.try_into() {
-            Some(_) => {
-                if let Some(rep) = self.subcmd_reply() {
-                    rep.validate()
-                }
-                if let Some(rep) = self.mcu_report() {
-                    rep.validate()
-                }
-            }
-            None => panic!("unknown report id {:x?}", self.id),
+    pub fn validate(&self) -> Result<(), InvalidReportError> {
+        if self.id.try_into().is_none() {
+            return Err(InvalidReportError::UnknownReportId(self.id));
+        }
+        if let Some(rep) = self.subcmd_reply() {
+            rep.validate()?;
+        }
+        if let Some(rep) = self.mcu_report() {
+            rep.validate()
         }
+        Ok(())
     }
 
     pub fn standard(&self) -> Option<&StandardInputReport> {
@@ -149,6 +148,7 @@ raw_enum! {
         mcu_report mcu_report_mut: SetMCUConf = MCUReport,
         mcu_state_result mcu_state_result_mut: SetMCUState = (),
         set_unknown_data set_unknown_data_mut: SetUnknownData = (),
+        reset_unknown_data reset_unknown_data_mut: ResetUnknownData = (),
         player_lights_result player_lights_result_mut: SetPlayerLights = (),
         home_light_result home_light_result_mut: SetHomeLight = (),
         imu_mode_result imu_mode_result_mut: SetIMUMode = (),
@@ -163,12 +163,18 @@ raw_enum! {
 }
 
 impl SubcommandReply {
-    pub fn validate(&self) {
-        assert!(
-            self.id.try_into().is_some(),
-            "invalid subcmd id{:?}",
-            self.id
-        )
+    pub fn nack(id: RawId<SubcommandId>) -> SubcommandReply {
+        let mut reply = SubcommandReply::new();
+        reply.ack = Ack::nack();
+        reply.id = id;
+        reply
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidReportError> {
+        if self.id.try_into().is_none() {
+            return Err(InvalidReportError::UnknownSubcommandId(self.id));
+        }
+        Ok(())
     }
 
     pub fn is_spi_write_success(&self) -> Option<bool> {
@@ -177,11 +183,38 @@ impl SubcommandReply {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum InvalidReportError {
+    UnknownReportId(RawId<InputReportId>),
+    UnknownSubcommandId(RawId<SubcommandId>),
+}
+
+impl std::error::Error for InvalidReportError {}
+
+impl fmt::Display for InvalidReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidReportError::UnknownReportId(id) => write!(f, "unknown input report {:?}", id),
+            InvalidReportError::UnknownSubcommandId(id) => {
+                write!(f, "unknown subcommand reply {:?}", id)
+            }
+        }
+    }
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub struct Ack(u8);
 
 impl Ack {
+    pub fn new(data: u8) -> Ack {
+        Ack(0x80 | data)
+    }
+
+    pub fn nack() -> Ack {
+        Ack(0)
+    }
+
     pub fn is_ok(self) -> bool {
         (self.0 & 0x80) != 0
     }
@@ -218,6 +251,24 @@ pub struct DeviceInfo {
     pub use_spi_colors: RawId<UseSPIColors>,
 }
 
+impl DeviceInfo {
+    pub fn new(
+        firmware_version: FirmwareVersion,
+        which_controller: WhichController,
+        mac_address: MACAddress,
+        use_spi_colors: RawId<UseSPIColors>,
+    ) -> DeviceInfo {
+        DeviceInfo {
+            firmware_version,
+            which_controller: which_controller.into(),
+            _something: 2,
+            mac_address,
+            _somethingelse: 1,
+            use_spi_colors,
+        }
+    }
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct FirmwareVersion(pub [u8; 2]);
@@ -3,14 +3,14 @@ use std::fmt;
 
 bitfield::bitfield! {
     #[repr(transparent)]
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, Default)]
     pub struct DeviceStatus(u8);
     impl Debug;
 
-    pub connected, _: 0;
-    pub u8, into DeviceType, device_type, _: 2, 1;
-    pub charging, _: 4;
-    pub u8, into BatteryLevel, battery_level, _: 7, 5;
+    pub connected, set_connected: 0;
+    pub u8, into DeviceType, device_type, set_device_type: 2, 1;
+    pub charging, set_charging: 4;
+    pub u8, into BatteryLevel, battery_level, set_battery_level: 7, 5;
 }
 
 #[derive(Debug, Copy, Clone, FromPrimitive)]
@@ -134,28 +134,28 @@ bitfield::bitfield! {
     #[derive(Copy, Clone, Default)]
     pub struct RightButtons(u8);
     impl Debug;
-    pub y, _: 0;
-    pub x, _: 1;
-    pub b, _: 2;
-    pub a, _: 3;
-    pub sr, _: 4;
-    pub sl, _: 5;
-    pub r, _: 6;
-    pub zr, _: 7;
+    pub y, set_y: 0;
+    pub x, set_x: 1;
+    pub b, set_b: 2;
+    pub a, set_a: 3;
+    pub sr, set_sr: 4;
+    pub sl, set_sl: 5;
+    pub r, set_r: 6;
+    pub zr, set_zr: 7;
 }
 bitfield::bitfield! {
     #[repr(transparent)]
     #[derive(Copy, Clone, Default)]
     pub struct MiddleButtons(u8);
     impl Debug;
-    pub minus, _: 0;
-    pub plus, _: 1;
-    pub rstick, _: 2;
-    pub lstick, _: 3;
-    pub home, _: 4;
-    pub capture, _: 5;
+    pub minus, set_minus: 0;
+    pub plus, set_plus: 1;
+    pub rstick, set_rstick: 2;
+    pub lstick, set_lstick: 3;
+    pub home, set_home: 4;
+    pub capture, set_capture: 5;
     pub _unused, _: 6;
-    pub charging_grip, _: 7;
+    pub charging_grip, set_charging_grip: 7;
 }
 
 bitfield::bitfield! {
@@ -163,14 +163,14 @@ bitfield::bitfield! {
     #[derive(Copy, Clone, Default)]
     pub struct LeftButtons(u8);
     impl Debug;
-    pub down, _: 0;
-    pub up, _: 1;
-    pub right, _: 2;
-    pub left, _: 3;
-    pub sr, _: 4;
-    pub sl, _: 5;
-    pub l, _: 6;
-    pub zl, _: 7;
+    pub down, set_down: 0;
+    pub up, set_up: 1;
+    pub right, set_right: 2;
+    pub left, set_left: 3;
+    pub sr, set_sr: 4;
+    pub sl, set_sl: 5;
+    pub l, set_l: 6;
+    pub zl, set_zl: 7;
 }
 
 pub enum Button {
@@ -197,6 +197,13 @@ pub struct Stick {
 }
 
 impl Stick {
+    pub fn new(x: u16, y: u16) -> Stick {
+        assert!(x <= 0xfff && y <= 0xfff);
+        Stick {
+            data: [x as u8, (x >> 8) as u8 | (y << 4) as u8, (y >> 4) as u8],
+        }
+    }
+
     pub fn x(self) -> u16 {
         u16::from(self.data[0]) | u16::from(self.data[1] & 0xf) << 8
     }
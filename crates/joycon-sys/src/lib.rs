@@ -8,12 +8,15 @@ extern crate num_derive;
 
 pub mod accessory;
 pub mod common;
+pub mod dissect;
+pub mod hci;
 pub mod imu;
 pub mod input;
 pub mod light;
 pub mod mcu;
 pub mod output;
 pub mod spi;
+pub mod trace;
 
 pub use common::*;
 pub use input::InputReport;
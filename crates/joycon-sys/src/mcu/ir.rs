@@ -98,6 +98,17 @@ pub struct IRStatus {
     pub required_fw_minor_version: U16LE,
 }
 
+impl IRStatus {
+    pub fn new(ir_mode: MCUIRMode, required_fw_version: (U16LE, U16LE)) -> IRStatus {
+        IRStatus {
+            _unknown_0x00: 0,
+            ir_mode: ir_mode.into(),
+            required_fw_major_version: required_fw_version.0,
+            required_fw_minor_version: required_fw_version.1,
+        }
+    }
+}
+
 // TODO: better debug
 #[repr(packed)]
 #[derive(Copy, Clone, Debug)]
@@ -109,6 +120,20 @@ pub struct IRRegistersSlice {
     pub values: [u8; 0x7f],
 }
 
+impl IRRegistersSlice {
+    pub fn new(page: u8, offset: u8, values: &[u8]) -> IRRegistersSlice {
+        let mut slice = IRRegistersSlice {
+            _unknown_0x00: 0,
+            page,
+            offset,
+            nb_registers: values.len() as u8,
+            values: [0; 0x7f],
+        };
+        slice.values[..values.len()].copy_from_slice(values);
+        slice
+    }
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone)]
 pub struct IRData {
@@ -122,6 +147,20 @@ pub struct IRData {
     pub img_fragment: [u8; 300],
 }
 
+impl IRData {
+    pub fn new(frag_number: u8, img_fragment: [u8; 300]) -> IRData {
+        IRData {
+            _unknown: [0; 2],
+            frag_number,
+            average_intensity: 0,
+            _unknown3: 0,
+            white_pixel_count: 0.into(),
+            ambient_noise_count: 0.into(),
+            img_fragment,
+        }
+    }
+}
+
 impl fmt::Debug for IRData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("IRData")
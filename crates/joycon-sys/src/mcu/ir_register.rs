@@ -37,6 +37,14 @@ impl Register {
         self.page
     }
 
+    pub fn offset(self) -> u8 {
+        self.offset
+    }
+
+    pub fn value(self) -> u8 {
+        self.value
+    }
+
     pub fn same_address(self, other: Register) -> bool {
         self.page == other.page && self.offset == other.offset
     }
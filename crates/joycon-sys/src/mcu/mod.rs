@@ -2,10 +2,12 @@ use crate::common::*;
 use crate::raw_enum;
 /// Cf https://github.com/CTCaer/Nintendo_Switch_Reverse_Engineering/blob/ir-nfc/mcu_ir_nfc_notes.md
 use ir::*;
+use nfc::*;
 use std::fmt;
 
 pub mod ir;
 mod ir_register;
+pub mod nfc;
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
@@ -33,8 +35,8 @@ raw_enum! {
         busy_initializing busy_initializing_mut: BusyInitializing = (),
         ir_status ir_status_mut: IRStatus = IRStatus,
         ir_registers ir_registers_mut: IRRegisters = IRRegistersSlice,
-        nfc_state nfc_state_mut: NFCState = (),
-        nfc_readdata nfc_read_data_mut: NFCReadData = (),
+        nfc_state nfc_state_mut: NFCState = NFCStatus,
+        nfc_readdata nfc_read_data_mut: NFCReadData = NFCReadData,
         empty_awaiting_cmd empty_awaiting_cmd_mut: EmptyAwaitingCmd = ()
     }
 }
@@ -66,6 +68,17 @@ pub struct MCUStatus {
     pub state: RawId<MCUMode>,
 }
 
+impl MCUStatus {
+    pub fn new(fw_version: (U16LE, U16LE), state: MCUMode) -> MCUStatus {
+        MCUStatus {
+            _unknown: [0; 2],
+            fw_major_version: fw_version.0,
+            fw_minor_version: fw_version.1,
+            state: state.into(),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
 pub enum MCUCommandId {
@@ -134,6 +147,42 @@ impl MCUCommand {
         .compute_crc()
     }
 
+    pub fn cmd_id(&self) -> RawId<MCUCommandId> {
+        self.cmd_id
+    }
+
+    pub fn subcmd_id(&self) -> RawId<MCUSubCommandId> {
+        self.subcmd_id
+    }
+
+    pub fn mcu_mode(&self) -> Option<RawId<MCUMode>> {
+        if self.cmd_id == MCUCommandId::ConfigureMCU
+            && self.subcmd_id == MCUSubCommandId::SetMCUMode
+        {
+            Some(unsafe { self.u.mcu_mode })
+        } else {
+            None
+        }
+    }
+
+    pub fn ir_mode(&self) -> Option<MCUIRModeData> {
+        if self.subcmd_id == MCUSubCommandId::SetIRMode {
+            Some(unsafe { self.u.ir_mode })
+        } else {
+            None
+        }
+    }
+
+    pub fn ir_registers(&self) -> Option<MCURegisters> {
+        if self.cmd_id == MCUCommandId::ConfigureIR
+            && self.subcmd_id == MCUSubCommandId::WriteIRRegisters
+        {
+            Some(unsafe { self.u.regs })
+        } else {
+            None
+        }
+    }
+
     fn compute_crc(mut self) -> MCUCommand {
         unsafe {
             self.u.crc.compute_crc8(self.subcmd_id.try_into().unwrap());
@@ -231,7 +280,7 @@ raw_enum! {
     #[field crc crc_mut: MCURequestCRC]
     pub enum MCURequestEnum {
         get_mcu_status get_mcu_status_mut: GetMCUStatus = (),
-        get_ncf_data get_nfc_data_mut: GetNFCData = (),
+        get_ncf_data get_nfc_data_mut: GetNFCData = NFCRequest,
         get_ir_data get_ir_data_mut: GetIRData = IRRequest
     }
 }
@@ -252,6 +301,14 @@ impl From<IRRequestEnum> for MCURequest {
     }
 }
 
+impl From<NFCRequest> for MCURequest {
+    fn from(nfc_request: NFCRequest) -> Self {
+        let mut request: MCURequest = MCURequestEnum::GetNFCData(nfc_request).into();
+        request.crc_mut().compute_crc8_nfc();
+        request
+    }
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone)]
 pub struct MCURequestCRC {
@@ -269,6 +326,11 @@ impl MCURequestCRC {
             IRRequestId::ReadRegister => 0x00,
         };
     }
+
+    pub fn compute_crc8_nfc(&mut self) {
+        self.crc = compute_crc8(0, &self.bytes);
+        self._padding_0xff = 0xff;
+    }
 }
 
 fn compute_crc8(id: u8, bytes: &[u8]) -> u8 {
@@ -0,0 +1,525 @@
+use crate::mcu::*;
+
+/// Size of a full NTAG215 dump (135 pages of 4 bytes), as used by amiibos.
+pub const NTAG215_SIZE: usize = 540;
+/// Size of a NTAG page, the unit of reads and writes.
+pub const NTAG_PAGE_SIZE: usize = 4;
+/// Number of pages of a NTAG215.
+pub const NTAG215_NB_PAGES: usize = NTAG215_SIZE / NTAG_PAGE_SIZE;
+
+/// Pages before hold the UID, the static lock bytes and the capability container.
+pub const NTAG215_FIRST_USER_PAGE: u8 = 0x04;
+/// Pages after hold the dynamic lock bytes, the configuration and the password.
+pub const NTAG215_LAST_USER_PAGE: u8 = 0x81;
+
+/// Whether `page` is free user memory, as opposed to lock and configuration pages.
+pub fn is_ntag215_user_page(page: usize) -> bool {
+    (NTAG215_FIRST_USER_PAGE as usize..=NTAG215_LAST_USER_PAGE as usize).contains(&page)
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum NFCRequestId {
+    StartPolling = 0x01,
+    StopPolling = 0x02,
+    GetStatus = 0x04,
+    ReadNTAG = 0x06,
+    WriteNTAG = 0x08,
+}
+
+raw_enum! {
+    #[id: NFCRequestId]
+    #[post_id header header_mut: NFCRequestHeader]
+    #[union: NFCRequestUnion]
+    #[struct: NFCRequest]
+    #[raw [u8; 31]]
+    pub enum NFCRequestEnum {
+        start_polling_args start_polling_args_mut: StartPolling = NFCPollingArgs,
+        stop_polling_args stop_polling_args_mut: StopPolling = (),
+        get_status_args get_status_args_mut: GetStatus = (),
+        read_ntag read_ntag_mut: ReadNTAG = NTAGReadArgs,
+        write_ntag_data write_ntag_data_mut: WriteNTAG = [u8; 31]
+    }
+}
+
+impl NFCRequest {
+    pub fn start_polling() -> Self {
+        NFCRequest::with_len(
+            NFCRequestEnum::StartPolling(NFCPollingArgs::default()),
+            std::mem::size_of::<NFCPollingArgs>() as u8,
+        )
+    }
+
+    pub fn stop_polling() -> Self {
+        NFCRequest::with_len(NFCRequestEnum::StopPolling(()), 0)
+    }
+
+    pub fn get_status() -> Self {
+        NFCRequest::with_len(NFCRequestEnum::GetStatus(()), 0)
+    }
+
+    /// Acknowledge a packet of a multi-packet reply.
+    pub fn ack(packet_number: u8) -> Self {
+        let mut request = NFCRequest::get_status();
+        request.header.ack_number = packet_number;
+        request
+    }
+
+    pub fn read_ntag215(uid: Option<[u8; 7]>) -> Self {
+        NFCRequest::with_len(
+            NFCRequestEnum::ReadNTAG(NTAGReadArgs::ntag215(uid)),
+            NTAGReadArgs::DATA_LEN,
+        )
+    }
+
+    /// Split a write of `data` at `start_page` of the tag `uid` into request packets.
+    pub fn write_ntag(uid: [u8; 7], start_page: u8, data: &[u8]) -> Vec<Self> {
+        assert_eq!(data.len() % NTAG_PAGE_SIZE, 0);
+        let header = NTAGWriteHeader {
+            _unknown_0xd0: 0xd0,
+            _unknown_0x07: 0x07,
+            uid,
+            check_uid: Bool::True.into(),
+            _unknown_0x00: 0,
+            start_page,
+            nb_pages: (data.len() / NTAG_PAGE_SIZE) as u8,
+        };
+        let mut payload = unsafe {
+            std::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                std::mem::size_of_val(&header),
+            )
+        }
+        .to_vec();
+        payload.extend_from_slice(data);
+
+        let chunks = payload.chunks(31);
+        let nb_packets = chunks.len();
+        chunks
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut raw = [0; 31];
+                raw[..chunk.len()].copy_from_slice(chunk);
+                let mut request =
+                    NFCRequest::with_len(NFCRequestEnum::WriteNTAG(raw), chunk.len() as u8);
+                request.header.packet_number = i as u8;
+                if i + 1 < nb_packets {
+                    request.header.flags = 0;
+                }
+                request
+            })
+            .collect()
+    }
+
+    fn with_len(request: NFCRequestEnum, data_len: u8) -> Self {
+        let mut request = NFCRequest::from(request);
+        request.header = NFCRequestHeader {
+            packet_number: 0,
+            ack_number: 0,
+            flags: NFC_FLAG_LAST_PACKET,
+            data_len,
+        };
+        request
+    }
+}
+
+/// Set on the last (or only) packet of a request.
+const NFC_FLAG_LAST_PACKET: u8 = 0x08;
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NFCRequestHeader {
+    pub packet_number: u8,
+    pub ack_number: u8,
+    pub flags: u8,
+    pub data_len: u8,
+}
+
+impl NFCRequestHeader {
+    pub fn is_last_packet(&self) -> bool {
+        self.flags & NFC_FLAG_LAST_PACKET != 0
+    }
+}
+
+/// Values from jc_toolkit, meaning unknown.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NFCPollingArgs {
+    _unknown_0x00: u8,
+    _unknown_0xffff: [u8; 2],
+    _unknown_0x00_2: u8,
+    _unknown_0x01: u8,
+}
+
+impl Default for NFCPollingArgs {
+    fn default() -> Self {
+        NFCPollingArgs {
+            _unknown_0x00: 0x00,
+            _unknown_0xffff: [0xff; 2],
+            _unknown_0x00_2: 0x00,
+            _unknown_0x01: 0x01,
+        }
+    }
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NTAGPageRange {
+    pub start: u8,
+    pub end: u8,
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NTAGReadArgs {
+    _unknown_0xd0: u8,
+    _unknown_0x07: u8,
+    /// Only read the tag with this UID, if `check_uid` is set.
+    pub uid: [u8; 7],
+    pub check_uid: RawId<Bool>,
+    _unknown_0x00: u8,
+    pub nb_ranges: u8,
+    /// Inclusive page ranges to read.
+    pub ranges: [NTAGPageRange; 3],
+    _unknown_0x00_2: u8,
+}
+
+impl NTAGReadArgs {
+    const DATA_LEN: u8 = std::mem::size_of::<NTAGReadArgs>() as u8;
+
+    pub fn ntag215(uid: Option<[u8; 7]>) -> NTAGReadArgs {
+        NTAGReadArgs {
+            _unknown_0xd0: 0xd0,
+            _unknown_0x07: 0x07,
+            uid: uid.unwrap_or_default(),
+            check_uid: Bool::from(uid.is_some()).into(),
+            _unknown_0x00: 0,
+            nb_ranges: 3,
+            ranges: [
+                NTAGPageRange {
+                    start: 0x00,
+                    end: 0x3b,
+                },
+                NTAGPageRange {
+                    start: 0x3c,
+                    end: 0x77,
+                },
+                NTAGPageRange {
+                    start: 0x78,
+                    end: 0x86,
+                },
+            ],
+            _unknown_0x00_2: 0,
+        }
+    }
+
+    /// UID of the tag that must be read, if any.
+    pub fn checked_uid(&self) -> Option<[u8; 7]> {
+        if matches!(self.check_uid.try_into(), Some(Bool::True)) {
+            Some(self.uid)
+        } else {
+            None
+        }
+    }
+}
+
+/// Start of the payload of a write, followed by the pages content.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NTAGWriteHeader {
+    _unknown_0xd0: u8,
+    _unknown_0x07: u8,
+    pub uid: [u8; 7],
+    pub check_uid: RawId<Bool>,
+    _unknown_0x00: u8,
+    pub start_page: u8,
+    pub nb_pages: u8,
+}
+
+impl NTAGWriteHeader {
+    /// Splits the payload reassembled from the write packets into the header
+    /// and the pages content.
+    pub fn parse(payload: &[u8]) -> Option<(NTAGWriteHeader, &[u8])> {
+        let size = std::mem::size_of::<NTAGWriteHeader>();
+        if payload.len() < size {
+            return None;
+        }
+        let header =
+            unsafe { std::ptr::read_unaligned(payload.as_ptr() as *const NTAGWriteHeader) };
+        Some((header, &payload[size..]))
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum NFCState {
+    None = 0x00,
+    Polling = 0x01,
+    PendingRead = 0x02,
+    Writing = 0x03,
+    ReadFinished = 0x04,
+    WriteFinished = 0x05,
+    PassThrough = 0x06,
+    Error = 0x07,
+    Deactivated = 0x08,
+    TagDetected = 0x09,
+    FaultyTag = 0x0a,
+    MifareFinished = 0x0b,
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+pub enum NFCTagType {
+    /// NFC Forum Type 2 tag, like the NTAG215 used by amiibos.
+    NTAG = 0x02,
+    Mifare = 0x04,
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NFCReportHeader {
+    /// 0 on success
+    pub result: u8,
+    _input_type: u8,
+    pub packet_number: u8,
+    _unknown_0x00: u8,
+    _unknown_0x0931: [u8; 2],
+}
+
+impl NFCReportHeader {
+    fn new(input_type: u8, packet_number: u8) -> NFCReportHeader {
+        NFCReportHeader {
+            result: 0,
+            _input_type: input_type,
+            packet_number,
+            _unknown_0x00: 0,
+            _unknown_0x0931: [0x09, 0x31],
+        }
+    }
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct NFCStatus {
+    pub header: NFCReportHeader,
+    pub state: RawId<NFCState>,
+    pub tag: NFCTagInfo,
+}
+
+impl NFCStatus {
+    pub fn new(state: NFCState, tag: NFCTagInfo) -> NFCStatus {
+        NFCStatus {
+            header: NFCReportHeader::new(0x05, 0),
+            state: state.into(),
+            tag,
+        }
+    }
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct NFCTagInfo {
+    _unknown: [u8; 3],
+    pub tag_detected: RawId<Bool>,
+    _unknown_0x01: u8,
+    pub tag_type: RawId<NFCTagType>,
+    _unknown_0x00: u8,
+    uid_len: u8,
+    uid: [u8; 10],
+}
+
+impl NFCTagInfo {
+    /// Information of a detected tag, `uid` is truncated to 10 bytes.
+    pub fn new(tag_type: NFCTagType, uid: &[u8]) -> NFCTagInfo {
+        let uid_len = uid.len().min(10);
+        let mut info = NFCTagInfo {
+            tag_detected: Bool::True.into(),
+            tag_type: tag_type.into(),
+            uid_len: uid_len as u8,
+            ..NFCTagInfo::none()
+        };
+        info.uid[..uid_len].copy_from_slice(&uid[..uid_len]);
+        info
+    }
+
+    /// No tag on the reader.
+    pub fn none() -> NFCTagInfo {
+        NFCTagInfo {
+            _unknown: [0; 3],
+            tag_detected: Bool::False.into(),
+            _unknown_0x01: 0x01,
+            tag_type: RawId::new(0),
+            _unknown_0x00: 0,
+            uid_len: 0,
+            uid: [0; 10],
+        }
+    }
+
+    pub fn uid(&self) -> &[u8] {
+        &self.uid[..(self.uid_len as usize).min(self.uid.len())]
+    }
+}
+
+impl fmt::Debug for NFCTagInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NFCTagInfo")
+            .field("tag_detected", &self.tag_detected)
+            .field("tag_type", &self.tag_type)
+            .field("uid", &self.uid())
+            .finish()
+    }
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct NFCReadData {
+    pub header: NFCReportHeader,
+    payload: [u8; 306],
+}
+
+impl NFCReadData {
+    const FIRST_PACKET_DATA_OFFSET: usize = 60;
+    const FIRST_PACKET_DATA_LEN: usize = 245;
+    const SECOND_PACKET_DATA_LEN: usize = NTAG215_SIZE - NFCReadData::FIRST_PACKET_DATA_LEN;
+
+    /// Packet `packet_number` (1 or 2) of the read of the NTAG215 `tag`.
+    pub fn new(packet_number: u8, tag: NFCTagInfo, dump: &[u8; NTAG215_SIZE]) -> NFCReadData {
+        let mut packet = NFCReadData {
+            header: NFCReportHeader::new(0x07, packet_number),
+            payload: [0; 306],
+        };
+        if packet_number == 1 {
+            unsafe {
+                std::ptr::write_unaligned(packet.payload[1..].as_mut_ptr() as *mut NFCTagInfo, tag)
+            };
+        }
+        if let Some((offset, len)) = packet.data().map(|(offset, data)| (offset, data.len())) {
+            let start = match packet_number {
+                1 => NFCReadData::FIRST_PACKET_DATA_OFFSET,
+                _ => 0,
+            };
+            packet.payload[start..start + len].copy_from_slice(&dump[offset..offset + len]);
+        }
+        packet
+    }
+
+    /// Tag information, only present in the first packet.
+    pub fn tag(&self) -> Option<NFCTagInfo> {
+        if self.header.packet_number == 1 {
+            // Same layout as `NFCStatus`, after the state byte.
+            Some(unsafe {
+                std::ptr::read_unaligned(self.payload[1..].as_ptr() as *const NFCTagInfo)
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Offset in the NTAG dump and content of the data of this packet.
+    pub fn data(&self) -> Option<(usize, &[u8])> {
+        match self.header.packet_number {
+            1 => Some((
+                0,
+                &self.payload[NFCReadData::FIRST_PACKET_DATA_OFFSET..]
+                    [..NFCReadData::FIRST_PACKET_DATA_LEN],
+            )),
+            2 => Some((
+                NFCReadData::FIRST_PACKET_DATA_LEN,
+                &self.payload[..NFCReadData::SECOND_PACKET_DATA_LEN],
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.header.packet_number == 2
+    }
+}
+
+impl fmt::Debug for NFCReadData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NFCReadData")
+            .field("header", &self.header)
+            .field("tag", &self.tag())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn check_input_layout() {
+    unsafe {
+        let report = crate::InputReport::new();
+        let mcu_report = report.u_mcu_report();
+        assert_eq!(
+            52,
+            offset_of(&report, &mcu_report.u.nfc_state.header.packet_number)
+        );
+        assert_eq!(56, offset_of(&report, &mcu_report.u.nfc_state.state));
+        assert_eq!(62, offset_of(&report, &mcu_report.u.nfc_state.tag.tag_type));
+        assert_eq!(65, offset_of(&report, &mcu_report.u.nfc_state.tag.uid));
+        assert_eq!(56, offset_of(&report, &mcu_report.u.nfc_readdata.payload));
+        assert_eq!(
+            362,
+            offset_of(&report, &mcu_report.u.nfc_readdata.payload)
+                + NFCReadData::FIRST_PACKET_DATA_OFFSET
+                + NFCReadData::FIRST_PACKET_DATA_LEN
+                + 1
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn write_packets() {
+    let packets = NFCRequest::write_ntag([1; 7], 0x10, &[0xaa; 8 * NTAG_PAGE_SIZE]);
+    assert_eq!(2, packets.len());
+    assert_eq!(0, packets[0].header().flags);
+    assert_eq!(31, packets[0].header().data_len);
+    assert_eq!(1, packets[1].header().packet_number);
+    assert_eq!(NFC_FLAG_LAST_PACKET, packets[1].header().flags);
+    assert_eq!(13 + 32 - 31, packets[1].header().data_len);
+    assert_eq!(0x10, packets[0].write_ntag_data().unwrap()[11]);
+}
+
+#[cfg(test)]
+#[test]
+fn read_packets() {
+    let mut dump = [0; NTAG215_SIZE];
+    for (i, byte) in dump.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let tag = NFCTagInfo::new(NFCTagType::NTAG, &[1, 2, 3, 4, 5, 6, 7]);
+    let mut read = [0; NTAG215_SIZE];
+    for packet_number in 1..=2 {
+        let packet = NFCReadData::new(packet_number, tag, &dump);
+        let (offset, data) = packet.data().unwrap();
+        read[offset..offset + data.len()].copy_from_slice(data);
+    }
+    assert_eq!(&dump[..], &read[..]);
+    let tag = NFCReadData::new(1, tag, &dump).tag().unwrap();
+    assert_eq!(&[1, 2, 3, 4, 5, 6, 7], tag.uid());
+    assert!(tag.tag_type == NFCTagType::NTAG);
+
+    let packets = NFCRequest::write_ntag([1; 7], 0x10, &dump[..8]);
+    let (header, data) = NTAGWriteHeader::parse(packets[0].write_ntag_data().unwrap()).unwrap();
+    assert_eq!(0x10, header.start_page);
+    assert_eq!(2, header.nb_pages);
+    assert_eq!(&dump[..8], &data[..8]);
+}
+
+#[cfg(test)]
+#[test]
+fn check_output_layout() {
+    unsafe {
+        let report = crate::output::OutputReport::new();
+        let cmd = report.as_mcu_request();
+        assert_eq!(11, offset_of(&report, &cmd.u.get_ncf_data));
+        assert_eq!(15, offset_of(&report, &cmd.u.get_ncf_data.header.data_len));
+        assert_eq!(16, offset_of(&report, &cmd.u.get_ncf_data.u.read_ntag));
+        assert_eq!(
+            0x13,
+            offset_of(&report, &cmd.u.get_ncf_data.u.read_ntag._unknown_0x00_2) - 16 + 1
+        );
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{common::*, input::UseSPIColors};
+use crate::{
+    common::*,
+    input::{MACAddress, UseSPIColors},
+};
 use cgmath::{vec2, Vector2, Vector3};
-use std::{convert::TryFrom, fmt, num::ParseIntError, str::FromStr};
+use std::{
+    convert::TryFrom, fmt, marker::PhantomData, num::ParseIntError, ops::Range, str::FromStr,
+};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SPIRange(u32, u8);
@@ -10,11 +15,37 @@ impl SPIRange {
         assert!(size <= 0x1D);
         SPIRange(offset, size)
     }
+
+    pub fn offset(self) -> u32 {
+        self.0
+    }
+
+    pub fn size(self) -> u8 {
+        self.1
+    }
 }
 
+/// Size of the SPI flash.
+pub const SPI_FLASH_SIZE: u32 = 0x80000;
+/// Maximum size of a single SPI read or write.
+pub const SPI_MAX_CHUNK: u8 = 0x1D;
+/// Configuration and calibration written during manufacturing.
+pub const SPI_FACTORY_AREA: Range<u32> = 0x6000..0x7000;
+
+const RANGE_PAIRING_MAGIC: SPIRange = SPIRange(0x2000, 1);
+const RANGE_PAIRING_KEYS: SPIRange = SPIRange(0x2004, 0x16);
+const RANGE_SECOND_PAIRING_MAGIC: SPIRange = SPIRange(0x2026, 1);
+const RANGE_SECOND_PAIRING_KEYS: SPIRange = SPIRange(0x202A, 0x16);
+const RANGE_SHIPMENT: SPIRange = SPIRange(0x5000, 1);
+const RANGE_SERIAL_NUMBER: SPIRange = SPIRange(0x6000, 0x10);
+const RANGE_HORIZONTAL_OFFSETS: SPIRange = SPIRange(0x6080, 6);
 const RANGE_FACTORY_CALIBRATION_SENSORS: SPIRange = SPIRange(0x6020, 0x18);
 const RANGE_FACTORY_CALIBRATION_STICKS: SPIRange = SPIRange(0x603D, 0x12);
+const RANGE_LEFT_STICK_PARAMETERS: SPIRange = SPIRange(0x6086, 0x12);
+const RANGE_RIGHT_STICK_PARAMETERS: SPIRange = SPIRange(0x6098, 0x12);
 const RANGE_USER_CALIBRATION_STICKS: SPIRange = SPIRange(0x8010, 0x16);
+const RANGE_USER_CALIBRATION_LEFT_STICK: SPIRange = SPIRange(0x8010, 0xB);
+const RANGE_USER_CALIBRATION_RIGHT_STICK: SPIRange = SPIRange(0x801B, 0xB);
 const RANGE_USER_CALIBRATION_SENSORS: SPIRange = SPIRange(0x8026, 0x1A);
 
 const RANGE_CONTROLLER_COLOR_USE_SPI: SPIRange = SPIRange(0x601B, 1);
@@ -30,6 +61,12 @@ pub struct WrongRangeError {
     got: SPIRange,
 }
 
+impl WrongRangeError {
+    pub fn new(expected: SPIRange, got: SPIRange) -> WrongRangeError {
+        WrongRangeError { expected, got }
+    }
+}
+
 impl fmt::Display for WrongRangeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -58,6 +95,10 @@ impl SPIReadRequest {
             size: range.1,
         }
     }
+
+    pub fn range(&self) -> SPIRange {
+        SPIRange(self.offset.into(), self.size)
+    }
 }
 
 #[repr(packed)]
@@ -79,6 +120,14 @@ impl SPIWriteRequest {
             data: SPIData { raw },
         }
     }
+
+    pub fn range(&self) -> SPIRange {
+        SPIRange(self.address.into(), self.size)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { &self.data.raw[..(self.size as usize).min(0x1D)] }
+    }
 }
 
 impl From<ControllerColor> for SPIWriteRequest {
@@ -140,14 +189,23 @@ fn dbg_spi_data(out: &mut fmt::DebugStruct, address: U32LE, size: u8, data: &SPI
     unsafe {
         let raw = &&data.raw[..size as usize];
         match (u32::from(address), size) {
-            (0x6000, 16) => out.field("serial", raw),
+            (0x2000, 1) => out.field("pairing", &data.pairing),
+            (0x2004, 0x16) => out.field("pairing_keys", &data.pairing_keys),
+            (0x2026, 1) => out.field("second_pairing", &data.second_pairing),
+            (0x202a, 0x16) => out.field("second_pairing_keys", &data.second_pairing_keys),
+            (0x5000, 1) => out.field("shipment", &data.shipment),
+            (0x6000, 16) => out.field("serial", &data.serial_number),
+            (0x6080, 6) => out.field("horizontal_offset", &data.horizontal_offsets),
             (0x603d, 25) => out.field("stick_factory", &data.sticks_factory_calib),
             (0x6050, 13) => out.field("color", &data.color),
             (0x6080, 24) => out
                 .field("horizontal_offset", &&raw[..6])
                 .field("stick_parameter1", &&raw[6..]),
-            (0x6098, 18) => out.field("stick_parameter2", raw),
-            (0x8010, 24) => out.field("stick_user", &data.sticks_user_calib),
+            (0x6086, 18) => out.field("left_stick_parameters", &data.stick_params),
+            (0x6098, 18) => out.field("right_stick_parameters", &data.stick_params),
+            (0x8010, 0x16) => out.field("stick_user", &data.sticks_user_calib),
+            (0x8010, 0xB) => out.field("left_stick_user", &data.left_stick_user_calib),
+            (0x801b, 0xB) => out.field("right_stick_user", &data.right_stick_user_calib),
             (0x8028, 24) => out.field("imu_user", &data.imu_factory_calib),
             _ => out
                 .field("address", &address)
@@ -166,6 +224,17 @@ pub struct SPIReadResult {
 }
 
 impl SPIReadResult {
+    pub fn new(range: SPIRange, data: &[u8]) -> SPIReadResult {
+        assert_eq!(range.1 as usize, data.len());
+        let mut raw = [0; 0x1D];
+        raw[..data.len()].copy_from_slice(data);
+        SPIReadResult {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData { raw },
+        }
+    }
+
     pub fn range(&self) -> SPIRange {
         SPIRange(self.address.into(), self.size)
     }
@@ -190,6 +259,12 @@ pub struct SPIWriteResult {
 }
 
 impl SPIWriteResult {
+    pub fn new(success: bool) -> SPIWriteResult {
+        SPIWriteResult {
+            status: if success { 0 } else { 1 },
+        }
+    }
+
     pub fn success(&self) -> bool {
         self.status == 0
     }
@@ -200,10 +275,20 @@ impl SPIWriteResult {
 union SPIData {
     sticks_factory_calib: SticksCalibration,
     sticks_user_calib: UserSticksCalibration,
+    left_stick_user_calib: UserStickCalibration<LeftStickCalibration>,
+    stick_params: StickParameters,
+    right_stick_user_calib: UserStickCalibration<RightStickCalibration>,
     imu_factory_calib: SensorCalibration,
     imu_user_calib: UserSensorCalibration,
     color: ControllerColor,
     use_spi_colors: RawId<UseSPIColors>,
+    pairing: Pairing<FirstPairing>,
+    pairing_keys: PairingKeys<FirstPairing>,
+    second_pairing: Pairing<SecondPairing>,
+    second_pairing_keys: PairingKeys<SecondPairing>,
+    shipment: Shipment,
+    serial_number: SerialNumber,
+    horizontal_offsets: HorizontalOffsets,
     raw: [u8; 0x1D],
 }
 
@@ -236,22 +321,80 @@ impl TryFrom<SPIReadResult> for SticksCalibration {
     }
 }
 
+impl From<SticksCalibration> for SPIWriteRequest {
+    fn from(calib: SticksCalibration) -> Self {
+        let range = SticksCalibration::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData {
+                sticks_factory_calib: calib,
+            },
+        }
+    }
+}
+
+/// User calibration of the sticks, set in the settings of the Switch.
+///
+/// Both sticks keep the byte layout of their factory calibration.
 #[repr(packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct UserSticksCalibration {
-    pub left: UserStickCalibration,
-    pub right: UserStickCalibration,
+    pub left: UserStickCalibration<LeftStickCalibration>,
+    pub right: UserStickCalibration<RightStickCalibration>,
 }
 
 #[repr(packed)]
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub struct LeftStickCalibration {
     max: [u8; 3],
     center: [u8; 3],
     min: [u8; 3],
 }
 
+/// Packs two 12 bits values.
+fn encode_stick((x, y): (u16, u16)) -> [u8; 3] {
+    [
+        x as u8,
+        ((x >> 8) & 0xF) as u8 | ((y << 4) as u8),
+        (y >> 4) as u8,
+    ]
+}
+
+/// Unpacks two 12 bits values.
+fn decode_stick(raw: [u8; 3]) -> (u16, u16) {
+    (
+        (((raw[1] as u16) << 8) & 0xF00) | raw[0] as u16,
+        ((raw[2] as u16) << 4) | (raw[1] >> 4) as u16,
+    )
+}
+
+/// Encodes the distance from `center` to `min` and `max`, as stored in flash.
+fn encode_deltas(
+    min: (u16, u16),
+    center: (u16, u16),
+    max: (u16, u16),
+) -> ([u8; 3], [u8; 3], [u8; 3]) {
+    (
+        encode_stick((
+            center.0.saturating_sub(min.0),
+            center.1.saturating_sub(min.1),
+        )),
+        encode_stick(center),
+        encode_stick((
+            max.0.saturating_sub(center.0),
+            max.1.saturating_sub(center.1),
+        )),
+    )
+}
+
 impl LeftStickCalibration {
+    /// Calibration from the raw positions at rest and at the extremes.
+    pub fn new(min: (u16, u16), center: (u16, u16), max: (u16, u16)) -> LeftStickCalibration {
+        let (min, center, max) = encode_deltas(min, center, max);
+        LeftStickCalibration { max, center, min }
+    }
+
     fn conv_x(&self, raw: [u8; 3]) -> u16 {
         (((raw[1] as u16) << 8) & 0xF00) | raw[0] as u16
     }
@@ -313,7 +456,7 @@ impl fmt::Debug for LeftStickCalibration {
 }
 
 #[repr(packed)]
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub struct RightStickCalibration {
     center: [u8; 3],
     min: [u8; 3],
@@ -321,6 +464,12 @@ pub struct RightStickCalibration {
 }
 
 impl RightStickCalibration {
+    /// Calibration from the raw positions at rest and at the extremes.
+    pub fn new(min: (u16, u16), center: (u16, u16), max: (u16, u16)) -> RightStickCalibration {
+        let (min, center, max) = encode_deltas(min, center, max);
+        RightStickCalibration { center, min, max }
+    }
+
     fn conv_x(&self, raw: [u8; 3]) -> u16 {
         (((raw[1] as u16) << 8) & 0xF00) | raw[0] as u16
     }
@@ -381,12 +530,94 @@ impl fmt::Debug for RightStickCalibration {
     }
 }
 
+/// Device parameters of a stick.
+///
+/// Only the deadzone and the range ratio are known.
 #[repr(packed)]
-#[derive(Copy, Clone)]
-pub struct UserStickCalibration {
+#[derive(Copy, Clone, Default)]
+pub struct StickParameters {
+    _unknown0: [u8; 3],
+    deadzone_range_ratio: [u8; 3],
+    _unknown1: [u8; 12],
+}
+
+impl StickParameters {
+    /// Radius of the deadzone around the center, in raw stick units.
+    pub fn deadzone(&self) -> u16 {
+        decode_stick(self.deadzone_range_ratio).0
+    }
+
+    pub fn range_ratio(&self) -> u16 {
+        decode_stick(self.deadzone_range_ratio).1
+    }
+}
+
+impl fmt::Debug for StickParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StickParameters")
+            .field("deadzone", &self.deadzone())
+            .field("range_ratio", &self.range_ratio())
+            .finish()
+    }
+}
+
+/// `StickParameters` of the left stick.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LeftStickParameters(pub StickParameters);
+
+impl SPI for LeftStickParameters {
+    fn range() -> SPIRange {
+        RANGE_LEFT_STICK_PARAMETERS
+    }
+}
+
+impl TryFrom<SPIReadResult> for LeftStickParameters {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(LeftStickParameters(unsafe { value.data.stick_params }))
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// `StickParameters` of the right stick.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RightStickParameters(pub StickParameters);
+
+impl SPI for RightStickParameters {
+    fn range() -> SPIRange {
+        RANGE_RIGHT_STICK_PARAMETERS
+    }
+}
+
+impl TryFrom<SPIReadResult> for RightStickParameters {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(RightStickParameters(unsafe { value.data.stick_params }))
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct UserStickCalibration<T> {
     magic: [u8; 2],
-    // TODO: left and right are different
-    calib: LeftStickCalibration,
+    calib: T,
 }
 impl SPI for UserSticksCalibration {
     fn range() -> SPIRange {
@@ -394,12 +625,44 @@ impl SPI for UserSticksCalibration {
     }
 }
 
-impl TryFrom<SPIReadResult> for UserSticksCalibration {
+impl From<UserSticksCalibration> for SPIWriteRequest {
+    fn from(calib: UserSticksCalibration) -> Self {
+        let range = UserSticksCalibration::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData {
+                sticks_user_calib: calib,
+            },
+        }
+    }
+}
+
+impl SPI for UserStickCalibration<LeftStickCalibration> {
+    fn range() -> SPIRange {
+        RANGE_USER_CALIBRATION_LEFT_STICK
+    }
+}
+
+impl From<UserStickCalibration<LeftStickCalibration>> for SPIWriteRequest {
+    fn from(calib: UserStickCalibration<LeftStickCalibration>) -> Self {
+        let range = UserStickCalibration::<LeftStickCalibration>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData {
+                left_stick_user_calib: calib,
+            },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for UserStickCalibration<LeftStickCalibration> {
     type Error = WrongRangeError;
 
     fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
         if value.range() == Self::range() {
-            Ok(unsafe { value.data.sticks_user_calib })
+            Ok(unsafe { value.data.left_stick_user_calib })
         } else {
             Err(WrongRangeError {
                 expected: Self::range(),
@@ -409,46 +672,96 @@ impl TryFrom<SPIReadResult> for UserSticksCalibration {
     }
 }
 
-impl UserStickCalibration {
-    pub fn calib(&self) -> Option<LeftStickCalibration> {
-        if self.magic == USER_CALIB_MAGIC {
-            Some(self.calib)
-        } else {
-            None
+impl SPI for UserStickCalibration<RightStickCalibration> {
+    fn range() -> SPIRange {
+        RANGE_USER_CALIBRATION_RIGHT_STICK
+    }
+}
+
+impl From<UserStickCalibration<RightStickCalibration>> for SPIWriteRequest {
+    fn from(calib: UserStickCalibration<RightStickCalibration>) -> Self {
+        let range = UserStickCalibration::<RightStickCalibration>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData {
+                right_stick_user_calib: calib,
+            },
         }
     }
+}
 
-    pub fn max(&self) -> Option<(u16, u16)> {
-        if self.magic == USER_CALIB_MAGIC {
-            Some(self.calib.max())
+impl TryFrom<SPIReadResult> for UserStickCalibration<RightStickCalibration> {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.right_stick_user_calib })
         } else {
-            None
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
         }
     }
+}
 
-    pub fn center(&self) -> Option<(u16, u16)> {
-        if self.magic == USER_CALIB_MAGIC {
-            Some(self.calib.center())
+impl TryFrom<SPIReadResult> for UserSticksCalibration {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.sticks_user_calib })
         } else {
-            None
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+impl<T: Default> UserStickCalibration<T> {
+    /// No user calibration, the factory one is used.
+    pub fn reset() -> UserStickCalibration<T> {
+        UserStickCalibration {
+            magic: USER_NO_CALIB_MAGIC,
+            calib: T::default(),
         }
     }
+}
 
-    pub fn min(&self) -> Option<(u16, u16)> {
+impl<T> From<T> for UserStickCalibration<T> {
+    fn from(calib: T) -> Self {
+        UserStickCalibration {
+            magic: USER_CALIB_MAGIC,
+            calib,
+        }
+    }
+}
+
+impl<T: Copy> UserStickCalibration<T> {
+    /// The calibration, if the user made one.
+    pub fn calib(&self) -> Option<T> {
         if self.magic == USER_CALIB_MAGIC {
-            Some(self.calib.min())
+            Some(self.calib)
         } else {
             None
         }
     }
 }
 
-impl fmt::Debug for UserStickCalibration {
+impl<T> UserStickCalibration<T> {
+    pub fn magic_state(&self) -> MagicState {
+        user_magic_state(self.magic)
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for UserStickCalibration<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.magic == USER_CALIB_MAGIC {
-            f.write_fmt(format_args!("{:?}", self.calib))
-        } else {
-            f.write_str("NoUserStickCalibration")
+        match self.calib() {
+            Some(calib) => calib.fmt(f),
+            None => f.write_str("NoUserStickCalibration"),
         }
     }
 }
@@ -530,6 +843,25 @@ impl TryFrom<SPIReadResult> for SensorCalibration {
 const USER_CALIB_MAGIC: [u8; 2] = [0xB2, 0xA1];
 const USER_NO_CALIB_MAGIC: [u8; 2] = [0xFF; 2];
 
+fn user_magic_state(magic: [u8; 2]) -> MagicState {
+    match magic {
+        USER_CALIB_MAGIC => MagicState::Valid,
+        USER_NO_CALIB_MAGIC => MagicState::Absent,
+        _ => MagicState::Corrupted,
+    }
+}
+
+/// State of the magic value marking optional data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagicState {
+    /// The data is present.
+    Valid,
+    /// The area is erased or marked as unused.
+    Absent,
+    /// Unknown magic value, the area is probably corrupted.
+    Corrupted,
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct UserSensorCalibration {
@@ -589,6 +921,10 @@ impl TryFrom<SPIReadResult> for UserSensorCalibration {
 }
 
 impl UserSensorCalibration {
+    pub fn magic_state(&self) -> MagicState {
+        user_magic_state(self.magic)
+    }
+
     pub fn calib(&self) -> Option<SensorCalibration> {
         if self.magic == USER_CALIB_MAGIC {
             Some(self.calib)
@@ -682,3 +1018,642 @@ impl TryFrom<SPIReadResult> for ControllerColor {
         }
     }
 }
+
+const PAIRING_MAGIC: u8 = 0x95;
+
+/// First pairing section, at 0x2000.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FirstPairing;
+
+/// Second pairing section, at 0x2026.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SecondPairing;
+
+/// Whether the controller is paired with a console, at the start of a pairing
+/// section.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct Pairing<S> {
+    magic: u8,
+    section: PhantomData<S>,
+}
+
+impl<S> Pairing<S> {
+    /// Erases the pairing, the controller then needs to be paired again.
+    pub fn unpaired() -> Pairing<S> {
+        Pairing {
+            magic: 0,
+            section: PhantomData,
+        }
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.magic == PAIRING_MAGIC
+    }
+
+    pub fn magic_state(&self) -> MagicState {
+        match self.magic {
+            PAIRING_MAGIC => MagicState::Valid,
+            0x00 | 0xFF => MagicState::Absent,
+            _ => MagicState::Corrupted,
+        }
+    }
+}
+
+impl<S> fmt::Debug for Pairing<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pairing")
+            .field("magic", &self.magic_state())
+            .finish()
+    }
+}
+
+impl SPI for Pairing<FirstPairing> {
+    fn range() -> SPIRange {
+        RANGE_PAIRING_MAGIC
+    }
+}
+
+impl From<Pairing<FirstPairing>> for SPIWriteRequest {
+    fn from(pairing: Pairing<FirstPairing>) -> SPIWriteRequest {
+        let range = Pairing::<FirstPairing>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData { pairing },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for Pairing<FirstPairing> {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.pairing })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+impl SPI for Pairing<SecondPairing> {
+    fn range() -> SPIRange {
+        RANGE_SECOND_PAIRING_MAGIC
+    }
+}
+
+impl From<Pairing<SecondPairing>> for SPIWriteRequest {
+    fn from(second_pairing: Pairing<SecondPairing>) -> SPIWriteRequest {
+        let range = Pairing::<SecondPairing>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData { second_pairing },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for Pairing<SecondPairing> {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.second_pairing })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// Bluetooth address and link key of the paired console, 4 bytes into a
+/// pairing section.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct PairingKeys<S> {
+    /// Big-endian, like `MACAddress`.
+    host_address: [u8; 6],
+    /// Little-endian.
+    link_key: [u8; 16],
+    section: PhantomData<S>,
+}
+
+impl<S> PairingKeys<S> {
+    pub fn new(host_address: MACAddress, link_key: [u8; 16]) -> PairingKeys<S> {
+        PairingKeys {
+            host_address: host_address.0,
+            link_key,
+            section: PhantomData,
+        }
+    }
+
+    pub fn host_address(&self) -> MACAddress {
+        MACAddress(self.host_address)
+    }
+
+    pub fn link_key(&self) -> [u8; 16] {
+        self.link_key
+    }
+}
+
+impl<S> fmt::Debug for PairingKeys<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PairingKeys")
+            .field("host_address", &format_args!("{}", self.host_address()))
+            .field("link_key", &self.link_key())
+            .finish()
+    }
+}
+
+impl SPI for PairingKeys<FirstPairing> {
+    fn range() -> SPIRange {
+        RANGE_PAIRING_KEYS
+    }
+}
+
+impl From<PairingKeys<FirstPairing>> for SPIWriteRequest {
+    fn from(pairing_keys: PairingKeys<FirstPairing>) -> SPIWriteRequest {
+        let range = PairingKeys::<FirstPairing>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData { pairing_keys },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for PairingKeys<FirstPairing> {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.pairing_keys })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+impl SPI for PairingKeys<SecondPairing> {
+    fn range() -> SPIRange {
+        RANGE_SECOND_PAIRING_KEYS
+    }
+}
+
+impl From<PairingKeys<SecondPairing>> for SPIWriteRequest {
+    fn from(second_pairing_keys: PairingKeys<SecondPairing>) -> SPIWriteRequest {
+        let range = PairingKeys::<SecondPairing>::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData {
+                second_pairing_keys,
+            },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for PairingKeys<SecondPairing> {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.second_pairing_keys })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// Shipment flag, at 0x5000.
+///
+/// See `SubcommandRequest::disable_shipment_mode`.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct Shipment {
+    enabled: RawId<Bool>,
+}
+
+impl Shipment {
+    pub fn new(enabled: bool) -> Shipment {
+        Shipment {
+            enabled: Bool::from(enabled).into(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        matches!(self.enabled.try_into(), Some(Bool::True))
+    }
+}
+
+impl SPI for Shipment {
+    fn range() -> SPIRange {
+        RANGE_SHIPMENT
+    }
+}
+
+impl From<Shipment> for SPIWriteRequest {
+    fn from(shipment: Shipment) -> SPIWriteRequest {
+        let range = Shipment::range();
+        SPIWriteRequest {
+            address: range.0.into(),
+            size: range.1,
+            data: SPIData { shipment },
+        }
+    }
+}
+
+impl TryFrom<SPIReadResult> for Shipment {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.shipment })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// Serial number of the controller, at 0x6000.
+///
+/// Written during manufacturing, so it is read only.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct SerialNumber {
+    raw: [u8; 16],
+}
+
+impl SerialNumber {
+    /// `None` if the controller has no serial number.
+    pub fn as_str(&self) -> Option<&str> {
+        if self.raw[0] >= 0x80 {
+            return None;
+        }
+        let len = self.raw.iter().position(|&c| c == 0).unwrap_or(16);
+        std::str::from_utf8(&self.raw[..len]).ok()
+    }
+}
+
+impl fmt::Debug for SerialNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_str() {
+            Some(serial) => serial.fmt(f),
+            None => f.write_str("NoSerialNumber"),
+        }
+    }
+}
+
+impl SPI for SerialNumber {
+    fn range() -> SPIRange {
+        RANGE_SERIAL_NUMBER
+    }
+}
+
+impl TryFrom<SPIReadResult> for SerialNumber {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.serial_number })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// Accelerometer values when the controller lies flat, at 0x6080.
+///
+/// Factory data, so it is read only.
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct HorizontalOffsets {
+    acc: [I16LE; 3],
+}
+
+impl HorizontalOffsets {
+    pub fn acc_offset(&self) -> Vector3<f64> {
+        vector_from_raw(self.acc)
+    }
+}
+
+impl fmt::Debug for HorizontalOffsets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HorizontalOffsets")
+            .field("acc", &self.acc_offset())
+            .finish()
+    }
+}
+
+impl SPI for HorizontalOffsets {
+    fn range() -> SPIRange {
+        RANGE_HORIZONTAL_OFFSETS
+    }
+}
+
+impl TryFrom<SPIReadResult> for HorizontalOffsets {
+    type Error = WrongRangeError;
+
+    fn try_from(value: SPIReadResult) -> Result<Self, Self::Error> {
+        if value.range() == Self::range() {
+            Ok(unsafe { value.data.horizontal_offsets })
+        } else {
+            Err(WrongRangeError {
+                expected: Self::range(),
+                got: value.range(),
+            })
+        }
+    }
+}
+
+/// Full content of the SPI flash, to inspect a dump without a controller.
+#[derive(Copy, Clone)]
+pub struct FlashImage<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> FlashImage<'a> {
+    /// `None` if `data` isn't the size of the flash.
+    pub fn new(data: &'a [u8]) -> Option<FlashImage<'a>> {
+        if data.len() == SPI_FLASH_SIZE as usize {
+            Some(FlashImage { data })
+        } else {
+            None
+        }
+    }
+
+    pub fn read<S: SPI>(&self) -> S {
+        let range = S::range();
+        let start = range.0 as usize;
+        let data = &self.data[start..start + range.1 as usize];
+        S::try_from(SPIReadResult::new(range, data)).unwrap()
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Corrupted magic values and erased factory data.
+    pub fn issues(&self) -> Vec<FlashIssue> {
+        let mut issues = vec![];
+        let magics = [
+            (
+                RANGE_PAIRING_MAGIC,
+                self.read::<Pairing<FirstPairing>>().magic_state(),
+            ),
+            (
+                RANGE_SECOND_PAIRING_MAGIC,
+                self.read::<Pairing<SecondPairing>>().magic_state(),
+            ),
+            (
+                RANGE_USER_CALIBRATION_LEFT_STICK,
+                self.read::<UserStickCalibration<LeftStickCalibration>>()
+                    .magic_state(),
+            ),
+            (
+                RANGE_USER_CALIBRATION_RIGHT_STICK,
+                self.read::<UserStickCalibration<RightStickCalibration>>()
+                    .magic_state(),
+            ),
+            (
+                UserSensorCalibration::range(),
+                self.read::<UserSensorCalibration>().magic_state(),
+            ),
+        ];
+        for (range, state) in magics.iter() {
+            if *state == MagicState::Corrupted {
+                issues.push(FlashIssue::CorruptedMagic(*range));
+            }
+        }
+        let factory = [
+            RANGE_SERIAL_NUMBER,
+            RANGE_FACTORY_CALIBRATION_SENSORS,
+            RANGE_FACTORY_CALIBRATION_STICKS,
+            RANGE_CONTROLLER_COLOR,
+            RANGE_LEFT_STICK_PARAMETERS,
+            RANGE_RIGHT_STICK_PARAMETERS,
+        ];
+        for range in factory.iter() {
+            let start = range.0 as usize;
+            if self.data[start..start + range.1 as usize]
+                .iter()
+                .all(|&b| b == 0xFF)
+            {
+                issues.push(FlashIssue::MissingFactoryData(*range));
+            }
+        }
+        issues
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashIssue {
+    /// The magic value of an area is neither valid nor absent.
+    CorruptedMagic(SPIRange),
+    /// A factory area is erased.
+    MissingFactoryData(SPIRange),
+}
+
+impl fmt::Display for FlashIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashIssue::CorruptedMagic(range) => {
+                write!(f, "corrupted magic value at 0x{:x}", range.0)
+            }
+            FlashIssue::MissingFactoryData(range) => write!(
+                f,
+                "missing factory data at 0x{:x}..0x{:x}",
+                range.0,
+                range.0 + range.1 as u32
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn user_sticks_calibration() {
+    #[rustfmt::skip]
+    let mut data = [
+        // Left: magic, max, center, min
+        0xb2, 0xa1, 0x00, 0x06, 0x60, 0xf0, 0x07, 0x81, 0x00, 0x05, 0x50,
+        // Right: magic, center, min, max
+        0xb2, 0xa1, 0x20, 0x08, 0x7e, 0x00, 0x05, 0x50, 0x00, 0x07, 0x70,
+    ];
+    let range = UserSticksCalibration::range();
+    let calib = UserSticksCalibration::try_from(SPIReadResult::new(range, &data)).unwrap();
+    let left = calib.left.calib().unwrap();
+    assert_eq!(left.center(), (0x7f0, 0x810));
+    assert_eq!(left.min(), (0x2f0, 0x310));
+    assert_eq!(left.max(), (0xdf0, 0xe10));
+    let right = calib.right.calib().unwrap();
+    assert_eq!(right.center(), (0x820, 0x7e0));
+    assert_eq!(right.min(), (0x320, 0x2e0));
+    assert_eq!(right.max(), (0xf20, 0xee0));
+
+    data[0..2].copy_from_slice(&USER_NO_CALIB_MAGIC);
+    let calib = UserSticksCalibration::try_from(SPIReadResult::new(range, &data)).unwrap();
+    assert!(calib.left.calib().is_none());
+    assert!(calib.right.calib().is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn encode_sticks_calibration() {
+    let (min, center, max) = ((0x250, 0x260), (0x7f0, 0x810), (0xd90, 0xe00));
+    let left = LeftStickCalibration::new(min, center, max);
+    assert_eq!((left.min(), left.center(), left.max()), (min, center, max));
+    let right = RightStickCalibration::new(min, center, max);
+    assert_eq!(
+        (right.min(), right.center(), right.max()),
+        (min, center, max)
+    );
+
+    let request = SPIWriteRequest::from(UserStickCalibration::from(right));
+    assert_eq!(request.range(), RANGE_USER_CALIBRATION_RIGHT_STICK);
+    let read = SPIReadResult::new(request.range(), request.data());
+    let user = UserStickCalibration::<RightStickCalibration>::try_from(read).unwrap();
+    assert_eq!(user.calib().unwrap().max(), max);
+
+    let request = SPIWriteRequest::from(UserStickCalibration::<LeftStickCalibration>::reset());
+    assert_eq!(&request.data()[..2], &USER_NO_CALIB_MAGIC);
+}
+
+#[cfg(test)]
+#[test]
+fn stick_parameters() {
+    let data = [
+        0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14, 0x54, 0x41, 0x15, 0x54, 0xc7, 0x79, 0x9c,
+        0x33, 0x36, 0x63,
+    ];
+    let read = SPIReadResult::new(RightStickParameters::range(), &data);
+    let params = RightStickParameters::try_from(read).unwrap().0;
+    assert_eq!(params.deadzone(), 0x096);
+    assert_eq!(params.range_ratio(), 0xf33);
+}
+
+#[cfg(test)]
+#[test]
+fn flash_map() {
+    let read = |range, data: &[u8]| SPIReadResult::new(range, data);
+
+    let serial =
+        SerialNumber::try_from(read(SerialNumber::range(), b"XEW70000000003\0\0")).unwrap();
+    assert_eq!(serial.as_str(), Some("XEW70000000003"));
+    let serial = SerialNumber::try_from(read(SerialNumber::range(), &[0x80; 16])).unwrap();
+    assert_eq!(serial.as_str(), None);
+
+    let offsets = HorizontalOffsets::try_from(read(
+        HorizontalOffsets::range(),
+        &[0x50, 0xfd, 0x00, 0x00, 0xc6, 0x0f],
+    ))
+    .unwrap();
+    assert_eq!(offsets.acc_offset(), Vector3::new(-688., 0., 4038.));
+
+    let request = SPIWriteRequest::from(Shipment::new(false));
+    let shipment = Shipment::try_from(read(request.range(), request.data())).unwrap();
+    assert!(!shipment.enabled());
+
+    let host = MACAddress([0x98, 0xb6, 0xe9, 0x01, 0x02, 0x03]);
+    let request = SPIWriteRequest::from(PairingKeys::<SecondPairing>::new(host, [7; 16]));
+    assert_eq!(request.range(), SPIRange(0x202A, 0x16));
+    let keys =
+        PairingKeys::<SecondPairing>::try_from(read(request.range(), request.data())).unwrap();
+    assert_eq!(keys.host_address().0, host.0);
+    assert_eq!(keys.link_key(), [7; 16]);
+    let pairing = Pairing::<FirstPairing>::try_from(read(RANGE_PAIRING_MAGIC, &[0x95])).unwrap();
+    assert!(pairing.is_paired());
+}
+
+#[cfg(test)]
+#[test]
+fn pairing_sections() {
+    // Magic, size of the rest, host address, link key, zeros and host capability.
+    let mut section = vec![0x95, 0x22, 0x00, 0x00];
+    section.extend_from_slice(&[0x98, 0xb6, 0xe9, 0x01, 0x02, 0x03]);
+    section.extend(0x10..0x20);
+    section.extend_from_slice(&[0; 10]);
+    section.push(0x68);
+    section.push(0x00);
+    assert_eq!(section.len(), 0x26);
+
+    let mut data = vec![0xFF; SPI_FLASH_SIZE as usize];
+    data[0x2000..0x2026].copy_from_slice(&section);
+    let image = FlashImage::new(&data).unwrap();
+    assert!(image.read::<Pairing<FirstPairing>>().is_paired());
+    let keys = image.read::<PairingKeys<FirstPairing>>();
+    assert_eq!(keys.host_address().0, [0x98, 0xb6, 0xe9, 0x01, 0x02, 0x03]);
+    assert_eq!(keys.link_key()[0], 0x10);
+    assert_eq!(keys.link_key()[15], 0x1f);
+    assert!(!image.read::<Pairing<SecondPairing>>().is_paired());
+
+    section[4] = 0x5c;
+    data[0x2026..0x204c].copy_from_slice(&section);
+    let image = FlashImage::new(&data).unwrap();
+    assert!(image.read::<Pairing<SecondPairing>>().is_paired());
+    let keys = image.read::<PairingKeys<SecondPairing>>();
+    assert_eq!(keys.host_address().0, [0x5c, 0xb6, 0xe9, 0x01, 0x02, 0x03]);
+    assert_eq!(keys.link_key()[15], 0x1f);
+}
+
+#[cfg(test)]
+#[test]
+fn flash_image() {
+    let mut data = vec![0xFF; SPI_FLASH_SIZE as usize];
+    assert!(FlashImage::new(&data[1..]).is_none());
+    let image = FlashImage::new(&data).unwrap();
+    assert!(!image.read::<Pairing<FirstPairing>>().is_paired());
+    assert_eq!(
+        image.issues(),
+        [
+            FlashIssue::MissingFactoryData(RANGE_SERIAL_NUMBER),
+            FlashIssue::MissingFactoryData(RANGE_FACTORY_CALIBRATION_SENSORS),
+            FlashIssue::MissingFactoryData(RANGE_FACTORY_CALIBRATION_STICKS),
+            FlashIssue::MissingFactoryData(RANGE_CONTROLLER_COLOR),
+            FlashIssue::MissingFactoryData(RANGE_LEFT_STICK_PARAMETERS),
+            FlashIssue::MissingFactoryData(RANGE_RIGHT_STICK_PARAMETERS),
+        ]
+    );
+
+    for range in [
+        RANGE_SERIAL_NUMBER,
+        RANGE_FACTORY_CALIBRATION_SENSORS,
+        RANGE_FACTORY_CALIBRATION_STICKS,
+        RANGE_CONTROLLER_COLOR,
+        RANGE_LEFT_STICK_PARAMETERS,
+        RANGE_RIGHT_STICK_PARAMETERS,
+    ]
+    .iter()
+    {
+        data[range.0 as usize] = 0;
+    }
+    data[0x2000] = PAIRING_MAGIC;
+    data[0x8010..0x8012].copy_from_slice(&USER_CALIB_MAGIC);
+    data[0x8026] = 0x12;
+    let image = FlashImage::new(&data).unwrap();
+    assert!(image.read::<Pairing<FirstPairing>>().is_paired());
+    let left = image.read::<UserStickCalibration<LeftStickCalibration>>();
+    assert_eq!(left.magic_state(), MagicState::Valid);
+    assert_eq!(
+        image.issues(),
+        [FlashIssue::CorruptedMagic(RANGE_USER_CALIBRATION_SENSORS)]
+    );
+}
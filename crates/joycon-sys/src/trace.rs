@@ -0,0 +1,542 @@
+//! Text format of the reports captured by `joytk relay`
+//!
+//! Each line holds a report: the direction (`>` from the controller, `<` to
+//! the controller), the time since the start of the capture and the bytes in
+//! hex, prefixed by the HID header:
+//!
+//! ```text
+//! > 0:00:07.963652 a13f0000040080008000800080
+//! ```
+//!
+//! See the `trace/` folder for examples.
+
+use crate::{
+    common::*,
+    mcu::MCUReportId,
+    output::{OutputReport, OutputReportId},
+    InputReport,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+/// HID header of the input reports.
+pub const INPUT_HEADER: u8 = 0xa1;
+/// HID header of the output reports.
+pub const OUTPUT_HEADER: u8 = 0xa2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the controller, `>`.
+    Input,
+    /// To the controller, `<`.
+    Output,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy)]
+pub enum TraceReport {
+    Input(InputReport),
+    Output(OutputReport),
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    /// Time since the start of the capture.
+    pub timestamp: Duration,
+    /// Decoded report, zero-padded if fewer bytes were captured.
+    pub report: TraceReport,
+    /// Captured bytes, without the HID header.
+    bytes: Vec<u8>,
+}
+
+impl TraceEntry {
+    pub fn direction(&self) -> Direction {
+        match self.report {
+            TraceReport::Input(_) => Direction::Input,
+            TraceReport::Output(_) => Direction::Output,
+        }
+    }
+
+    pub fn input(&self) -> Option<&InputReport> {
+        match self.report {
+            TraceReport::Input(ref report) => Some(report),
+            TraceReport::Output(_) => None,
+        }
+    }
+
+    pub fn output(&self) -> Option<&OutputReport> {
+        match self.report {
+            TraceReport::Output(ref report) => Some(report),
+            TraceReport::Input(_) => None,
+        }
+    }
+
+    /// Subcommand of a request or of a reply.
+    pub fn subcommand_id(&self) -> Option<RawId<SubcommandId>> {
+        match self.report {
+            TraceReport::Input(ref report) => report.subcmd_reply().map(|r| r.id()),
+            TraceReport::Output(ref report) => report.rumble_subcmd().map(|r| r.id()),
+        }
+    }
+
+    pub fn mcu_report_id(&self) -> Option<RawId<MCUReportId>> {
+        self.input()
+            .and_then(InputReport::mcu_report)
+            .map(|r| r.id())
+    }
+
+    /// Captured bytes of the report, without the HID header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (direction, header) = match self.direction() {
+            Direction::Input => ('>', INPUT_HEADER),
+            Direction::Output => ('<', OUTPUT_HEADER),
+        };
+        write!(
+            f,
+            "{} {} {:02x}",
+            direction,
+            format_timestamp(self.timestamp),
+            header
+        )?;
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TraceEntry {
+    type Err = TraceParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+        let direction = match fields.next() {
+            Some(">") => Direction::Input,
+            Some("<") => Direction::Output,
+            Some(_) => return Err(TraceParseError::InvalidDirection),
+            None => return Err(TraceParseError::MissingField),
+        };
+        let timestamp = parse_timestamp(fields.next().ok_or(TraceParseError::MissingField)?)
+            .ok_or(TraceParseError::InvalidTimestamp)?;
+        let bytes = hex::decode(fields.next().ok_or(TraceParseError::MissingField)?)
+            .map_err(|_| TraceParseError::InvalidHex)?;
+        if fields.next().is_some() {
+            return Err(TraceParseError::TrailingData);
+        }
+
+        // The captures of the first versions of `joytk relay` have no header
+        let header = match direction {
+            Direction::Input => INPUT_HEADER,
+            Direction::Output => OUTPUT_HEADER,
+        };
+        let bytes = match bytes.split_first() {
+            Some((&first, rest)) if first == header => rest,
+            _ => &bytes[..],
+        };
+        TraceEntry::from_bytes(timestamp, direction, bytes)
+    }
+}
+
+impl TraceEntry {
+    /// Builds an entry from the bytes of a report, without the HID header.
+    pub fn from_bytes(
+        timestamp: Duration,
+        direction: Direction,
+        bytes: &[u8],
+    ) -> Result<TraceEntry, TraceParseError> {
+        if bytes.is_empty() {
+            return Err(TraceParseError::MissingField);
+        }
+        let report = match direction {
+            Direction::Input => {
+                let mut report = InputReport::new();
+                copy_report(report.as_bytes_mut(), bytes)?;
+                TraceReport::Input(report)
+            }
+            Direction::Output => {
+                let mut report = OutputReport::new();
+                copy_report(report.as_bytes_mut(), bytes)?;
+                TraceReport::Output(report)
+            }
+        };
+        Ok(TraceEntry {
+            timestamp,
+            report,
+            bytes: bytes.to_vec(),
+        })
+    }
+}
+
+/// Formats a timestamp like in the traces, `h:mm:ss.micros`.
+pub fn format_timestamp(timestamp: Duration) -> String {
+    let secs = timestamp.as_secs();
+    format!(
+        "{}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        timestamp.subsec_micros()
+    )
+}
+
+fn copy_report(raw: &mut [u8], bytes: &[u8]) -> Result<(), TraceParseError> {
+    if bytes.len() > raw.len() {
+        return Err(TraceParseError::TooLong(bytes.len()));
+    }
+    raw[..bytes.len()].copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Parses `h:mm:ss.micros`, or seconds as written by the first versions of
+/// `joytk relay`.
+pub fn parse_timestamp(input: &str) -> Option<Duration> {
+    let (whole, fraction) = match input.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (input, ""),
+    };
+    let mut secs = 0u64;
+    for part in whole.split(':') {
+        secs = secs.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else if fraction.len() <= 9 && fraction.bytes().all(|c| c.is_ascii_digit()) {
+        fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32)
+    } else {
+        return None;
+    };
+    Some(Duration::new(secs, nanos))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceParseError {
+    MissingField,
+    InvalidDirection,
+    InvalidTimestamp,
+    InvalidHex,
+    TrailingData,
+    /// The report is bigger than the biggest known report.
+    TooLong(usize),
+}
+
+impl std::error::Error for TraceParseError {}
+
+impl fmt::Display for TraceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceParseError::MissingField => f.write_str("missing field"),
+            TraceParseError::InvalidDirection => f.write_str("invalid direction, expected > or <"),
+            TraceParseError::InvalidTimestamp => f.write_str("invalid timestamp"),
+            TraceParseError::InvalidHex => f.write_str("invalid hex data"),
+            TraceParseError::TrailingData => f.write_str("trailing data"),
+            TraceParseError::TooLong(len) => write!(f, "report too long ({} bytes)", len),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Parse { line: usize, error: TraceParseError },
+}
+
+impl std::error::Error for TraceError {}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(e) => e.fmt(f),
+            TraceError::Parse { line, error } => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        TraceError::Io(e)
+    }
+}
+
+/// Iterator over the entries of a trace, skipping the empty lines.
+pub struct TraceReader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(input: R) -> TraceReader<R> {
+        TraceReader {
+            lines: input.lines(),
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = Result<TraceEntry, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(line.parse().map_err(|error| TraceError::Parse {
+                line: self.line,
+                error,
+            }));
+        }
+    }
+}
+
+pub struct TraceWriter<W> {
+    output: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(output: W) -> TraceWriter<W> {
+        TraceWriter { output }
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        writeln!(self.output, "{}", entry)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// Selection of trace entries.
+///
+/// Empty lists match everything, and an entry must match each non-empty
+/// list.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub direction: Option<Direction>,
+    pub input_reports: Vec<InputReportId>,
+    pub output_reports: Vec<OutputReportId>,
+    /// Subcommand requests and replies.
+    pub subcommands: Vec<SubcommandId>,
+    pub mcu_reports: Vec<MCUReportId>,
+    /// Entries at or after this timestamp.
+    pub since: Option<Duration>,
+    /// Entries before this timestamp.
+    pub until: Option<Duration>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        if let Some(direction) = self.direction {
+            if direction != entry.direction() {
+                return false;
+            }
+        }
+        if self.since.map(|t| entry.timestamp < t) == Some(true)
+            || self.until.map(|t| entry.timestamp >= t) == Some(true)
+        {
+            return false;
+        }
+        matches_any(entry.input().map(InputReport::id), &self.input_reports)
+            && matches_any(entry.output().map(OutputReport::id), &self.output_reports)
+            && matches_any(entry.subcommand_id(), &self.subcommands)
+            && matches_any(entry.mcu_report_id(), &self.mcu_reports)
+    }
+}
+
+/// Whether `id` is in `ids`, or `ids` is empty.
+fn matches_any<Id>(id: Option<RawId<Id>>, ids: &[Id]) -> bool
+where
+    Id: num::FromPrimitive + PartialEq + Copy,
+{
+    match id {
+        _ if ids.is_empty() => true,
+        Some(id) => ids.iter().any(|&x| id == x),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_trace() {
+    let log = "\
+> 0:00:07.963652 a13f0000040080008000800080
+< 0:00:07.981528 a201030000000000000000020000000000000000000000000000000000000000000000000000000000000000000000000000
+
+> 0:00:08.011124 a121344e000000000000004d6e0982020407020264b5c62a01c4010100000000000000000000000000000000000000000000
+";
+    let entries: Vec<_> = TraceReader::new(log.as_bytes())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].timestamp, Duration::from_micros(7_963_652));
+    assert_eq!(entries[1].direction(), Direction::Output);
+    let mut out = TraceWriter::new(vec![]);
+    for entry in &entries {
+        out.write(entry).unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(out.into_inner()).unwrap(),
+        log.replace("\n\n", "\n")
+    );
+
+    let filter = TraceFilter {
+        subcommands: vec![SubcommandId::RequestDeviceInfo],
+        ..TraceFilter::default()
+    };
+    let selected: Vec<_> = entries.iter().filter(|e| filter.matches(e)).collect();
+    assert_eq!(selected.len(), 2);
+    let filter = TraceFilter {
+        direction: Some(Direction::Input),
+        ..filter
+    };
+    assert_eq!(entries.iter().filter(|e| filter.matches(e)).count(), 1);
+    let filter = TraceFilter {
+        since: Some(Duration::from_secs(7)),
+        until: Some(entries[2].timestamp),
+        ..TraceFilter::default()
+    };
+    assert_eq!(entries.iter().filter(|e| filter.matches(e)).count(), 2);
+
+    // Reports whose size isn't known are written back as captured
+    let log = "> 0:00:01.000000 a123000000\n< 0:00:01.500000 a2030102\n";
+    let entries: Vec<_> = TraceReader::new(log.as_bytes())
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(entries[0].as_bytes(), [0x23, 0, 0, 0]);
+    assert_eq!(
+        entries[1].output().unwrap().id(),
+        OutputReportId::MCUFwUpdate
+    );
+    let mut out = TraceWriter::new(vec![]);
+    for entry in &entries {
+        out.write(entry).unwrap();
+    }
+    assert_eq!(String::from_utf8(out.into_inner()).unwrap(), log);
+
+    let entry: TraceEntry = "> 0012.5000 3f0000040080008000800080".parse().unwrap();
+    assert_eq!(entry.timestamp, Duration::from_millis(12_500));
+    assert_eq!(entry.input().unwrap().id(), InputReportId::Normal);
+
+    let errors: Vec<_> = TraceReader::new("> 0:00:01.0 a13f\n= 1 a1\n> x a1\n< 1 zz\n".as_bytes())
+        .filter_map(|e| match e {
+            Err(TraceError::Parse { line, error }) => Some((line, error)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (2, TraceParseError::InvalidDirection),
+            (3, TraceParseError::InvalidTimestamp),
+            (4, TraceParseError::InvalidHex),
+        ][..]
+    );
+}
+
+/// Decodes every report of the captures in `trace/` and checks that encoding
+/// them again gives the same bytes.
+#[cfg(test)]
+#[test]
+fn golden_traces() {
+    use crate::{input::InputReportEnum, output::OutputReportEnum};
+    use std::{convert::TryFrom, fs::File, io::BufReader, path::Path};
+
+    // Seen in the captures, but their meaning is still unknown
+    let undocumented_mcu_reports = [RawId::<MCUReportId>::new(0x09)];
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../trace");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let mut problems = vec![];
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let lines = std::io::BufRead::lines(BufReader::new(File::open(path).unwrap()));
+        for (i, line) in lines.enumerate() {
+            let line = line.unwrap();
+            let mut report =
+                |problem: String| problems.push(format!("{}:{}: {}", name, i + 1, problem));
+            let entry: TraceEntry = match line.parse() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report(e.to_string());
+                    continue;
+                }
+            };
+            if entry.to_string() != line {
+                report(format!("re-encoded as {}", entry));
+            }
+            match entry.report {
+                TraceReport::Input(input) => {
+                    match InputReportEnum::try_from(input) {
+                        Ok(decoded) => {
+                            if InputReport::from(decoded).as_bytes() != input.as_bytes() {
+                                report("input report changed by decoding".to_string());
+                            }
+                        }
+                        Err(_) => report(format!("unknown input report {:?}", input.id())),
+                    }
+                    if let Some(reply) = input.subcmd_reply() {
+                        if reply.id().try_into().is_none() {
+                            report(format!("unknown subcommand reply {:?}", reply.id()));
+                        }
+                    }
+                    if let Some(mcu) = input.mcu_report() {
+                        if mcu.id().try_into().is_none()
+                            && !undocumented_mcu_reports.contains(&mcu.id())
+                        {
+                            report(format!("unknown MCU report {:?}", mcu.id()));
+                        }
+                    }
+                }
+                TraceReport::Output(output) => {
+                    match OutputReportEnum::try_from(output) {
+                        Ok(decoded) => {
+                            let mut encoded = OutputReport::from(decoded);
+                            *encoded.rumble_mut() = *output.rumble();
+                            if encoded.as_bytes() != output.as_bytes() {
+                                report("output report changed by decoding".to_string());
+                            }
+                        }
+                        Err(_) => report(format!("unknown output report {:?}", output.id())),
+                    }
+                    if let Some(request) = output.rumble_subcmd() {
+                        if request.id().try_into().is_none() {
+                            report(format!("unknown subcommand {:?}", request.id()));
+                        }
+                    }
+                    if let Some(request) = output.request_mcu_data() {
+                        if request.id().try_into().is_none() {
+                            report(format!("unknown MCU request {:?}", request.id()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    assert!(problems.is_empty(), "{}", problems.join("\n"));
+}
@@ -0,0 +1,251 @@
+//! Async API, usable from any executor.
+//!
+//! hidapi is blocking, so the device is still read by the reader thread of
+//! `JoyCon::spawn`, but nothing in the async code blocks: many controllers
+//! can be driven from the same task pool.
+
+use crate::handle::{Command, ReplySender, ReportSender, REPORT_QUEUE};
+use crate::{Error, JoyCon, Report, Result};
+use futures::{
+    channel::{mpsc, oneshot},
+    Stream, StreamExt,
+};
+use joycon_sys::{
+    imu,
+    input::{SubcommandReply, WhichController},
+    light,
+    mcu::ir::Resolution,
+    output::{OutputReport, RumbleData, SubcommandRequest},
+    spi::{SPIReadRequest, SPI},
+};
+use std::{
+    convert::TryInto,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc::Sender, Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Controller running in a background thread, driven with futures.
+///
+/// The commands are sent as soon as the method is called, and the returned
+/// future resolves when the reply arrives. The futures don't borrow the
+/// controller, so they can run concurrently with the report stream.
+pub struct AsyncJoyCon {
+    commands: Sender<Command>,
+    device_type: WhichController,
+    reports: ReportStream,
+}
+
+/// Input reports of an `AsyncJoyCon`.
+///
+/// Reports are dropped if the stream lags more than `REPORT_QUEUE` reports
+/// behind. The last item is the error that stopped the reader thread, if any.
+pub struct ReportStream {
+    reports: mpsc::Receiver<Report>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl JoyCon {
+    /// Moves the controller to a background thread driven by an
+    /// `AsyncJoyCon`.
+    pub fn into_async(self) -> AsyncJoyCon {
+        let (reports_tx, reports_rx) = mpsc::channel(REPORT_QUEUE);
+        let error = Arc::new(Mutex::new(None));
+        let device_type = self.device_type();
+        let commands = self
+            .spawn_reader(ReportSender::Async {
+                reports: reports_tx,
+                error: error.clone(),
+            })
+            .commands;
+        AsyncJoyCon {
+            commands,
+            device_type,
+            reports: ReportStream {
+                reports: reports_rx,
+                error,
+            },
+        }
+    }
+}
+
+impl AsyncJoyCon {
+    pub fn device_type(&self) -> WhichController {
+        self.device_type
+    }
+
+    /// Stream of the input reports.
+    ///
+    /// `AsyncJoyCon` also implements `Stream` itself.
+    pub fn reports(&mut self) -> &mut ReportStream {
+        &mut self.reports
+    }
+
+    pub async fn tick(&mut self) -> Result<Report> {
+        self.reports
+            .next()
+            .await
+            .unwrap_or(Err(Error::Disconnected))
+    }
+
+    pub fn send(&self, report: OutputReport) -> Result<()> {
+        self.commands
+            .send(Command::Send(report))
+            .map_err(|_| Error::Disconnected)
+    }
+
+    pub fn set_rumble(&self, rumble: RumbleData) -> Result<()> {
+        self.send(OutputReport::set_rumble(rumble))
+    }
+
+    pub fn call_subcmd_wait<S: Into<SubcommandRequest>>(
+        &self,
+        subcmd: S,
+    ) -> impl Future<Output = Result<SubcommandReply>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sent = self
+            .commands
+            .send(Command::Subcmd(subcmd.into(), ReplySender::Async(reply_tx)));
+        async move {
+            sent.map_err(|_| Error::Disconnected)?;
+            reply_rx.await.map_err(|_| Error::Disconnected)?
+        }
+    }
+
+    pub fn read_spi<S: SPI>(&self) -> impl Future<Output = Result<S>> {
+        let reply = self.call_subcmd_wait(SPIReadRequest::new(S::range()));
+        async move {
+            let reply = reply.await?;
+            let result = reply.spi_read_result().unwrap();
+            Ok((*result).try_into()?)
+        }
+    }
+
+    pub fn set_home_light(&self, home_light: light::HomeLight) -> impl Future<Output = Result<()>> {
+        let reply = self.call_subcmd_wait(home_light);
+        async move {
+            reply.await?;
+            Ok(())
+        }
+    }
+
+    pub fn set_player_light(
+        &self,
+        player_lights: light::PlayerLights,
+    ) -> impl Future<Output = Result<()>> {
+        let reply = self.call_subcmd_wait(player_lights);
+        async move {
+            reply.await?;
+            Ok(())
+        }
+    }
+
+    pub fn enable_imu(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::enable_imu)
+    }
+
+    pub fn disable_imu(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::disable_imu)
+    }
+
+    pub fn configure_imu(&self, sensitivity: imu::Sensitivity) -> impl Future<Output = Result<()>> {
+        self.run(move |joycon| joycon.configure_imu(sensitivity))
+    }
+
+    pub fn reset_yaw(&self) -> impl Future<Output = Result<()>> {
+        self.run(|joycon| {
+            joycon.reset_yaw();
+            Ok(())
+        })
+    }
+
+    pub fn load_calibration(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::load_calibration)
+    }
+
+    pub fn enable_ir(&self, resolution: Resolution) -> impl Future<Output = Result<()>> {
+        self.run(move |joycon| joycon.enable_ir(resolution))
+    }
+
+    pub fn disable_mcu(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::disable_mcu)
+    }
+
+    pub fn enable_ringcon(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::enable_ringcon)
+    }
+
+    pub fn disable_ringcon(&self) -> impl Future<Output = Result<()>> {
+        self.run(JoyCon::disable_ringcon)
+    }
+
+    /// Runs a blocking `JoyCon` method on the reader thread.
+    ///
+    /// Used for the multi-step MCU sequences. The reports received meanwhile
+    /// are consumed by `f` and don't reach the stream.
+    fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut JoyCon) -> Result<R> + Send + 'static,
+    ) -> impl Future<Output = Result<R>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let sent = self.commands.send(Command::Run(Box::new(move |joycon| {
+            let _ = result_tx.send(f(joycon));
+        })));
+        async move {
+            sent.map_err(|_| Error::Disconnected)?;
+            result_rx.await.map_err(|_| Error::Disconnected)?
+        }
+    }
+}
+
+impl Stream for AsyncJoyCon {
+    type Item = Result<Report>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.reports).poll_next(cx)
+    }
+}
+
+impl Stream for ReportStream {
+    type Item = Result<Report>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.reports).poll_next(cx) {
+            Poll::Ready(Some(report)) => Poll::Ready(Some(Ok(report))),
+            Poll::Ready(None) => Poll::Ready(self.error.lock().unwrap().take().map(Err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl std::fmt::Debug for AsyncJoyCon {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AsyncJoyCon")
+            .field("device_type", &self.device_type)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn subcmd_and_stream() {
+    use joycon_sys::spi::SensorCalibration;
+
+    let device_type = WhichController::ProController;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = JoyCon::new(emulator, device_type).unwrap().into_async();
+
+    futures::executor::block_on(async {
+        let calib = joycon.read_spi::<SensorCalibration>();
+        joycon.load_calibration().await.unwrap();
+        joycon.enable_imu().await.unwrap();
+        calib.await.unwrap();
+
+        let reports: Vec<_> = joycon.reports().take(10).collect().await;
+        assert_eq!(reports.len(), 10);
+        assert!(reports.iter().all(|r| r.is_ok()));
+        joycon.tick().await.unwrap();
+    });
+}
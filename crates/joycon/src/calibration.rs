@@ -1,50 +1,170 @@
 use cgmath::*;
 use joycon_sys::imu::IMU_SAMPLES_PER_SECOND;
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
-type Entry = Vector3<f64>;
+/// Origin of a calibration stored in the SPI flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationSource {
+    /// Measured during manufacturing.
+    Factory,
+    /// Made by the user in the settings of the Switch.
+    User,
+}
+
+/// Rotation speed in dps and acceleration in g.
+type Sample = (Vector3<f64>, Vector3<f64>);
 
+/// Continuous estimation of the gyroscope bias.
+///
+/// The controller is considered still when, over the whole window, both
+/// sensors vary less than their threshold and the rotation speed stays
+/// small. While it is still, the bias follows the average rotation speed,
+/// with the window as time constant.
 #[derive(Clone, Debug)]
-pub struct Calibration {
-    history: VecDeque<Entry>,
+pub struct GyroCalibration {
+    window: VecDeque<Sample>,
     capacity: usize,
+    bias: Vector3<f64>,
+    still_samples: usize,
+    still: bool,
+    gyro_threshold: f64,
+    accel_threshold: f64,
 }
 
-impl Calibration {
-    pub fn with_capacity(capacity: usize) -> Calibration {
-        Calibration {
-            history: VecDeque::with_capacity(capacity),
+impl GyroCalibration {
+    /// Max standard deviation of the rotation speed at rest, in dps.
+    pub const DEFAULT_GYRO_THRESHOLD: f64 = 1.;
+    /// Max standard deviation of the acceleration at rest, in g.
+    pub const DEFAULT_ACCEL_THRESHOLD: f64 = 0.02;
+    /// Larger rotation speeds are never considered as bias, in dps.
+    pub const MAX_BIAS: f64 = 10.;
+
+    pub fn new(window: Duration) -> GyroCalibration {
+        let capacity = Self::window_samples(window);
+        GyroCalibration {
+            window: VecDeque::with_capacity(capacity),
             capacity,
+            bias: Vector3::zero(),
+            still_samples: 0,
+            still: false,
+            gyro_threshold: Self::DEFAULT_GYRO_THRESHOLD,
+            accel_threshold: Self::DEFAULT_ACCEL_THRESHOLD,
         }
     }
 
-    pub fn push(&mut self, entry: Entry) {
-        if self.history.len() == self.capacity {
-            self.history.pop_back();
+    fn window_samples(window: Duration) -> usize {
+        ((window.as_secs_f64() * IMU_SAMPLES_PER_SECOND as f64) as usize).max(1)
+    }
+
+    /// Duration over which the stillness is detected and the bias averaged.
+    pub fn set_window(&mut self, window: Duration) {
+        self.capacity = Self::window_samples(window);
+        while self.window.len() > self.capacity {
+            self.window.pop_front();
         }
-        self.history.push_front(entry);
+        self.still_samples = self.still_samples.min(self.capacity);
     }
 
-    pub fn reset(&mut self) {
-        self.history.clear();
+    /// Stillness thresholds, as standard deviations in dps and g.
+    pub fn set_thresholds(&mut self, gyro: f64, accel: f64) {
+        self.gyro_threshold = gyro;
+        self.accel_threshold = accel;
+    }
+
+    /// Adds an uncompensated sample.
+    pub fn push(&mut self, gyro: Vector3<f64>, accel: Vector3<f64>) {
+        if self.window.len() == self.capacity {
+            self.window.pop_front();
+        }
+        self.window.push_back((gyro, accel));
+
+        let mean_gyro = self.still_mean();
+        self.still = mean_gyro.is_some();
+        if let Some(mean_gyro) = mean_gyro {
+            self.still_samples = (self.still_samples + 1).min(self.capacity);
+            self.bias += (mean_gyro - self.bias) / self.still_samples as f64;
+        }
     }
 
-    pub fn get_average(&mut self) -> Entry {
-        let zero = Vector3::new(0., 0., 0.);
-        let len = self.history.len() as f64;
-        if len == 0. {
-            return zero;
+    /// Average rotation speed of the window if the controller was still.
+    fn still_mean(&self) -> Option<Vector3<f64>> {
+        if self.window.len() < self.capacity {
+            return None;
         }
-        self.history
-            .iter()
-            .cloned()
-            .fold(zero, |acc, val| acc + val)
-            / len
+        let len = self.window.len() as f64;
+        let (gyro_sum, accel_sum) = self.window.iter().fold(
+            (Vector3::zero(), Vector3::zero()),
+            |(g, a), (gyro, accel)| (g + gyro, a + accel),
+        );
+        let (gyro_mean, accel_mean) = (gyro_sum / len, accel_sum / len);
+        let (gyro_var, accel_var) = self.window.iter().fold((0., 0.), |(g, a), (gyro, accel)| {
+            (
+                g + (gyro - gyro_mean).magnitude2(),
+                a + (accel - accel_mean).magnitude2(),
+            )
+        });
+        let still = gyro_var / len < self.gyro_threshold.powi(2)
+            && accel_var / len < self.accel_threshold.powi(2)
+            && gyro_mean.magnitude() < Self::MAX_BIAS;
+        if still {
+            Some(gyro_mean)
+        } else {
+            None
+        }
+    }
+
+    /// Rotation speed measured at rest, in dps.
+    pub fn bias(&self) -> Vector3<f64> {
+        self.bias
+    }
+
+    /// From 0 when the controller was never still, to 1 after a full
+    /// window at rest.
+    pub fn confidence(&self) -> f64 {
+        self.still_samples as f64 / self.capacity as f64
+    }
+
+    /// Whether the controller was still during the last window.
+    pub fn is_still(&self) -> bool {
+        self.still
+    }
+
+    pub fn reset(&mut self) {
+        self.window.clear();
+        self.bias = Vector3::zero();
+        self.still_samples = 0;
+        self.still = false;
     }
 }
 
-impl Default for Calibration {
+impl Default for GyroCalibration {
     fn default() -> Self {
-        Calibration::with_capacity(3 * IMU_SAMPLES_PER_SECOND as usize)
+        GyroCalibration::new(Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn learns_bias_at_rest() {
+    let mut calib = GyroCalibration::new(Duration::from_millis(500));
+    let bias = vec3(0.5, -1.2, 0.3);
+    let noise = |i: i32| 0.1 * (-1f64).powi(i);
+    for i in 0..100 {
+        calib.push(bias + vec3(noise(i), 0., 0.), vec3(0., 0., 1.));
+    }
+    assert!(calib.is_still());
+    assert_eq!(calib.confidence(), 0.01);
+    assert!((calib.bias() - bias).magnitude() < 0.01);
+
+    for i in 0..100 {
+        calib.push(bias, vec3(0., 0., 1. + noise(i) / 20.));
+    }
+    assert!(calib.confidence() > 0.99);
+
+    // Moving, the bias isn't updated
+    for i in 0..100 {
+        calib.push(vec3(90. * noise(i), 0., 0.), vec3(0., 0., 1.));
     }
+    assert!(!calib.is_still());
+    assert!((calib.bias() - bias).magnitude() < 0.01);
 }
@@ -0,0 +1,52 @@
+use joycon_sys::{
+    input::{Ack, InvalidReportError},
+    spi::WrongRangeError,
+    trace::TraceError,
+    RawId, SubcommandId,
+};
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unsupported device 0x{0:04x}")]
+    UnsupportedDevice(u16),
+    #[error("short read: got {got} bytes, expected {expected}")]
+    ShortRead { got: usize, expected: usize },
+    #[error("short write: wrote {written} bytes, expected {expected}")]
+    ShortWrite { written: usize, expected: usize },
+    #[error(transparent)]
+    InvalidReport(#[from] InvalidReportError),
+    #[error("subcommand {id:?} rejected by the controller: {ack:?}")]
+    Nack { id: RawId<SubcommandId>, ack: Ack },
+    #[error("timeout while waiting for the reply to subcommand {0:?}")]
+    Timeout(RawId<SubcommandId>),
+    #[error(transparent)]
+    SPIWrongRange(#[from] WrongRangeError),
+    #[error("SPI write at 0x{0:x} failed")]
+    SPIWriteFailed(u32),
+    #[error("SPI range 0x{0:x}..0x{1:x} is write protected")]
+    SPIWriteProtected(u32, u32),
+    #[error("timeout while waiting for the MCU")]
+    MCUTimeout,
+    #[error("NFC error: {0}")]
+    NFC(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("device disconnected")]
+    Disconnected,
+    #[error(transparent)]
+    Hidapi(#[from] hidapi::HidError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Trace(#[from] TraceError),
+    #[error("output report mismatch: expected {}, got {}", hex::encode(.expected), hex::encode(.got))]
+    ReplayMismatch { expected: Vec<u8>, got: Vec<u8> },
+    #[error("output report after the end of the trace")]
+    ReplayEnded,
+    /// Internal error of the emulated controller.
+    #[error("emulator error: {0}")]
+    Emulator(String),
+}
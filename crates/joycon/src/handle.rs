@@ -0,0 +1,388 @@
+//! Background reader thread.
+//!
+//! `JoyCon::call_subcmd_wait` drops every standard report received while
+//! waiting for the reply. `JoyCon::spawn` instead moves the controller to a
+//! thread that reads continuously, routes the subcommand replies to their
+//! callers and forwards the input reports on a channel.
+
+use crate::hid::check_reply;
+use crate::{ControllerId, Error, JoyCon, Report, Result};
+use joycon_sys::{
+    input::{SubcommandReply, WhichController},
+    light,
+    output::{OutputReport, RumbleData, SubcommandRequest},
+    RawId, SubcommandId,
+};
+use std::{
+    sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{instrument, warn};
+
+/// Maximum number of reports waiting to be received before new ones are
+/// dropped.
+pub(crate) const REPORT_QUEUE: usize = 64;
+
+/// Time in milliseconds the reader thread waits for a report before checking
+/// for new commands.
+const POLL_TIMEOUT: i32 = 5;
+
+/// Time after which a subcommand without reply fails with `Error::Timeout`,
+/// even if the controller stopped sending reports.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub(crate) enum Command {
+    Send(OutputReport),
+    Subcmd(SubcommandRequest, ReplySender),
+    /// Runs a blocking `JoyCon` method on the reader thread.
+    Run(BlockingCall),
+    Close,
+}
+
+pub(crate) enum ReplySender {
+    Blocking(Sender<Result<SubcommandReply>>),
+    #[cfg(feature = "async")]
+    Async(futures::channel::oneshot::Sender<Result<SubcommandReply>>),
+}
+
+impl ReplySender {
+    fn send(self, reply: Result<SubcommandReply>) {
+        // The caller may have given up on the reply
+        match self {
+            ReplySender::Blocking(sender) => {
+                let _ = sender.send(reply);
+            }
+            #[cfg(feature = "async")]
+            ReplySender::Async(sender) => {
+                let _ = sender.send(reply);
+            }
+        }
+    }
+}
+
+pub(crate) enum ReportSender {
+    Blocking(SyncSender<Result<Report>>),
+    /// Shared by the controllers of a `ControllerManager`.
+    Tagged {
+        reports: SyncSender<(ControllerId, Result<Report>)>,
+        id: ControllerId,
+    },
+    #[cfg(feature = "async")]
+    Async {
+        reports: futures::channel::mpsc::Sender<Report>,
+        error: std::sync::Arc<std::sync::Mutex<Option<Error>>>,
+    },
+}
+
+impl ReportSender {
+    fn try_send(&mut self, report: Report) -> std::result::Result<(), TrySendError<()>> {
+        match self {
+            ReportSender::Blocking(sender) => sender.try_send(Ok(report)).map_err(|e| match e {
+                TrySendError::Full(_) => TrySendError::Full(()),
+                TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+            }),
+            ReportSender::Tagged { reports, id } => {
+                reports.try_send((*id, Ok(report))).map_err(|e| match e {
+                    TrySendError::Full(_) => TrySendError::Full(()),
+                    TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+                })
+            }
+            #[cfg(feature = "async")]
+            ReportSender::Async { reports, .. } => reports.try_send(report).map_err(|e| {
+                if e.is_full() {
+                    TrySendError::Full(())
+                } else {
+                    TrySendError::Disconnected(())
+                }
+            }),
+        }
+    }
+
+    fn fail(self, error: Error) {
+        match self {
+            ReportSender::Blocking(sender) => {
+                let _ = sender.send(Err(error));
+            }
+            ReportSender::Tagged { reports, id } => {
+                let _ = reports.send((id, Err(error)));
+            }
+            #[cfg(feature = "async")]
+            ReportSender::Async { error: slot, .. } => {
+                *slot.lock().unwrap() = Some(error);
+            }
+        }
+    }
+}
+
+struct PendingReply {
+    id: RawId<SubcommandId>,
+    reply: ReplySender,
+    deadline: Instant,
+}
+
+/// Cloneable handle to a controller running in a background thread.
+///
+/// Created by `JoyCon::spawn`. Every method can be called from any thread.
+/// The thread stops once all the handles and the report receiver are
+/// dropped, on `close`, or if the device fails.
+#[derive(Clone)]
+pub struct JoyConHandle {
+    pub(crate) commands: Sender<Command>,
+    device_type: WhichController,
+}
+
+impl JoyCon {
+    /// Moves the controller to a background thread.
+    ///
+    /// Returns a handle to send commands and a channel receiving the input
+    /// reports, including the ones carrying a subcommand reply. Reports are
+    /// dropped if the receiver lags more than `REPORT_QUEUE` reports behind.
+    /// The last message is the error that stopped the thread, if any.
+    pub fn spawn(self) -> (JoyConHandle, Receiver<Result<Report>>) {
+        let (reports_tx, reports_rx) = sync_channel(REPORT_QUEUE);
+        let handle = self.spawn_reader(ReportSender::Blocking(reports_tx));
+        (handle, reports_rx)
+    }
+
+    pub(crate) fn spawn_reader(self, reports: ReportSender) -> JoyConHandle {
+        let (commands_tx, commands_rx) = channel();
+        let device_type = self.device_type();
+        thread::Builder::new()
+            .name(format!("joycon-{}", device_type))
+            .spawn(move || reader_thread(self, commands_rx, reports))
+            .expect("failed to spawn the reader thread");
+        JoyConHandle {
+            commands: commands_tx,
+            device_type,
+        }
+    }
+}
+
+impl JoyConHandle {
+    pub fn device_type(&self) -> WhichController {
+        self.device_type
+    }
+
+    pub fn send(&self, report: OutputReport) -> Result<()> {
+        self.commands
+            .send(Command::Send(report))
+            .map_err(|_| Error::Disconnected)
+    }
+
+    pub fn set_rumble(&self, rumble: RumbleData) -> Result<()> {
+        self.send(OutputReport::set_rumble(rumble))
+    }
+
+    /// Stops the reader thread and closes the device.
+    ///
+    /// The calls still waiting for a reply fail with `Error::Disconnected`.
+    pub fn close(&self) {
+        let _ = self.commands.send(Command::Close);
+    }
+
+    #[instrument(level = "debug", skip(self), err)]
+    pub fn call_subcmd_wait<S: Into<SubcommandRequest> + std::fmt::Debug>(
+        &self,
+        subcmd: S,
+    ) -> Result<SubcommandReply> {
+        let (reply_tx, reply_rx) = channel();
+        self.commands
+            .send(Command::Subcmd(
+                subcmd.into(),
+                ReplySender::Blocking(reply_tx),
+            ))
+            .map_err(|_| Error::Disconnected)?;
+        reply_rx.recv().map_err(|_| Error::Disconnected)?
+    }
+
+    #[instrument(level = "info", skip(self), err)]
+    pub fn set_home_light(&self, home_light: light::HomeLight) -> Result<()> {
+        self.call_subcmd_wait(home_light)?;
+        Ok(())
+    }
+
+    #[instrument(level = "info", skip(self), err)]
+    pub fn set_player_light(&self, player_lights: light::PlayerLights) -> Result<()> {
+        self.call_subcmd_wait(player_lights)?;
+        Ok(())
+    }
+
+    /// Sets the current heading as the zero yaw of the orientation.
+    pub fn reset_yaw(&self) -> Result<()> {
+        self.commands
+            .send(Command::Run(Box::new(JoyCon::reset_yaw)))
+            .map_err(|_| Error::Disconnected)
+    }
+}
+
+impl std::fmt::Debug for JoyConHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("JoyConHandle")
+            .field("device_type", &self.device_type)
+            .finish()
+    }
+}
+
+fn reader_thread(mut joycon: JoyCon, commands: Receiver<Command>, mut reports: ReportSender) {
+    let mut pending: Vec<PendingReply> = vec![];
+    let mut blocked = None;
+    let mut handles_dropped = false;
+    let mut receiver_dropped = false;
+    let result = loop {
+        if handles_dropped && receiver_dropped {
+            break Ok(());
+        }
+
+        match handle_commands(
+            &mut joycon,
+            &commands,
+            &mut pending,
+            &mut blocked,
+            &mut handles_dropped,
+        ) {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+
+        let report = match joycon.recv_timeout(POLL_TIMEOUT) {
+            Ok(report) => report,
+            Err(e) => break Err(e),
+        };
+
+        if let Some(reply) = report.as_ref().and_then(|report| report.subcmd_reply()) {
+            if let Some(i) = pending.iter().position(|p| p.id == reply.id()) {
+                pending.remove(i).reply.send(check_reply(reply));
+            }
+        }
+        // Checked on every pass, the controller may have stopped sending
+        // reports altogether.
+        let now = Instant::now();
+        let (timed_out, still_pending): (Vec<_>, Vec<_>) =
+            pending.drain(..).partition(|p| p.deadline <= now);
+        pending = still_pending;
+        for waiting in timed_out {
+            waiting.reply.send(Err(Error::Timeout(waiting.id)));
+        }
+
+        let report = match report {
+            Some(report) => report,
+            None => continue,
+        };
+        if !receiver_dropped {
+            if let Some(report) = joycon.process(report) {
+                match reports.try_send(report) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => warn!("report queue full, dropping a report"),
+                    Err(TrySendError::Disconnected(_)) => receiver_dropped = true,
+                }
+            }
+        }
+    };
+
+    if let Err(e) = result {
+        for waiting in pending {
+            waiting.reply.send(Err(Error::Disconnected));
+        }
+        reports.fail(e);
+    }
+}
+
+type BlockingCall = Box<dyn FnOnce(&mut JoyCon) + Send>;
+
+/// Returns `false` once the handle asked to close.
+fn handle_commands(
+    joycon: &mut JoyCon,
+    commands: &Receiver<Command>,
+    pending: &mut Vec<PendingReply>,
+    blocked: &mut Option<BlockingCall>,
+    handles_dropped: &mut bool,
+) -> Result<bool> {
+    // A blocking call would eat the pending replies, so it waits for them and
+    // the next commands wait for it.
+    if blocked.is_some() && !pending.is_empty() {
+        return Ok(true);
+    }
+    if let Some(f) = blocked.take() {
+        f(joycon);
+    }
+    while !*handles_dropped {
+        match commands.try_recv() {
+            Ok(Command::Send(mut report)) => joycon.send(&mut report)?,
+            Ok(Command::Subcmd(subcmd, reply)) => {
+                joycon.send(&mut subcmd.into())?;
+                pending.push(PendingReply {
+                    id: subcmd.id(),
+                    reply,
+                    deadline: Instant::now() + REPLY_TIMEOUT,
+                });
+            }
+            Ok(Command::Run(f)) if pending.is_empty() => f(joycon),
+            Ok(Command::Run(f)) => {
+                *blocked = Some(f);
+                break;
+            }
+            Ok(Command::Close) => return Ok(false),
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => *handles_dropped = true,
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+#[test]
+fn reports_during_subcmd() {
+    let device_type = WhichController::RightJoyCon;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let joycon = JoyCon::new(emulator, device_type).unwrap();
+    let (handle, reports) = joycon.spawn();
+
+    let other = handle.clone();
+    let lights = thread::spawn(move || {
+        other.set_player_light(light::PlayerLights::new(
+            light::PlayerLight::On,
+            light::PlayerLight::Off,
+            light::PlayerLight::Off,
+            light::PlayerLight::On,
+        ))
+    });
+    let info = handle
+        .call_subcmd_wait(joycon_sys::output::SubcommandRequestEnum::RequestDeviceInfo(()))
+        .unwrap();
+    assert!(info.device_info().is_some());
+    lights.join().unwrap().unwrap();
+
+    // The replies also carry the controller state
+    assert!(reports.iter().take(2).all(|r| r.is_ok()));
+}
+
+#[cfg(test)]
+#[test]
+fn reply_timeout() {
+    let device_type = WhichController::RightJoyCon;
+    let (transport, peer) = crate::transport::memory_pipe();
+    // Answers the initialization, then stays silent.
+    let controller = thread::spawn(move || {
+        let mut emulator = joycon_emulator::Emulator::new(device_type);
+        emulator.set_realtime(false);
+        while let Some(report) = peer.recv_timeout(Duration::from_millis(200)).unwrap() {
+            emulator.write(&report).unwrap();
+            let mut buf = [0; 362];
+            let len = emulator.read(&mut buf).unwrap();
+            peer.send(&buf[..len]).unwrap();
+        }
+        peer
+    });
+    let joycon = JoyCon::new(transport, device_type).unwrap();
+    let _peer = controller.join().unwrap();
+    let (handle, _reports) = joycon.spawn();
+
+    let start = Instant::now();
+    let result =
+        handle.call_subcmd_wait(joycon_sys::output::SubcommandRequestEnum::RequestDeviceInfo(()));
+    assert!(matches!(result, Err(Error::Timeout(_))));
+    assert!(start.elapsed() >= REPLY_TIMEOUT);
+}
@@ -1,17 +1,22 @@
-use std::convert::TryInto;
+use std::{
+    convert::{TryFrom, TryInto},
+    ops::Range,
+};
 
 use crate::imu_handler;
-use anyhow::{bail, ensure, Context, Result};
+use crate::transport::{self, Transport};
+use crate::{CalibrationSource, Error, GyroCalibration, Result, SPIBackup};
 use cgmath::Vector2;
+use hid_gamepad_sys::StickFilter;
 use joycon_sys::mcu::*;
 use joycon_sys::output::*;
 use joycon_sys::spi::*;
 use joycon_sys::*;
-use joycon_sys::{imu::IMUMode, mcu::ir::*};
+use joycon_sys::{imu::IMUMode, mcu::ir::*, mcu::nfc::*};
 use joycon_sys::{input::*, light};
-use tracing::{field::debug, instrument, trace, Span};
+use tracing::{field::debug, info, instrument, trace, warn, Span};
 
-const WAIT_TIMEOUT: u32 = 200;
+pub(crate) const WAIT_TIMEOUT: u32 = 200;
 
 #[derive(Debug, Clone)]
 pub struct Report {
@@ -26,38 +31,43 @@ pub struct Report {
 }
 
 pub struct JoyCon {
-    device: hidapi::HidDevice,
-    info: hidapi::DeviceInfo,
+    device: Box<dyn Transport>,
     counter: u8,
     pub max_raw_gyro: i16,
     pub max_raw_accel: i16,
     left_stick_calib: LeftStickCalibration,
     right_stick_calib: RightStickCalibration,
+    sticks_calib_source: (CalibrationSource, CalibrationSource),
+    left_stick_filter: StickFilter,
+    right_stick_filter: StickFilter,
     #[cfg(feature = "ir")]
     image: crate::image::Image,
     enable_ir_loop: bool,
     imu_handler: crate::imu_handler::Handler,
+    /// Processed IMU frames of the last received report.
+    last_imu: Option<[imu_handler::IMU; 3]>,
+    /// Whether the IMU frames of the reports hold data.
+    imu_enabled: bool,
     device_type: WhichController,
+    /// Cached for the SPI backups.
+    mac_address: Option<MACAddress>,
+    spi_backup: Option<SPIBackup>,
+    factory_writes: bool,
 }
 
 impl JoyCon {
     #[instrument(level = "info", skip(device), err)]
-    pub fn new(device: hidapi::HidDevice, info: hidapi::DeviceInfo) -> Result<JoyCon> {
-        let device_type = match info.product_id() {
-            JOYCON_L_BT => WhichController::LeftJoyCon,
-            JOYCON_R_BT => WhichController::RightJoyCon,
-            PRO_CONTROLLER => WhichController::ProController,
-            JOYCON_CHARGING_GRIP => panic!("unsupported charging grip"),
-            _ => panic!("unknown controller type"),
-        };
+    pub fn new<T: Transport + 'static>(device: T, device_type: WhichController) -> Result<JoyCon> {
         let mut joycon = JoyCon {
-            device,
-            info,
+            device: Box::new(device),
             counter: 0,
             max_raw_gyro: 0,
             max_raw_accel: 0,
             left_stick_calib: LeftStickCalibration::default(),
             right_stick_calib: RightStickCalibration::default(),
+            sticks_calib_source: (CalibrationSource::Factory, CalibrationSource::Factory),
+            left_stick_filter: StickFilter::new(),
+            right_stick_filter: StickFilter::new(),
             #[cfg(feature = "ir")]
             image: crate::image::Image::new(),
             enable_ir_loop: false,
@@ -66,7 +76,12 @@ impl JoyCon {
                 imu::GyroSens::default(),
                 imu::AccSens::default(),
             ),
+            last_imu: None,
+            imu_enabled: true,
             device_type,
+            mac_address: None,
+            spi_backup: None,
+            factory_writes: false,
         };
 
         joycon.call_subcmd_wait(SubcommandRequest::disable_shipment_mode())?;
@@ -74,6 +89,15 @@ impl JoyCon {
         Ok(joycon)
     }
 
+    #[instrument(level = "info", skip(device, info), err)]
+    pub fn from_hidapi(device: hidapi::HidDevice, info: &hidapi::DeviceInfo) -> Result<JoyCon> {
+        JoyCon::new(device, transport::controller_type(info.product_id())?)
+    }
+
+    pub fn device_type(&self) -> WhichController {
+        self.device_type
+    }
+
     pub fn supports_ir(&self) -> bool {
         self.device_type == WhichController::RightJoyCon
     }
@@ -85,23 +109,49 @@ impl JoyCon {
         Span::current().record("special", &report.is_special());
         trace!(out_report = %hex::encode(report.as_bytes()));
         let nb_written = self.device.write(report.as_bytes())?;
-        assert_eq!(nb_written, report.byte_size());
+        if nb_written != report.byte_size() {
+            return Err(Error::ShortWrite {
+                written: nb_written,
+                expected: report.byte_size(),
+            });
+        }
         Ok(())
     }
 
     #[instrument(level = "trace", skip(self), fields(special, report))]
     pub fn recv(&mut self) -> Result<InputReport> {
+        loop {
+            if let Some(report) = self.recv_timeout(-1)? {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Same as `recv`, but returns `None` if no report is received before
+    /// `timeout` milliseconds.
+    pub(crate) fn recv_timeout(&mut self, timeout: i32) -> Result<Option<InputReport>> {
         let mut report = InputReport::new();
-        let nb_read = self.device.read(report.as_bytes_mut())?;
-        assert!(nb_read >= report.len(), "{} < {}", nb_read, report.len());
+        let nb_read = self.device.read_timeout(report.as_bytes_mut(), timeout)?;
+        if nb_read == 0 {
+            return Ok(None);
+        }
+        report.validate()?;
+        if nb_read < report.len() {
+            return Err(Error::ShortRead {
+                got: nb_read,
+                expected: report.len(),
+            });
+        }
         Span::current()
             .record("special", &report.is_special())
             .record("report", &debug(report));
         trace!(in__report = %hex::encode(report.as_bytes()));
-        report.validate();
-        if let Some(frames) = report.imu_frames() {
-            self.imu_handler.handle_frames(frames);
-        }
+        // Every frame goes through the handler, even if the report is then
+        // dropped, to keep the orientation up to date.
+        self.last_imu = report
+            .imu_frames()
+            .filter(|_| self.imu_enabled)
+            .map(|frames| self.imu_handler.handle_frames(frames));
         #[cfg(feature = "ir")]
         if let Some(mcu_report) = report.mcu_report() {
             if self.enable_ir_loop {
@@ -110,7 +160,7 @@ impl JoyCon {
                 }
             }
         }
-        Ok(report)
+        Ok(Some(report))
     }
 
     pub fn set_rumble(&mut self, rumble: RumbleData) -> Result<()> {
@@ -119,30 +169,61 @@ impl JoyCon {
     }
 
     pub fn tick(&mut self) -> Result<Report> {
-        let report = self.recv()?;
-        let std_report = report.standard().expect("should be standard");
+        // Skip the simple HID reports sent before the report mode is set
+        loop {
+            let report = self.recv()?;
+            if let Some(report) = self.process(report) {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Applies the calibration to a standard input report.
+    pub(crate) fn process(&mut self, report: InputReport) -> Option<Report> {
+        let std_report = *report.standard()?;
 
-        let left_stick = self
-            .left_stick_calib
-            .value_from_raw(std_report.left_stick.x(), std_report.left_stick.y());
-        let right_stick = self
-            .right_stick_calib
-            .value_from_raw(std_report.right_stick.x(), std_report.right_stick.y());
+        let left_stick = self.left_stick_filter.apply(
+            self.left_stick_calib
+                .value_from_raw(std_report.left_stick.x(), std_report.left_stick.y()),
+        );
+        let right_stick = self.right_stick_filter.apply(
+            self.right_stick_calib
+                .value_from_raw(std_report.right_stick.x(), std_report.right_stick.y()),
+        );
 
-        Ok(Report {
+        Some(Report {
             left_stick,
             right_stick,
             buttons: std_report.buttons,
             info: std_report.info,
             #[cfg(feature = "ir")]
             image: self.image.last_image.take(),
-            imu: report
-                .imu_frames()
-                .map(|f| self.imu_handler.handle_frames(f)),
+            imu: self.last_imu.take(),
             raw: report,
         })
     }
 
+    /// Processing of the left and right sticks.
+    pub fn stick_filters(&self) -> (StickFilter, StickFilter) {
+        (self.left_stick_filter, self.right_stick_filter)
+    }
+
+    /// Replaces the processing of the sticks.
+    ///
+    /// `load_calibration` sets the deadzones from the stick parameters of
+    /// the controller, so this should be called after.
+    pub fn set_stick_filters(&mut self, left: StickFilter, right: StickFilter) {
+        self.left_stick_filter = left;
+        self.right_stick_filter = right;
+    }
+
+    /// Calibration used for the left and right sticks.
+    ///
+    /// The user calibration is preferred, when there is one.
+    pub fn sticks_calibration_source(&self) -> (CalibrationSource, CalibrationSource) {
+        self.sticks_calib_source
+    }
+
     pub fn load_calibration(&mut self) -> Result<()> {
         let factor_sensor_calib = self.read_spi()?;
         self.imu_handler.set_factory(factor_sensor_calib);
@@ -153,16 +234,34 @@ impl JoyCon {
         self.imu_handler.reset_calibration();
 
         let factory_settings: SticksCalibration = self.read_spi()?;
-        //let user_result = self.read_spi(RANGE_USER_CALIBRATION_STICKS)?;
-        //let user_settings = user_result.sticks_user_calib().unwrap();
-        //self.left_stick_calib = user_settings.left.calib().unwrap_or(factory_settings.left);
-        //self.right_stick_calib = user_settings
-        //    .right
-        //    .calib()
-        //    .unwrap_or(factory_settings.right);
-        // TODO: fix
-        self.left_stick_calib = factory_settings.left;
-        self.right_stick_calib = factory_settings.right;
+        let user_settings: UserSticksCalibration = self.read_spi()?;
+        let (left, left_source) = match user_settings.left.calib() {
+            Some(calib) => (calib, CalibrationSource::User),
+            None => (factory_settings.left, CalibrationSource::Factory),
+        };
+        let (right, right_source) = match user_settings.right.calib() {
+            Some(calib) => (calib, CalibrationSource::User),
+            None => (factory_settings.right, CalibrationSource::Factory),
+        };
+        self.left_stick_calib = left;
+        self.right_stick_calib = right;
+        self.sticks_calib_source = (left_source, right_source);
+
+        // A Joy-Con only has the parameters of its own stick
+        if self.device_type != WhichController::RightJoyCon {
+            let params: LeftStickParameters = self.read_spi()?;
+            self.left_stick_filter.deadzone =
+                relative_deadzone(params.0.deadzone(), left.min(), left.center(), left.max());
+        }
+        if self.device_type != WhichController::LeftJoyCon {
+            let params: RightStickParameters = self.read_spi()?;
+            self.right_stick_filter.deadzone = relative_deadzone(
+                params.0.deadzone(),
+                right.min(),
+                right.center(),
+                right.max(),
+            );
+        }
 
         Ok(())
     }
@@ -206,13 +305,12 @@ impl JoyCon {
             let in_report = self.recv()?;
             if let Some(reply) = in_report.subcmd_reply() {
                 if reply.id() == subcmd.id() {
-                    ensure!(reply.ack().is_ok(), "subcmd reply is nack");
-                    return Ok(*reply);
+                    return check_reply(reply);
                 }
             }
         }
 
-        bail!("Timeout while waiting for subcommand");
+        Err(Error::Timeout(subcmd.id()))
     }
 
     #[instrument(level = "info", skip(self), err)]
@@ -226,7 +324,9 @@ impl JoyCon {
     pub fn read_spi_raw(&mut self, range: SPIRange) -> Result<[u8; 0x1D]> {
         let reply = self.call_subcmd_wait(SPIReadRequest::new(range))?;
         let result = reply.spi_read_result().unwrap();
-        assert_eq!(result.range(), range);
+        if result.range() != range {
+            return Err(WrongRangeError::new(range, result.range()).into());
+        }
         Ok(result.raw())
     }
 
@@ -235,15 +335,197 @@ impl JoyCon {
         &mut self,
         value: S,
     ) -> Result<bool> {
-        let reply = self.call_subcmd_wait(value.into())?;
+        let request: SPIWriteRequest = value.into();
+        let range = request.range();
+        self.prepare_spi_write(range.offset(), range.size() as usize)?;
+        let reply = self.call_subcmd_wait(request)?;
         Ok(reply.is_spi_write_success().unwrap())
     }
 
     #[instrument(level = "info", skip(self), err)]
     pub unsafe fn write_spi_raw(&mut self, range: SPIRange, data: &[u8]) -> Result<bool> {
+        self.prepare_spi_write(range.offset(), range.size() as usize)?;
+        self.write_spi_chunk(range, data)
+    }
+
+    unsafe fn write_spi_chunk(&mut self, range: SPIRange, data: &[u8]) -> Result<bool> {
         let reply = self.call_subcmd_wait(SPIWriteRequest::new(range, data))?;
         Ok(reply.is_spi_write_success().unwrap())
     }
+
+    /// Saves the content of the SPI flash before each write, to be able to
+    /// undo it with `undo_spi_write`.
+    pub fn set_spi_backup(&mut self, backup: Option<SPIBackup>) {
+        self.spi_backup = backup;
+    }
+
+    /// Allows writing to `SPI_FACTORY_AREA`, which is refused by default.
+    pub fn allow_factory_writes(&mut self, allow: bool) {
+        self.factory_writes = allow;
+    }
+
+    /// Restores the content of the flash saved before the last write, and
+    /// deletes this backup.
+    ///
+    /// Returns the restored range, or `None` if there is no backup left.
+    #[instrument(level = "info", skip(self), err)]
+    pub fn undo_spi_write(&mut self) -> Result<Option<Range<u32>>> {
+        let backup = self
+            .spi_backup
+            .clone()
+            .ok_or_else(|| Error::InvalidArgument("no SPI backup directory".to_string()))?;
+        let mac = self.mac_address()?;
+        let entry = match backup.last(mac)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        self.write_spi_chunks(entry.offset, &entry.data, |_, _| {})?;
+        std::fs::remove_file(&entry.path)?;
+        Ok(Some(entry.offset..entry.offset + entry.data.len() as u32))
+    }
+
+    /// Checks the write protection and takes a backup if enabled.
+    fn prepare_spi_write(&mut self, offset: u32, len: usize) -> Result<()> {
+        let end = spi_region_end(offset, len)?;
+        if !self.factory_writes && offset < SPI_FACTORY_AREA.end && SPI_FACTORY_AREA.start < end {
+            return Err(Error::SPIWriteProtected(offset, end));
+        }
+        if let Some(backup) = self.spi_backup.clone() {
+            let data = self.read_spi_region(offset, len)?;
+            let mac = self.mac_address()?;
+            let path = backup.save(mac, offset, &data)?;
+            info!(path = %path.display(), "saved SPI backup");
+        }
+        Ok(())
+    }
+
+    fn mac_address(&mut self) -> Result<MACAddress> {
+        if let Some(mac) = self.mac_address {
+            return Ok(mac);
+        }
+        let mac = self.get_dev_info()?.mac_address;
+        self.mac_address = Some(mac);
+        Ok(mac)
+    }
+
+    /// Reads `len` bytes of the SPI flash, starting at `offset`.
+    pub fn read_spi_region(&mut self, offset: u32, len: usize) -> Result<Vec<u8>> {
+        self.read_spi_region_with_progress(offset, len, |_, _| {})
+    }
+
+    /// Like `read_spi_region`, calling `progress(done, total)` in bytes
+    /// after each chunk.
+    #[instrument(level = "info", skip(self, progress), err)]
+    pub fn read_spi_region_with_progress(
+        &mut self,
+        offset: u32,
+        len: usize,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<u8>> {
+        let chunks = spi_chunks(offset, len)?;
+        let mut out = Vec::with_capacity(len);
+        for range in chunks {
+            let chunk = retry_spi(|| self.read_spi_raw(range))?;
+            out.extend_from_slice(&chunk[..range.size() as usize]);
+            progress(out.len(), len);
+        }
+        Ok(out)
+    }
+
+    /// Writes `data` to the SPI flash, starting at `offset`.
+    pub fn write_spi_region(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        self.write_spi_region_with_progress(offset, data, |_, _| {})
+    }
+
+    /// Like `write_spi_region`, calling `progress(done, total)` in bytes
+    /// after each chunk.
+    #[instrument(level = "info", skip(self, data, progress), err)]
+    pub fn write_spi_region_with_progress(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        self.prepare_spi_write(offset, data.len())?;
+        self.write_spi_chunks(offset, data, progress)
+    }
+
+    fn write_spi_chunks(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        for range in spi_chunks(offset, data.len())? {
+            let start = (range.offset() - offset) as usize;
+            let chunk = &data[start..start + range.size() as usize];
+            retry_spi(|| {
+                if unsafe { self.write_spi_chunk(range, chunk)? } {
+                    Ok(())
+                } else {
+                    Err(Error::SPIWriteFailed(range.offset()))
+                }
+            })?;
+            progress(start + chunk.len(), data.len());
+        }
+        Ok(())
+    }
+}
+
+/// Attempts for each chunk of a SPI region.
+const SPI_ATTEMPTS: usize = 3;
+
+/// End of a SPI region, checking that it fits in the flash.
+fn spi_region_end(offset: u32, len: usize) -> Result<u32> {
+    u32::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len))
+        .filter(|&end| end <= SPI_FLASH_SIZE)
+        .ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "SPI region of 0x{:x} bytes at 0x{:x} is out of the flash",
+                len, offset
+            ))
+        })
+}
+
+/// Splits a SPI region in ranges small enough for a single subcommand.
+fn spi_chunks(offset: u32, len: usize) -> Result<impl Iterator<Item = SPIRange>> {
+    let end = spi_region_end(offset, len)? as usize;
+    Ok((offset as usize..end)
+        .step_by(SPI_MAX_CHUNK as usize)
+        .map(move |start| {
+            let size = (end - start).min(SPI_MAX_CHUNK as usize);
+            // The size is checked just above
+            unsafe { SPIRange::new(start as u32, size as u8) }
+        }))
+}
+
+/// Retries the transient failures of a SPI operation.
+fn retry_spi<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e @ Error::Timeout(_))
+            | Err(e @ Error::SPIWrongRange(_))
+            | Err(e @ Error::SPIWriteFailed(_))
+                if attempt < SPI_ATTEMPTS =>
+            {
+                warn!(error = %e, attempt, "retrying SPI operation");
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Deadzone relative to the average range of the stick.
+fn relative_deadzone(deadzone: u16, min: (u16, u16), center: (u16, u16), max: (u16, u16)) -> f64 {
+    let range = (max.0 - center.0) + (max.1 - center.1) + (center.0 - min.0) + (center.1 - min.1);
+    if range == 0 {
+        return 0.;
+    }
+    (deadzone as f64 * 4. / range as f64).min(1.)
 }
 
 /// MCU handling (infrared camera and NFC reader)
@@ -281,8 +563,7 @@ impl JoyCon {
     fn enable_mcu(&mut self) -> Result<()> {
         self.set_report_mode_mcu()?;
         self.call_subcmd_wait(SubcommandRequestEnum::SetMCUState(MCUMode::Standby.into()))?;
-        self.wait_mcu_status(MCUMode::Standby)
-            .context("enable_mcu")?;
+        self.wait_mcu_status(MCUMode::Standby)?;
         Ok(())
     }
 
@@ -297,8 +578,7 @@ impl JoyCon {
     #[instrument(level = "info", skip(self), err)]
     fn set_mcu_mode_ir(&mut self) -> Result<()> {
         self.call_subcmd_wait(MCUCommand::set_mcu_mode(MCUMode::IR))?;
-        self.wait_mcu_status(MCUMode::IR)
-            .context("set_mcu_mode_ir")?;
+        self.wait_mcu_status(MCUMode::IR)?;
         self.enable_ir_loop = true;
         Ok(())
     }
@@ -325,8 +605,7 @@ impl JoyCon {
             r.ir_status()
                 .map(|status| dbg!(status.ir_mode) == ir_mode)
                 .unwrap_or(false)
-        })
-        .context("check sensor state")?;
+        })?;
         Ok(())
     }
 
@@ -342,17 +621,15 @@ impl JoyCon {
                 offset,
                 nb_registers,
             });
-            let mcu_report = self
-                .wait_mcu_cond(request, |mcu_report| {
-                    if let Some(reg_slice) = mcu_report.ir_registers() {
-                        reg_slice.page == page
-                            && reg_slice.offset == offset
-                            && reg_slice.nb_registers == nb_registers
-                    } else {
-                        false
-                    }
-                })
-                .context("get IR registers slice")?;
+            let mcu_report = self.wait_mcu_cond(request, |mcu_report| {
+                if let Some(reg_slice) = mcu_report.ir_registers() {
+                    reg_slice.page == page
+                        && reg_slice.offset == offset
+                        && reg_slice.nb_registers == nb_registers
+                } else {
+                    false
+                }
+            })?;
             let reg_slice = mcu_report.ir_registers().expect("already validated above");
             registers.extend(Register::decode_raw(
                 page,
@@ -382,12 +659,9 @@ impl JoyCon {
 
     #[instrument(level = "info", skip(self), err)]
     pub fn change_ir_resolution(&mut self, resolution: Resolution) -> Result<()> {
-        self.set_ir_wait_conf()
-            .context("change_ir_resolution reset")?;
-        self.set_ir_registers(&[Register::resolution(resolution), Register::finish()])
-            .context("change_ir_resolution")?;
-        self.set_ir_image_mode(MCUIRMode::ImageTransfer, resolution.max_fragment_id())
-            .context("change_ir_resolution enable")?;
+        self.set_ir_wait_conf()?;
+        self.set_ir_registers(&[Register::resolution(resolution), Register::finish()])?;
+        self.set_ir_image_mode(MCUIRMode::ImageTransfer, resolution.max_fragment_id())?;
         #[cfg(feature = "ir")]
         self.image.change_resolution(resolution);
         Ok(())
@@ -415,8 +689,7 @@ impl JoyCon {
             r.ir_status()
                 .map(|status| status.ir_mode == MCUIRMode::WaitingForConfigurationMaybe)
                 .unwrap_or(false)
-        })
-        .context("check sensor state")?;
+        })?;
         Ok(())
     }
 
@@ -446,7 +719,7 @@ impl JoyCon {
                 }
             }
         }
-        bail!("error getting the MCU status: timeout");
+        Err(Error::MCUTimeout)
     }
 
     #[instrument(level = "debug", skip(self), err)]
@@ -460,28 +733,262 @@ impl JoyCon {
     }
 }
 
+/// NFC handling (amiibo reader)
+impl JoyCon {
+    pub fn supports_nfc(&self) -> bool {
+        self.device_type == WhichController::RightJoyCon
+            || self.device_type == WhichController::ProController
+    }
+
+    #[instrument(level = "info", skip(self), err)]
+    pub fn enable_nfc(&mut self) -> Result<()> {
+        if !self.supports_nfc() {
+            return Err(Error::NFC(format!(
+                "not supported on the {}",
+                self.device_type
+            )));
+        }
+        self.enable_mcu()?;
+        self.call_subcmd_wait(MCUCommand::set_mcu_mode(MCUMode::NFC))?;
+        self.wait_mcu_status(MCUMode::NFC)?;
+        self.wait_nfc_state(NFCState::None)?;
+        Ok(())
+    }
+
+    #[instrument(level = "info", skip(self), err)]
+    pub fn disable_nfc(&mut self) -> Result<()> {
+        self.send_mcu_subcmd(NFCRequest::stop_polling().into())?;
+        self.disable_mcu()?;
+        Ok(())
+    }
+
+    /// Wait for a tag to be placed on the reader.
+    #[instrument(level = "info", skip(self), err)]
+    pub fn poll_nfc_tag(&mut self) -> Result<NFCTagInfo> {
+        self.send_mcu_subcmd(NFCRequest::start_polling().into())?;
+        let mcu_report = self.wait_nfc_state(NFCState::TagDetected)?;
+        Ok(mcu_report.nfc_state().expect("already validated above").tag)
+    }
+
+    /// Read the full content of the NTAG215 placed on the reader.
+    #[instrument(level = "info", skip(self), err)]
+    pub fn read_nfc_tag(&mut self) -> Result<[u8; NTAG215_SIZE]> {
+        self.enable_nfc()?;
+        let result = self.poll_nfc_tag().and_then(|tag| self.read_ntag215(&tag));
+        // Always turn the reader off, but report the first error.
+        let disabled = self.disable_nfc();
+        let dump = result?;
+        disabled?;
+        Ok(dump)
+    }
+
+    /// Write `data` to the NTAG215 with the given UID, starting at `start_page`.
+    ///
+    /// Only user pages can be written unless `allow_config_pages` is set, since
+    /// overwriting the lock or configuration pages can permanently brick the tag.
+    /// The written pages are read back to verify them.
+    #[instrument(level = "info", skip(self, data), err)]
+    pub fn write_nfc_tag(
+        &mut self,
+        uid: &[u8],
+        start_page: u8,
+        data: &[u8],
+        allow_config_pages: bool,
+    ) -> Result<()> {
+        let (nb_pages, remainder) = (data.len() / NTAG_PAGE_SIZE, data.len() % NTAG_PAGE_SIZE);
+        if remainder != 0 {
+            return Err(Error::InvalidArgument(format!(
+                "NFC write of {} bytes is not page-aligned",
+                data.len()
+            )));
+        }
+        let pages = start_page as usize..start_page as usize + nb_pages;
+        if pages.end > NTAG215_NB_PAGES {
+            return Err(Error::InvalidArgument(format!(
+                "NFC write of pages {:?} out of the tag",
+                pages
+            )));
+        }
+        if !allow_config_pages && !pages.clone().all(is_ntag215_user_page) {
+            return Err(Error::InvalidArgument(format!(
+                "NFC write of pages {:?} touches lock or configuration pages",
+                pages
+            )));
+        }
+
+        self.enable_nfc()?;
+        let result = self.write_ntag215(uid, start_page, data);
+        // Always turn the reader off, but report the first error.
+        let disabled = self.disable_nfc();
+        result?;
+        disabled
+    }
+
+    #[instrument(level = "debug", skip(self, data), err)]
+    fn write_ntag215(&mut self, uid: &[u8], start_page: u8, data: &[u8]) -> Result<()> {
+        let tag = self.poll_nfc_tag()?;
+        if tag.uid() != uid {
+            return Err(Error::NFC(format!(
+                "wrong tag: expected UID {}, got {}",
+                hex::encode(uid),
+                hex::encode(tag.uid())
+            )));
+        }
+        let raw_uid = tag
+            .uid()
+            .try_into()
+            .map_err(|_| Error::NFC(format!("unsupported UID {}", hex::encode(tag.uid()))))?;
+        for packet in NFCRequest::write_ntag(raw_uid, start_page, data) {
+            self.send_mcu_subcmd(packet.into())?;
+            // Don't flood the MCU
+            self.recv()?;
+        }
+        self.wait_nfc_state(NFCState::WriteFinished)?;
+
+        let tag = self.poll_nfc_tag()?;
+        let dump = self.read_ntag215(&tag)?;
+        let offset = start_page as usize * NTAG_PAGE_SIZE;
+        if dump[offset..offset + data.len()] != *data {
+            return Err(Error::NFC("write verification failed".to_string()));
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), err)]
+    fn read_ntag215(&mut self, tag: &NFCTagInfo) -> Result<[u8; NTAG215_SIZE]> {
+        if tag.tag_type != NFCTagType::NTAG {
+            return Err(Error::NFC(format!("unsupported tag {:?}", tag)));
+        }
+        self.send_mcu_subcmd(NFCRequest::read_ntag215(tag.uid().try_into().ok()).into())?;
+
+        let mut data = [0; NTAG215_SIZE];
+        let mut received = 0;
+        for _ in 0..WAIT_TIMEOUT {
+            let in_report = self.recv()?;
+            let mcu_report = match in_report.mcu_report() {
+                Some(mcu_report) => mcu_report,
+                None => continue,
+            };
+            if let Some(packet) = mcu_report.nfc_readdata() {
+                let result = packet.header.result;
+                if result != 0 {
+                    return Err(Error::NFC(format!("read error 0x{:x}", result)));
+                }
+                if let Some((offset, chunk)) = packet.data() {
+                    data[offset..offset + chunk.len()].copy_from_slice(chunk);
+                    received += chunk.len();
+                }
+                self.send_mcu_subcmd(NFCRequest::ack(packet.header.packet_number).into())?;
+                if packet.is_last() {
+                    if received != NTAG215_SIZE {
+                        return Err(Error::NFC(format!(
+                            "missing packets: got {} bytes",
+                            received
+                        )));
+                    }
+                    return Ok(data);
+                }
+            } else if let Some(status) = mcu_report.nfc_state() {
+                if status.state == NFCState::Error || status.state == NFCState::FaultyTag {
+                    return Err(Error::NFC(format!("read error: {:?}", status.state)));
+                }
+            }
+        }
+        Err(Error::MCUTimeout)
+    }
+
+    #[instrument(level = "debug", skip(self), err)]
+    fn wait_nfc_state(&mut self, state: NFCState) -> Result<MCUReport> {
+        let mut failed = false;
+        let mcu_report = self.wait_mcu_cond(NFCRequest::get_status(), |report| {
+            match report.nfc_state() {
+                Some(status)
+                    if status.state == NFCState::Error || status.state == NFCState::FaultyTag =>
+                {
+                    failed = true;
+                    true
+                }
+                Some(status) => status.state == state,
+                None => false,
+            }
+        })?;
+        if failed {
+            return Err(Error::NFC(format!(
+                "{:?}",
+                mcu_report
+                    .nfc_state()
+                    .expect("already validated above")
+                    .state
+            )));
+        }
+        Ok(mcu_report)
+    }
+}
+
 /// IMU handling (gyroscope and accelerometer)
 impl JoyCon {
+    /// Gains of the orientation filter, see `OrientationFilter`.
+    pub fn set_orientation_gains(&mut self, kp: f64, ki: f64) {
+        self.imu_handler.set_filter_gains(kp, ki);
+    }
+
+    /// Sets the current heading as the zero yaw of the orientation.
+    pub fn reset_yaw(&mut self) {
+        self.imu_handler.reset_yaw();
+    }
+
+    /// Gyroscope bias learned while the controller is still.
+    pub fn gyro_calibration(&self) -> &GyroCalibration {
+        self.imu_handler.gyro_calibration()
+    }
+
+    /// Tunes the stillness detection, see `GyroCalibration`.
+    pub fn gyro_calibration_mut(&mut self) -> &mut GyroCalibration {
+        self.imu_handler.gyro_calibration_mut()
+    }
+
     #[instrument(level = "info", skip(self), err)]
     pub fn enable_imu(&mut self) -> Result<()> {
         self.call_subcmd_wait(SubcommandRequestEnum::SetIMUMode(IMUMode::GyroAccel.into()))?;
+        self.imu_enabled = true;
         Ok(())
     }
 
-    // TODO: needed?
+    /// Turns the IMU off to save battery. The reports then have no IMU data.
     #[instrument(level = "info", skip(self), err)]
-    pub fn set_imu_sens(&mut self) -> Result<()> {
-        let gyro_sens = imu::GyroSens::DPS2000;
-        let accel_sens = imu::AccSens::G8;
-        self.call_subcmd_wait(imu::Sensitivity {
-            gyro_sens: gyro_sens.into(),
-            acc_sens: accel_sens.into(),
-            ..imu::Sensitivity::default()
-        })?;
-        // TODO
-        /*
-        self.gyro_sens = gyro_sens;
-        self.accel_sens = accel_sens;*/
+    pub fn disable_imu(&mut self) -> Result<()> {
+        self.call_subcmd_wait(SubcommandRequestEnum::SetIMUMode(IMUMode::Disabled.into()))?;
+        self.imu_enabled = false;
+        Ok(())
+    }
+
+    /// Sets the ranges and filters of the IMU.
+    ///
+    /// The frames received after the acknowledgment are converted with the
+    /// new ranges.
+    #[instrument(level = "info", skip(self), err)]
+    pub fn configure_imu(&mut self, sensitivity: imu::Sensitivity) -> Result<()> {
+        let invalid =
+            |field| Error::InvalidArgument(format!("unknown {} in {:?}", field, sensitivity));
+        let gyro_sens = sensitivity
+            .gyro_sens
+            .try_into()
+            .ok_or_else(|| invalid("gyroscope sensitivity"))?;
+        let accel_sens = sensitivity
+            .acc_sens
+            .try_into()
+            .ok_or_else(|| invalid("accelerometer sensitivity"))?;
+        sensitivity
+            .gyro_perf_rate
+            .try_into()
+            .ok_or_else(|| invalid("gyroscope performance rate"))?;
+        sensitivity
+            .acc_anti_aliasing
+            .try_into()
+            .ok_or_else(|| invalid("accelerometer anti-aliasing"))?;
+
+        self.call_subcmd_wait(sensitivity)?;
+        self.imu_handler.set_sensitivity(gyro_sens, accel_sens);
         Ok(())
     }
 }
@@ -493,7 +1000,8 @@ impl JoyCon {
         self.call_subcmd_wait(SubcommandRequestEnum::SetMCUState(MCUMode::Standby.into()))?;
         loop {
             let out = self.call_subcmd_wait(MCUCommand::set_mcu_mode(MCUMode::MaybeRingcon))?;
-            if out.mcu_report().unwrap().state_report().unwrap().state == MCUMode::MaybeRingcon {
+            let status = out.mcu_report().and_then(|r| r.state_report());
+            if status.map(|s| s.state == MCUMode::MaybeRingcon) == Some(true) {
                 break;
             }
         }
@@ -527,7 +1035,7 @@ impl JoyCon {
     }
 
     #[instrument(level = "debug", skip(self), err)]
-    pub fn mcu_wait_not_busy(&mut self) -> anyhow::Result<()> {
+    pub fn mcu_wait_not_busy(&mut self) -> Result<()> {
         loop {
             let report = self.recv()?;
             if let Some(x) = report.mcu_report() {
@@ -539,12 +1047,209 @@ impl JoyCon {
     }
 }
 
+pub(crate) fn check_reply(reply: &SubcommandReply) -> Result<SubcommandReply> {
+    if !reply.ack().is_ok() {
+        return Err(Error::Nack {
+            id: reply.id(),
+            ack: *reply.ack(),
+        });
+    }
+    Ok(*reply)
+}
+
 impl std::fmt::Debug for JoyCon {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("JoyCon")
-            .field("manufacturer", &self.info.manufacturer_string())
-            .field("product", &self.info.product_string())
-            .field("serial", &self.info.serial_number())
+            .field("device_type", &self.device_type)
             .finish()
     }
 }
+
+#[cfg(test)]
+#[test]
+fn configure_imu() {
+    let device_type = WhichController::ProController;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = JoyCon::new(emulator, device_type).unwrap();
+    joycon.load_calibration().unwrap();
+    joycon.enable_imu().unwrap();
+    let next_imu = |joycon: &mut JoyCon| loop {
+        if let Some(imu) = joycon.tick().unwrap().imu {
+            return imu[0];
+        }
+    };
+    assert!((next_imu(&mut joycon).accel.z - 1.).abs() < 1e-3);
+
+    joycon
+        .configure_imu(imu::Sensitivity {
+            acc_sens: imu::AccSens::G2.into(),
+            ..imu::Sensitivity::default()
+        })
+        .unwrap();
+    // The raw values change with the range, but not the converted ones
+    assert!((next_imu(&mut joycon).accel.z - 1.).abs() < 1e-3);
+
+    let invalid = imu::Sensitivity {
+        gyro_sens: RawId::new(0xff),
+        ..imu::Sensitivity::default()
+    };
+    assert!(matches!(
+        joycon.configure_imu(invalid),
+        Err(Error::InvalidArgument(_))
+    ));
+
+    joycon.disable_imu().unwrap();
+    assert!(joycon.tick().unwrap().imu.is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn user_stick_calibration() {
+    let device_type = WhichController::ProController;
+    let mut joycon = JoyCon::new(joycon_emulator::Emulator::new(device_type), device_type).unwrap();
+    joycon.load_calibration().unwrap();
+    assert_eq!(
+        joycon.sticks_calibration_source(),
+        (CalibrationSource::Factory, CalibrationSource::Factory)
+    );
+    // Deadzone of 150 for a range of 0x600
+    let (left, right) = joycon.stick_filters();
+    assert!((left.deadzone - 150. / 0x600 as f64).abs() < 1e-6);
+    assert_eq!(left.deadzone, right.deadzone);
+
+    let calib = LeftStickCalibration::new((0x100, 0x100), (0x800, 0x800), (0xf00, 0xf00));
+    assert!(joycon.write_spi(UserStickCalibration::from(calib)).unwrap());
+    joycon.load_calibration().unwrap();
+    assert_eq!(
+        joycon.sticks_calibration_source(),
+        (CalibrationSource::User, CalibrationSource::Factory)
+    );
+    assert_eq!(joycon.left_stick_calib.max(), (0xf00, 0xf00));
+}
+
+#[cfg(test)]
+#[test]
+fn spi_region() {
+    let device_type = WhichController::ProController;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = JoyCon::new(emulator, device_type).unwrap();
+
+    let data: Vec<u8> = (0..100).collect();
+    let mut steps = vec![];
+    joycon
+        .write_spi_region_with_progress(0x9000, &data, |done, total| steps.push((done, total)))
+        .unwrap();
+    assert_eq!(steps, [(29, 100), (58, 100), (87, 100), (100, 100)]);
+    assert_eq!(joycon.read_spi_region(0x9000, 100).unwrap(), data);
+
+    assert!(matches!(
+        joycon.read_spi_region(SPI_FLASH_SIZE - 10, 20),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        joycon.read_spi_region(0x9000, usize::MAX),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        joycon.write_spi_region(u32::MAX - 10, &[0; 20]),
+        Err(Error::InvalidArgument(_))
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn spi_backup() {
+    let device_type = WhichController::ProController;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = JoyCon::new(emulator, device_type).unwrap();
+
+    assert!(matches!(
+        joycon.write_spi(ControllerColor::default()),
+        Err(Error::SPIWriteProtected(0x6050, 0x605c))
+    ));
+
+    let dir = std::env::temp_dir().join(format!("joycon-spi-backup-{}", std::process::id()));
+    joycon.set_spi_backup(Some(SPIBackup::new(&dir)));
+    let before = joycon.read_spi_region(0x9000, 40).unwrap();
+    joycon.write_spi_region(0x9000, &[0x42; 40]).unwrap();
+    assert_eq!(joycon.read_spi_region(0x9000, 40).unwrap(), [0x42; 40]);
+    assert_eq!(joycon.undo_spi_write().unwrap(), Some(0x9000..0x9028));
+    assert_eq!(joycon.read_spi_region(0x9000, 40).unwrap(), before);
+    assert_eq!(joycon.undo_spi_write().unwrap(), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+fn nfc_joycon(dump: [u8; NTAG215_SIZE], ignore_writes: bool) -> JoyCon {
+    let device_type = WhichController::RightJoyCon;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    emulator.set_nfc_tag(Some(dump));
+    emulator.set_nfc_ignore_writes(ignore_writes);
+    JoyCon::new(emulator, device_type).unwrap()
+}
+
+#[cfg(test)]
+fn ntag215_dump() -> [u8; NTAG215_SIZE] {
+    let mut dump = [0; NTAG215_SIZE];
+    for (i, byte) in dump.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+    // UID 04a1b2c3d4e5f6, each part followed by its check byte
+    dump[..8].copy_from_slice(&[0x04, 0xa1, 0xb2, 0x88, 0xc3, 0xd4, 0xe5, 0xf6]);
+    dump
+}
+
+#[cfg(test)]
+#[test]
+fn read_nfc_tag() {
+    let dump = ntag215_dump();
+    let mut joycon = nfc_joycon(dump, false);
+    joycon.enable_nfc().unwrap();
+    let tag = joycon.poll_nfc_tag().unwrap();
+    assert!(tag.tag_type == NFCTagType::NTAG);
+    assert_eq!(tag.uid(), [0x04, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6]);
+    joycon.disable_nfc().unwrap();
+
+    assert_eq!(joycon.read_nfc_tag().unwrap()[..], dump[..]);
+}
+
+#[cfg(test)]
+#[test]
+fn write_nfc_tag() {
+    let dump = ntag215_dump();
+    let uid = [0x04, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6];
+    let mut joycon = nfc_joycon(dump, false);
+    let data: Vec<u8> = (0..40).collect();
+    joycon.write_nfc_tag(&uid, 0x10, &data, false).unwrap();
+    let mut expected = dump;
+    expected[0x40..0x40 + data.len()].copy_from_slice(&data);
+    assert_eq!(joycon.read_nfc_tag().unwrap()[..], expected[..]);
+
+    let invalid = |result| matches!(result, Err(Error::InvalidArgument(_)));
+    assert!(invalid(joycon.write_nfc_tag(&uid, 0x03, &[0; 8], false)));
+    assert!(invalid(joycon.write_nfc_tag(&uid, 0x82, &[0; 4], false)));
+    assert!(invalid(joycon.write_nfc_tag(&uid, 0x10, &[0; 5], false)));
+    assert!(invalid(joycon.write_nfc_tag(&uid, 0x86, &[0; 8], true)));
+    joycon.write_nfc_tag(&uid, 0x82, &[0; 4], true).unwrap();
+
+    assert!(matches!(
+        joycon.write_nfc_tag(&[0x04, 0, 0, 0, 0, 0, 0], 0x10, &[0; 4], false),
+        Err(Error::NFC(msg)) if msg.starts_with("wrong tag")
+    ));
+    assert_eq!(joycon.read_nfc_tag().unwrap()[0x40..0x44], data[..4]);
+}
+
+#[cfg(test)]
+#[test]
+fn write_nfc_tag_verification() {
+    let uid = [0x04, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6];
+    let mut joycon = nfc_joycon(ntag215_dump(), true);
+    assert!(matches!(
+        joycon.write_nfc_tag(&uid, 0x10, &[0x42; 8], false),
+        Err(Error::NFC(msg)) if msg == "write verification failed"
+    ));
+}
@@ -1,5 +1,6 @@
-use crate::calibration::Calibration;
+use crate::calibration::GyroCalibration;
 use cgmath::*;
+use hid_gamepad_sys::OrientationFilter;
 use input::WhichController;
 use joycon_sys::*;
 
@@ -14,6 +15,12 @@ pub struct IMU {
     pub gyro: Vector3<f64>,
     /// Current acceleration.
     pub accel: Vector3<f64>,
+    /// Acceleration without the gravity.
+    pub linear_accel: Vector3<f64>,
+    /// Estimated orientation, see `OrientationFilter`.
+    pub orientation: Quaternion<f64>,
+    /// Confidence in the gyroscope bias compensation, see `GyroCalibration`.
+    pub calibration_confidence: f64,
 }
 
 impl IMU {
@@ -23,12 +30,12 @@ impl IMU {
 
 pub struct Handler {
     device_type: WhichController,
-    calib_gyro: Calibration,
+    calib_gyro: GyroCalibration,
     gyro_sens: imu::GyroSens,
     accel_sens: imu::AccSens,
     factory_calibration: spi::SensorCalibration,
     user_calibration: spi::UserSensorCalibration,
-    calib_nb: u32,
+    filter: OrientationFilter,
 }
 
 impl Handler {
@@ -39,12 +46,12 @@ impl Handler {
     ) -> Self {
         Handler {
             device_type,
-            calib_gyro: Calibration::with_capacity(200),
+            calib_gyro: GyroCalibration::default(),
             gyro_sens,
             accel_sens,
             factory_calibration: spi::SensorCalibration::default(),
             user_calibration: spi::UserSensorCalibration::default(),
-            calib_nb: 0,
+            filter: OrientationFilter::new(),
         }
     }
 
@@ -68,23 +75,41 @@ impl Handler {
             .unwrap_or_else(|| self.factory_calibration.gyro_offset())
     }
 
+    fn acc_factor(&self) -> Vector3<f64> {
+        self.user_calibration
+            .acc_factor()
+            .unwrap_or_else(|| self.factory_calibration.acc_factor())
+    }
+
+    fn gyro_factor(&self) -> Vector3<f64> {
+        self.user_calibration
+            .gyro_factor()
+            .unwrap_or_else(|| self.factory_calibration.gyro_factor())
+    }
+
     pub fn handle_frames(&mut self, frames: &[imu::Frame]) -> [IMU; 3] {
         let gyro_offset = self.gyro_calib();
         let acc_offset = self.acc_calib();
+        let gyro_factor = self.gyro_factor();
+        let acc_factor = self.acc_factor();
         let mut out = [IMU {
             gyro: Vector3::zero(),
             accel: Vector3::zero(),
+            linear_accel: Vector3::zero(),
+            orientation: Quaternion::one(),
+            calibration_confidence: 0.,
         }; 3];
-        for (frame, out) in frames.iter().rev().zip(out.iter_mut()) {
-            let raw_rotation = frame.rotation_dps(gyro_offset, self.gyro_sens);
-            let raw_acc = frame.accel_g(acc_offset, self.accel_sens);
-            if self.calib_nb > 0 {
-                self.calib_gyro.push(raw_rotation);
-                self.calib_nb -= 1;
-            }
+        // `frames` is oldest first, `out` newest first.
+        for (frame, out) in frames.iter().zip(out.iter_mut().rev()) {
+            let raw_rotation = frame.rotation_dps(gyro_offset, gyro_factor, self.gyro_sens);
+            let raw_acc = frame.accel_g(acc_offset, acc_factor, self.accel_sens);
+            self.calib_gyro.push(raw_rotation, raw_acc);
             *out = IMU {
-                gyro: raw_rotation - self.calib_gyro.get_average(),
+                gyro: raw_rotation - self.calib_gyro.bias(),
                 accel: raw_acc,
+                linear_accel: Vector3::zero(),
+                orientation: Quaternion::one(),
+                calibration_confidence: self.calib_gyro.confidence(),
             };
             // The devices don't have the same axis.
             match self.device_type {
@@ -97,12 +122,36 @@ impl Handler {
                     out.accel = -out.accel;
                 }
             }
+            out.orientation = self
+                .filter
+                .update(out.gyro, out.accel, IMU::SAMPLE_DURATION);
+            out.linear_accel = self.filter.linear_acceleration(out.accel);
         }
         out
     }
 
+    pub fn set_sensitivity(&mut self, gyro_sens: imu::GyroSens, accel_sens: imu::AccSens) {
+        self.gyro_sens = gyro_sens;
+        self.accel_sens = accel_sens;
+    }
+
+    pub fn set_filter_gains(&mut self, kp: f64, ki: f64) {
+        self.filter.set_gains(kp, ki);
+    }
+
+    pub fn reset_yaw(&mut self) {
+        self.filter.reset_yaw();
+    }
+
+    pub fn gyro_calibration(&self) -> &GyroCalibration {
+        &self.calib_gyro
+    }
+
+    pub fn gyro_calibration_mut(&mut self) -> &mut GyroCalibration {
+        &mut self.calib_gyro
+    }
+
     pub fn reset_calibration(&mut self) {
         self.calib_gyro.reset();
-        self.calib_nb = 0;
     }
 }
@@ -1,19 +1,34 @@
+#[cfg(feature = "async")]
+mod async_joycon;
 mod calibration;
+mod error;
+mod handle;
 mod hid;
 #[cfg(feature = "ir")]
 mod image;
 mod imu_handler;
+mod manager;
+mod spi_backup;
+pub mod transport;
 
 #[cfg(feature = "ir")]
 pub use crate::image::*;
-use anyhow::Result;
+#[cfg(feature = "async")]
+pub use async_joycon::*;
 pub use calibration::*;
-use cgmath::vec3;
+use cgmath::{vec3, Matrix3, Quaternion};
+pub use error::*;
+pub use handle::*;
 pub use hid::*;
+pub use hid_gamepad_sys::{DeadzoneShape, StickFilter};
 use hid_gamepad_sys::{GamepadDevice, GamepadDriver, JoyKey, Motion};
 use hidapi::HidApi;
 pub use imu_handler::IMU;
+#[cfg(feature = "emulator")]
+pub use joycon_emulator;
 pub use joycon_sys;
+pub use manager::*;
+pub use spi_backup::*;
 
 pub use hidapi;
 use joycon_sys::{imu::IMU_SAMPLES_PER_SECOND, NINTENDO_VENDOR_ID};
@@ -25,9 +40,9 @@ impl GamepadDriver for JoyconDriver {
         &self,
         api: &HidApi,
         device_info: &hidapi::DeviceInfo,
-    ) -> Result<Option<Box<dyn GamepadDevice>>> {
+    ) -> anyhow::Result<Option<Box<dyn GamepadDevice>>> {
         if device_info.vendor_id() == NINTENDO_VENDOR_ID {
-            let mut joycon = JoyCon::new(device_info.open_device(api)?, device_info.clone())?;
+            let mut joycon = JoyCon::from_hidapi(device_info.open_device(api)?, device_info)?;
             joycon.enable_imu()?;
             joycon.load_calibration()?;
             Ok(Some(Box::new(joycon)))
@@ -38,10 +53,14 @@ impl GamepadDriver for JoyconDriver {
 }
 
 impl GamepadDevice for JoyCon {
-    fn recv(&mut self) -> Result<hid_gamepad_sys::Report> {
+    fn recv(&mut self) -> anyhow::Result<hid_gamepad_sys::Report> {
         Ok(self.tick()?.into())
     }
 
+    fn set_stick_filters(&mut self, left: StickFilter, right: StickFilter) {
+        JoyCon::set_stick_filters(self, left, right)
+    }
+
     fn as_any(&mut self) -> &mut dyn std::any::Any {
         self
     }
@@ -50,16 +69,26 @@ impl GamepadDevice for JoyCon {
 impl From<Report> for hid_gamepad_sys::Report {
     fn from(report: Report) -> Self {
         let b = &report.buttons;
+        // Same axis change as `rotation_speed`
+        let gyro_axes = Quaternion::from(Matrix3::new(0., 0., -1., 1., 0., 0., 0., -1., 0.));
         Self {
             left_joystick: report.left_stick,
             right_joystick: report.right_stick,
+            // Empty when the IMU is disabled
             motion: report
                 .imu
-                .unwrap()
                 .iter()
+                .flatten()
                 .map(|x| Motion {
                     acceleration: vec3(-x.accel.y, x.accel.z, x.accel.x).into(),
                     rotation_speed: vec3(x.gyro.y, -x.gyro.z, -x.gyro.x).into(),
+                    linear_acceleration: vec3(
+                        -x.linear_accel.y,
+                        x.linear_accel.z,
+                        x.linear_accel.x,
+                    )
+                    .into(),
+                    orientation: gyro_axes * x.orientation * gyro_axes.conjugate(),
                 })
                 .collect(),
             keys: enum_map::enum_map! {
@@ -88,3 +117,16 @@ impl From<Report> for hid_gamepad_sys::Report {
         }
     }
 }
+
+#[cfg(test)]
+#[test]
+fn gamepad_report_without_imu() {
+    let device_type = joycon_sys::input::WhichController::ProController;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = JoyCon::new(emulator, device_type).unwrap();
+    joycon.disable_imu().unwrap();
+    let report = joycon.tick().unwrap();
+    assert!(report.imu.is_none());
+    assert!(hid_gamepad_sys::Report::from(report).motion.is_empty());
+}
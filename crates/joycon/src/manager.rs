@@ -0,0 +1,369 @@
+//! Hotplug handling for several controllers at once.
+
+use crate::handle::{ReportSender, REPORT_QUEUE};
+use crate::transport::{self, Transport};
+use crate::{Error, JoyCon, JoyConHandle, Report, Result};
+use hidapi::HidApi;
+use joycon_sys::{
+    input::WhichController,
+    light::{PlayerLight, PlayerLights},
+    NINTENDO_VENDOR_ID,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::CString,
+    fmt,
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Time between two scans for new or removed controllers.
+const SCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Stable ID of a controller, kept across reconnections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ControllerId(pub u32);
+
+impl fmt::Display for ControllerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A controller seen by a `DeviceSource`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceEntry {
+    /// Path used to open the device.
+    pub path: String,
+    /// MAC address for bluetooth controllers.
+    pub serial: Option<String>,
+    pub device_type: WhichController,
+}
+
+impl DeviceEntry {
+    /// Key of the stable ID: the serial if known, else the path.
+    pub fn key(&self) -> &str {
+        self.serial.as_deref().unwrap_or(&self.path)
+    }
+}
+
+/// Enumerates and opens the controllers for a `ControllerManager`.
+pub trait DeviceSource {
+    /// Controllers currently connected.
+    fn scan(&mut self) -> Result<Vec<DeviceEntry>>;
+
+    fn open(&mut self, device: &DeviceEntry) -> Result<Box<dyn Transport>>;
+}
+
+/// Controllers seen by hidapi.
+pub struct HidapiSource {
+    api: HidApi,
+}
+
+impl HidapiSource {
+    pub fn new() -> Result<HidapiSource> {
+        Ok(HidapiSource {
+            api: HidApi::new()?,
+        })
+    }
+}
+
+impl DeviceSource for HidapiSource {
+    fn scan(&mut self) -> Result<Vec<DeviceEntry>> {
+        self.api.refresh_devices()?;
+        Ok(self
+            .api
+            .device_list()
+            .filter(|info| info.vendor_id() == NINTENDO_VENDOR_ID)
+            .filter_map(|info| {
+                Some(DeviceEntry {
+                    path: info.path().to_string_lossy().into_owned(),
+                    serial: info
+                        .serial_number()
+                        .filter(|serial| !serial.is_empty())
+                        .map(String::from),
+                    device_type: transport::controller_type(info.product_id()).ok()?,
+                })
+            })
+            .collect())
+    }
+
+    fn open(&mut self, device: &DeviceEntry) -> Result<Box<dyn Transport>> {
+        let path = CString::new(device.path.as_str())
+            .map_err(|_| Error::InvalidArgument(format!("invalid path {:?}", device.path)))?;
+        Ok(Box::new(self.api.open_path(&path)?))
+    }
+}
+
+/// Virtual controllers that can be plugged and unplugged at will.
+#[cfg(any(test, feature = "emulator"))]
+#[derive(Clone, Default)]
+pub struct EmulatedSource {
+    devices: std::sync::Arc<std::sync::Mutex<Vec<DeviceEntry>>>,
+}
+
+#[cfg(any(test, feature = "emulator"))]
+impl EmulatedSource {
+    pub fn new() -> EmulatedSource {
+        EmulatedSource::default()
+    }
+
+    pub fn plug(&self, serial: &str, device_type: WhichController) {
+        self.devices.lock().unwrap().push(DeviceEntry {
+            path: format!("emulated/{}", serial),
+            serial: Some(serial.to_string()),
+            device_type,
+        });
+    }
+
+    pub fn unplug(&self, serial: &str) {
+        self.devices
+            .lock()
+            .unwrap()
+            .retain(|d| d.serial.as_deref() != Some(serial));
+    }
+}
+
+#[cfg(any(test, feature = "emulator"))]
+impl DeviceSource for EmulatedSource {
+    fn scan(&mut self) -> Result<Vec<DeviceEntry>> {
+        Ok(self.devices.lock().unwrap().clone())
+    }
+
+    fn open(&mut self, device: &DeviceEntry) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(joycon_emulator::Emulator::new(device.device_type)))
+    }
+}
+
+#[derive(Debug)]
+pub enum ControllerEvent {
+    /// A controller was connected and initialized, or reconnected.
+    Connected {
+        id: ControllerId,
+        device_type: WhichController,
+    },
+    Disconnected {
+        id: ControllerId,
+    },
+    Report {
+        id: ControllerId,
+        report: Box<Report>,
+    },
+}
+
+struct Connected {
+    key: String,
+    handle: JoyConHandle,
+}
+
+/// Tracks the connected controllers and merges their reports.
+///
+/// Each controller gets a stable ID keyed by its serial. On every
+/// (re)connection, the calibration is loaded, the IMU is enabled and the
+/// player lights show the ID.
+pub struct ControllerManager<S = HidapiSource> {
+    source: S,
+    ids: HashMap<String, ControllerId>,
+    connected: HashMap<ControllerId, Connected>,
+    events: VecDeque<ControllerEvent>,
+    reports_tx: SyncSender<(ControllerId, Result<Report>)>,
+    reports_rx: Receiver<(ControllerId, Result<Report>)>,
+    next_scan: Instant,
+}
+
+impl ControllerManager<HidapiSource> {
+    pub fn with_hidapi() -> Result<ControllerManager<HidapiSource>> {
+        Ok(ControllerManager::new(HidapiSource::new()?))
+    }
+}
+
+impl<S: DeviceSource> ControllerManager<S> {
+    pub fn new(source: S) -> ControllerManager<S> {
+        let (reports_tx, reports_rx) = sync_channel(REPORT_QUEUE);
+        ControllerManager {
+            source,
+            ids: HashMap::new(),
+            connected: HashMap::new(),
+            events: VecDeque::new(),
+            reports_tx,
+            reports_rx,
+            next_scan: Instant::now(),
+        }
+    }
+
+    /// Handle of a connected controller, to send it commands.
+    pub fn handle(&self, id: ControllerId) -> Option<&JoyConHandle> {
+        self.connected.get(&id).map(|c| &c.handle)
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = (ControllerId, &JoyConHandle)> {
+        self.connected.iter().map(|(id, c)| (*id, &c.handle))
+    }
+
+    /// Scans for new and removed controllers.
+    ///
+    /// Called automatically by `next_event`.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.next_scan = Instant::now() + SCAN_INTERVAL;
+        let devices = self.source.scan()?;
+
+        let present: HashSet<&str> = devices.iter().map(DeviceEntry::key).collect();
+        let removed: Vec<ControllerId> = self
+            .connected
+            .iter()
+            .filter(|(_, c)| !present.contains(c.key.as_str()))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in removed {
+            self.disconnect(id);
+        }
+
+        for device in &devices {
+            let id = self.id(device.key());
+            if self.connected.contains_key(&id) {
+                continue;
+            }
+            match self.connect(id, device) {
+                Ok(handle) => {
+                    info!(%id, device_type = %device.device_type, "controller connected");
+                    self.connected.insert(
+                        id,
+                        Connected {
+                            key: device.key().to_string(),
+                            handle,
+                        },
+                    );
+                    self.events.push_back(ControllerEvent::Connected {
+                        id,
+                        device_type: device.device_type,
+                    });
+                }
+                // Retried on the next scan
+                Err(e) => warn!(%id, error = %e, "controller initialization failed"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Next event of any controller, or `None` after `timeout`.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<ControllerEvent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if now >= self.next_scan {
+                self.refresh()?;
+                continue;
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+            match self
+                .reports_rx
+                .recv_timeout(deadline.min(self.next_scan) - now)
+            {
+                // Reports still queued after a disconnection are ignored
+                Ok((id, _)) if !self.connected.contains_key(&id) => {}
+                Ok((id, Ok(report))) => {
+                    return Ok(Some(ControllerEvent::Report {
+                        id,
+                        report: Box::new(report),
+                    }))
+                }
+                Ok((id, Err(e))) => {
+                    warn!(%id, error = %e, "controller error");
+                    self.disconnect(id);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!("the manager owns a sender"),
+            }
+        }
+    }
+
+    fn id(&mut self, key: &str) -> ControllerId {
+        let next_id = ControllerId(self.ids.len() as u32);
+        *self.ids.entry(key.to_string()).or_insert(next_id)
+    }
+
+    fn connect(&mut self, id: ControllerId, device: &DeviceEntry) -> Result<JoyConHandle> {
+        let transport = self.source.open(device)?;
+        let mut joycon = JoyCon::new(transport, device.device_type)?;
+        joycon.load_calibration()?;
+        joycon.enable_imu()?;
+        joycon.set_player_light(player_lights(id))?;
+        Ok(joycon.spawn_reader(ReportSender::Tagged {
+            reports: self.reports_tx.clone(),
+            id,
+        }))
+    }
+
+    fn disconnect(&mut self, id: ControllerId) {
+        if let Some(controller) = self.connected.remove(&id) {
+            info!(%id, "controller disconnected");
+            controller.handle.close();
+            self.events.push_back(ControllerEvent::Disconnected { id });
+        }
+    }
+}
+
+/// Lights the LED of the player number.
+fn player_lights(id: ControllerId) -> PlayerLights {
+    let light = |i| {
+        if id.0 % 4 == i {
+            PlayerLight::On
+        } else {
+            PlayerLight::Off
+        }
+    };
+    PlayerLights::new(light(0), light(1), light(2), light(3))
+}
+
+#[cfg(test)]
+#[test]
+fn hotplug() {
+    let source = EmulatedSource::new();
+    let mut manager = ControllerManager::new(source.clone());
+    let timeout = Duration::from_secs(2);
+    let next_lifecycle_event = |manager: &mut ControllerManager<EmulatedSource>| loop {
+        match manager.next_event(timeout).unwrap() {
+            Some(ControllerEvent::Report { .. }) => continue,
+            event => return event,
+        }
+    };
+
+    source.plug("98:b6:e9:00:00:01", WhichController::LeftJoyCon);
+    source.plug("98:b6:e9:00:00:02", WhichController::RightJoyCon);
+    let mut ids = vec![];
+    for _ in 0..2 {
+        match next_lifecycle_event(&mut manager) {
+            Some(ControllerEvent::Connected { id, .. }) => ids.push(id),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    ids.sort();
+    assert_eq!(ids, [ControllerId(0), ControllerId(1)]);
+    match manager.next_event(timeout).unwrap() {
+        Some(ControllerEvent::Report { .. }) => {}
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    source.unplug("98:b6:e9:00:00:01");
+    let left = match next_lifecycle_event(&mut manager) {
+        Some(ControllerEvent::Disconnected { id }) => id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert!(manager.handle(left).is_none());
+
+    source.plug("98:b6:e9:00:00:01", WhichController::LeftJoyCon);
+    match next_lifecycle_event(&mut manager) {
+        Some(ControllerEvent::Connected { id, device_type }) => {
+            assert_eq!(id, left);
+            assert_eq!(device_type, WhichController::LeftJoyCon);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert_eq!(manager.connected().count(), 2);
+}
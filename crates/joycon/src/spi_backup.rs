@@ -0,0 +1,90 @@
+use crate::Result;
+use joycon_sys::input::MACAddress;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Directory of SPI flash backups, with a subdirectory per controller.
+///
+/// Each backup is the content of a region before a write, stored in a file
+/// named `<sequence>-<offset>.bin`.
+#[derive(Debug, Clone)]
+pub struct SPIBackup {
+    dir: PathBuf,
+}
+
+/// Content of a region before a write.
+#[derive(Debug, Clone)]
+pub struct SPIBackupEntry {
+    pub path: PathBuf,
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl SPIBackup {
+    pub fn new(dir: impl Into<PathBuf>) -> SPIBackup {
+        SPIBackup { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Saves the content of a region, after the previous backups.
+    pub fn save(&self, mac: MACAddress, offset: u32, data: &[u8]) -> Result<PathBuf> {
+        let dir = self.controller_dir(mac);
+        fs::create_dir_all(&dir)?;
+        let sequence = self
+            .entries(mac)?
+            .last()
+            .map(|(sequence, _, _)| sequence + 1)
+            .unwrap_or(0);
+        let path = dir.join(format!("{:08}-{:05x}.bin", sequence, offset));
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    /// Most recent backup of the controller.
+    pub fn last(&self, mac: MACAddress) -> Result<Option<SPIBackupEntry>> {
+        match self.entries(mac)?.pop() {
+            Some((_, offset, path)) => Ok(Some(SPIBackupEntry {
+                data: fs::read(&path)?,
+                path,
+                offset,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn controller_dir(&self, mac: MACAddress) -> PathBuf {
+        self.dir.join(hex::encode(mac.0))
+    }
+
+    /// Sequence number, offset and path of each backup, oldest first.
+    fn entries(&self, mac: MACAddress) -> Result<Vec<(u64, u32, PathBuf)>> {
+        let dir = self.controller_dir(mac);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let parsed = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| {
+                    let (sequence, offset) = stem.split_once('-')?;
+                    Some((
+                        sequence.parse().ok()?,
+                        u32::from_str_radix(offset, 16).ok()?,
+                    ))
+                });
+            if let Some((sequence, offset)) = parsed {
+                entries.push((sequence, offset, path));
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
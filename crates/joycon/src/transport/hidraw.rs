@@ -0,0 +1,82 @@
+use super::Transport;
+use crate::Result;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
+};
+
+/// `_IOR('H', 0x03, struct hidraw_devinfo)`
+const HIDIOCGRAWINFO: libc::c_ulong = 0x8008_4803;
+
+#[repr(C)]
+#[derive(Default)]
+struct HidrawDevinfo {
+    bustype: u32,
+    vendor: i16,
+    product: i16,
+}
+
+/// `/dev/hidraw*` device, used without hidapi.
+pub struct HidrawDevice {
+    file: File,
+}
+
+impl HidrawDevice {
+    pub fn open(path: impl AsRef<Path>) -> Result<HidrawDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(HidrawDevice { file })
+    }
+
+    /// Takes ownership of an already opened hidraw file descriptor, for
+    /// example one received from a privileged helper.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open hidraw file descriptor not owned by anything
+    /// else.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> HidrawDevice {
+        HidrawDevice {
+            file: File::from_raw_fd(fd),
+        }
+    }
+
+    /// `(vendor_id, product_id)` of the device.
+    pub fn ids(&self) -> Result<(u16, u16)> {
+        let mut info = HidrawDevinfo::default();
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), HIDIOCGRAWINFO, &mut info) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok((info.vendor as u16, info.product as u16))
+    }
+}
+
+impl Transport for HidrawDevice {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if ret == 0 {
+                return Ok(0);
+            }
+            return Ok(self.file.read(buf)?);
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(self.file.write(buf)?)
+    }
+}
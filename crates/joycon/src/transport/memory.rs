@@ -0,0 +1,93 @@
+use super::Transport;
+use crate::{Error, Result};
+use std::{
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::Duration,
+};
+
+/// Creates an in-memory transport and the controller side of it.
+///
+/// Useful to feed hand-crafted reports to `JoyCon` in tests.
+pub fn memory_pipe() -> (MemoryTransport, MemoryPeer) {
+    let (input_tx, input_rx) = channel();
+    let (output_tx, output_rx) = channel();
+    (
+        MemoryTransport {
+            input: input_rx,
+            output: output_tx,
+        },
+        MemoryPeer {
+            input: input_tx,
+            output: output_rx,
+        },
+    )
+}
+
+/// Host side of `memory_pipe`.
+pub struct MemoryTransport {
+    input: Receiver<Vec<u8>>,
+    output: Sender<Vec<u8>>,
+}
+
+/// Controller side of `memory_pipe`.
+pub struct MemoryPeer {
+    input: Sender<Vec<u8>>,
+    output: Receiver<Vec<u8>>,
+}
+
+impl MemoryPeer {
+    /// Queues an input report for the host.
+    pub fn send(&self, report: &[u8]) -> Result<()> {
+        if self.input.send(report.to_vec()).is_err() {
+            return Err(Error::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Next output report written by the host, `None` on timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        match self.output.recv_timeout(timeout) {
+            Ok(report) => Ok(Some(report)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        let report = if timeout < 0 {
+            self.input.recv().ok()
+        } else if timeout == 0 {
+            match self.input.try_recv() {
+                Ok(report) => Some(report),
+                Err(TryRecvError::Empty) => return Ok(0),
+                Err(TryRecvError::Disconnected) => None,
+            }
+        } else {
+            match self
+                .input
+                .recv_timeout(Duration::from_millis(timeout as u64))
+            {
+                Ok(report) => Some(report),
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => None,
+            }
+        };
+        match report {
+            Some(report) => {
+                let len = report.len().min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                Ok(len)
+            }
+            None => Err(Error::Disconnected),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.output.send(buf.to_vec()).is_err() {
+            return Err(Error::Disconnected);
+        }
+        Ok(buf.len())
+    }
+}
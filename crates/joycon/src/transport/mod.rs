@@ -0,0 +1,89 @@
+//! Raw I/O with the controller.
+//!
+//! `JoyCon` only needs to read and write HID reports, so it can run on top of
+//! anything implementing `Transport`: a real device through hidapi or hidraw,
+//! an in-memory pipe or a recorded trace.
+
+use crate::{Error, Result};
+use joycon_sys::{input::WhichController, JOYCON_L_BT, JOYCON_R_BT, PRO_CONTROLLER};
+
+#[cfg(target_os = "linux")]
+mod hidraw;
+mod memory;
+mod replay;
+
+#[cfg(target_os = "linux")]
+pub use hidraw::*;
+pub use memory::*;
+pub use replay::*;
+
+pub trait Transport: Send {
+    /// Reads an input report, including the report ID.
+    ///
+    /// Returns 0 if no report is received before `timeout` milliseconds. A
+    /// negative timeout blocks until a report is available.
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize>;
+
+    /// Writes an output report, including the report ID.
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read_timeout(buf, -1)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        (**self).read_timeout(buf, timeout)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+}
+
+impl Transport for hidapi::HidDevice {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        Ok(hidapi::HidDevice::read_timeout(self, buf, timeout)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(hidapi::HidDevice::write(self, buf)?)
+    }
+}
+
+#[cfg(any(test, feature = "emulator"))]
+impl Transport for joycon_emulator::Emulator {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        joycon_emulator::Emulator::read_timeout(self, buf, timeout)
+            .map_err(|e| Error::Emulator(e.to_string()))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        joycon_emulator::Emulator::write(self, buf).map_err(|e| Error::Emulator(e.to_string()))
+    }
+}
+
+/// Controller type from the USB product ID.
+pub fn controller_type(product_id: u16) -> Result<WhichController> {
+    Ok(match product_id {
+        JOYCON_L_BT => WhichController::LeftJoyCon,
+        JOYCON_R_BT => WhichController::RightJoyCon,
+        PRO_CONTROLLER => WhichController::ProController,
+        _ => return Err(Error::UnsupportedDevice(product_id)),
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn init_emulated() {
+    let device_type = WhichController::RightJoyCon;
+    let mut emulator = joycon_emulator::Emulator::new(device_type);
+    emulator.set_realtime(false);
+    let mut joycon = crate::JoyCon::new(emulator, device_type).unwrap();
+    joycon.load_calibration().unwrap();
+    joycon.enable_imu().unwrap();
+    let report = joycon.tick().unwrap();
+    assert!(report.right_stick.x.abs() < 1e-3);
+    assert!(report.imu.is_some());
+}
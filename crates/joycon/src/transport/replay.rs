@@ -0,0 +1,120 @@
+use super::Transport;
+use crate::{Error, Result};
+use joycon_sys::trace::{Direction, TraceReader};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+/// Offset of the subcommand data in an output report, after the ID, the
+/// packet counter and the rumble data.
+const OUTPUT_PAYLOAD_OFFSET: usize = 10;
+
+/// Replays the input reports of a trace recorded by `joytk relay`.
+///
+/// See `trace/README.md` for the format. Input reports are returned in order
+/// regardless of what is written. In strict mode, each written report must
+/// match the next recorded output report, ignoring the packet counter and
+/// the rumble data.
+pub struct TraceReplay {
+    inputs: std::vec::IntoIter<Vec<u8>>,
+    outputs: std::vec::IntoIter<Vec<u8>>,
+    strict: bool,
+}
+
+impl TraceReplay {
+    pub fn open(path: impl AsRef<Path>) -> Result<TraceReplay> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        TraceReplay::parse(BufReader::new(file))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<TraceReplay> {
+        TraceReplay::parse(reader)
+    }
+
+    fn parse(reader: impl BufRead) -> Result<TraceReplay> {
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        for entry in TraceReader::new(reader) {
+            let entry = entry?;
+            let report = entry.as_bytes().to_vec();
+            match entry.direction() {
+                Direction::Input => inputs.push(report),
+                Direction::Output => outputs.push(report),
+            }
+        }
+        Ok(TraceReplay {
+            inputs: inputs.into_iter(),
+            outputs: outputs.into_iter(),
+            strict: false,
+        })
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+}
+
+impl Transport for TraceReplay {
+    fn read_timeout(&mut self, buf: &mut [u8], _timeout: i32) -> Result<usize> {
+        match self.inputs.next() {
+            Some(report) => {
+                let len = report.len().min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                Ok(len)
+            }
+            None => Err(Error::Disconnected),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.strict {
+            let expected = match self.outputs.next() {
+                Some(report) => report,
+                None => return Err(Error::ReplayEnded),
+            };
+            let payload = |r: &[u8]| -> Vec<u8> {
+                let mut payload = r.get(OUTPUT_PAYLOAD_OFFSET..).unwrap_or(&[]).to_vec();
+                while payload.last() == Some(&0) {
+                    payload.pop();
+                }
+                payload
+            };
+            if buf.first() != expected.first() || payload(buf) != payload(&expected) {
+                return Err(Error::ReplayMismatch {
+                    expected,
+                    got: buf.to_vec(),
+                });
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn replay_trace() {
+    // The second report has no HID header, like in the first captures
+    let log = "> 0:00:01.000000 a13f0000\n> 0:00:02.000000 3f0100\n< 0:00:03 a2010a\n";
+    let mut replay = TraceReplay::from_reader(log.as_bytes()).unwrap();
+    let mut buf = [0; 10];
+    assert_eq!(replay.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf[..3], [0x3f, 0, 0]);
+    assert_eq!(replay.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf[..3], [0x3f, 1, 0]);
+    assert!(matches!(replay.read(&mut buf), Err(Error::Disconnected)));
+
+    replay.set_strict(true);
+    assert!(matches!(
+        replay.write(&[0x02]),
+        Err(Error::ReplayMismatch { .. })
+    ));
+    assert!(matches!(replay.write(&[0x01]), Err(Error::ReplayEnded)));
+
+    assert!(matches!(
+        TraceReplay::from_reader("> 0:00:01 é\n".as_bytes()),
+        Err(Error::Trace(_))
+    ));
+}
@@ -90,7 +90,7 @@ fn hid_main(
 ) -> anyhow::Result<bool> {
     let mut _mouse = mouse::Mouse::new();
 
-    let mut device = JoyCon::new(device, device_info.clone())?;
+    let mut device = JoyCon::from_hidapi(device, device_info)?;
     println!("new dev: {:?}", device.get_dev_info()?);
 
     println!("Calibrating...");
@@ -1,37 +1,47 @@
 use joycon::{
-    hidapi::{self, HidApi},
     joycon_sys::{
         input::BatteryLevel,
         light::{self, PlayerLight},
         output::RumbleData,
         output::RumbleSide,
-        NINTENDO_VENDOR_ID,
+        output::SubcommandRequestEnum,
     },
-    JoyCon,
+    ControllerEvent, ControllerManager, JoyConHandle,
 };
+use std::{collections::HashSet, time::Duration};
 
 fn main() -> anyhow::Result<()> {
-    let mut api = HidApi::new()?;
+    let mut manager = ControllerManager::with_hidapi()?;
+    // Connected controllers waiting for a report with their battery level.
+    let mut new_controllers = HashSet::new();
     loop {
-        api.refresh_devices()?;
-        if let Some(device_info) = api
-            .device_list()
-            .find(|x| x.vendor_id() == NINTENDO_VENDOR_ID)
-        {
-            let device = device_info.open_device(&api)?;
-            match hid_main(device, device_info) {
-                Ok(()) => std::thread::sleep(std::time::Duration::from_secs(2)),
-                Err(e) => println!("Joycon error: {}", e),
+        match manager.next_event(Duration::from_secs(1))? {
+            Some(ControllerEvent::Connected { id, .. }) => {
+                new_controllers.insert(id);
             }
-        } else {
-            std::thread::sleep(std::time::Duration::from_secs(1));
+            Some(ControllerEvent::Report { id, report }) if new_controllers.remove(&id) => {
+                let handle = manager
+                    .handle(id)
+                    .expect("the controller just sent a report")
+                    .clone();
+                let battery_level = report.info.battery_level();
+                std::thread::spawn(move || {
+                    if let Err(e) = hid_main(&handle, battery_level) {
+                        println!("Joycon {} error: {}", id, e);
+                    }
+                });
+            }
+            Some(ControllerEvent::Disconnected { id }) => {
+                new_controllers.remove(&id);
+            }
+            Some(ControllerEvent::Report { .. }) | None => {}
         }
     }
 }
 
-fn hid_main(device: hidapi::HidDevice, device_info: &hidapi::DeviceInfo) -> anyhow::Result<()> {
-    let mut device = JoyCon::new(device, device_info.clone())?;
-    println!("new dev: {:?}", device.get_dev_info()?);
+fn hid_main(device: &JoyConHandle, battery_level: BatteryLevel) -> anyhow::Result<()> {
+    let info = device.call_subcmd_wait(SubcommandRequestEnum::RequestDeviceInfo(()))?;
+    println!("new dev: {:?}", info.device_info());
 
     dbg!(device.set_home_light(light::HomeLight::new(
         0x8,
@@ -40,8 +50,6 @@ fn hid_main(device: hidapi::HidDevice, device_info: &hidapi::DeviceInfo) -> anyh
         &[(0xf, 0xf, 0), (0x2, 0xf, 0)],
     ))?);
 
-    let battery_level = device.tick()?.info.battery_level();
-
     device.set_player_light(light::PlayerLights::new(
         (battery_level >= BatteryLevel::Full).into(),
         (battery_level >= BatteryLevel::Medium).into(),
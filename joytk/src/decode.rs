@@ -0,0 +1,293 @@
+//! `joytk decode`
+
+use crate::opts::{Decode, DecodeFormat, TraceDirection};
+use anyhow::{Context, Result};
+use colored::Colorize;
+use joycon::{
+    joycon_sys::{
+        hci::{self, HciReader},
+        input::InputReportEnum,
+        mcu::{ir::Resolution, MCUReportId},
+        output::{OutputReportEnum, OutputReportId},
+        trace::{format_timestamp, Direction, TraceEntry, TraceFilter, TraceReader, TraceReport},
+        InputReportId, RawId, SubcommandId,
+    },
+    Image,
+};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    io::{self, BufRead},
+    time::Duration,
+};
+
+pub fn decode(opts: &Decode) -> Result<()> {
+    let filter = TraceFilter {
+        direction: opts.direction.map(|direction| match direction {
+            TraceDirection::Input => Direction::Input,
+            TraceDirection::Output => Direction::Output,
+        }),
+        input_reports: parse_ids(&opts.input_report, "input report", |id| {
+            RawId::<InputReportId>::new(id).try_into()
+        })?,
+        output_reports: parse_ids(&opts.output_report, "output report", |id| {
+            RawId::<OutputReportId>::new(id).try_into()
+        })?,
+        subcommands: parse_ids(&opts.subcommand, "subcommand", |id| {
+            RawId::<SubcommandId>::new(id).try_into()
+        })?,
+        mcu_reports: parse_ids(&opts.mcu_report, "MCU report", |id| {
+            RawId::<MCUReportId>::new(id).try_into()
+        })?,
+        since: opts.since,
+        until: opts.until,
+    };
+    if let Some(ref dir) = opts.images_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let entries: Box<dyn Iterator<Item = Result<TraceEntry>>> =
+        if hci::is_hci_capture(input.fill_buf()?) {
+            Box::new(HciReader::new(input)?.map(|e| e.map_err(Into::into)))
+        } else {
+            Box::new(TraceReader::new(input).map(|e| e.map_err(Into::into)))
+        };
+
+    let mut image = Image::new();
+    image.change_resolution(Resolution::R320x240);
+    let mut image_count = 0;
+    let mut summary = Summary::default();
+    for entry in entries {
+        let entry = entry?;
+        // The images are split across many reports, so they are rebuilt
+        // before filtering.
+        if let (Some(dir), Some(mcu)) = (
+            &opts.images_dir,
+            entry.input().and_then(|report| report.mcu_report()),
+        ) {
+            image.handle(mcu);
+            if let Some(img) = image.last_image.take() {
+                image_count += 1;
+                let path = dir.join(format!("{:05}.png", image_count));
+                img.save(&path)
+                    .with_context(|| format!("writing {}", path.display()))?;
+            }
+        }
+
+        if !filter.matches(&entry) {
+            continue;
+        }
+        match (opts.summary, opts.format) {
+            (true, _) => summary.add(&entry),
+            (false, DecodeFormat::Text) => print_entry(&entry),
+            (false, DecodeFormat::Json) => println!("{}", entry_json(&entry)),
+        }
+    }
+
+    match (opts.summary, opts.format) {
+        (true, DecodeFormat::Text) => summary.print(),
+        (true, DecodeFormat::Json) => println!("{}", summary.to_json()),
+        (false, _) => {}
+    }
+    Ok(())
+}
+
+fn parse_ids<Id>(ids: &[u8], kind: &str, parse: impl Fn(u8) -> Option<Id>) -> Result<Vec<Id>> {
+    ids.iter()
+        .map(|&id| parse(id).with_context(|| format!("unknown {} 0x{:02x}", kind, id)))
+        .collect()
+}
+
+/// Decoded subcommand or MCU report, the interesting parts of a trace.
+fn details(report: TraceReport) -> Option<String> {
+    match report {
+        TraceReport::Input(report) => match InputReportEnum::try_from(report) {
+            Ok(InputReportEnum::StandardAndSubcmd((_, subcmd))) => Some(format!("{:?}", subcmd)),
+            Ok(InputReportEnum::StandardFullMCU((_, _, mcu))) => Some(format!("{:?}", mcu)),
+            _ => None,
+        },
+        TraceReport::Output(report) => match OutputReportEnum::try_from(report) {
+            Ok(OutputReportEnum::RumbleAndSubcmd(subcmd)) => Some(format!("{:?}", subcmd)),
+            Ok(OutputReportEnum::RequestMCUData(mcu)) => Some(format!("{:?}", mcu)),
+            _ => None,
+        },
+    }
+}
+
+/// Prints at least the timestamp, the direction and the report ID, followed by
+/// the details if there are some.
+fn print_entry(entry: &TraceEntry) {
+    let (direction, report) = match entry.report {
+        TraceReport::Input(report) => (">", format!("{:?}", report.id())),
+        TraceReport::Output(report) => ("<", format!("{:?}", report.id())),
+    };
+    print!(
+        "{} {} {}",
+        format_timestamp(entry.timestamp).blue(),
+        direction,
+        report
+    );
+    let details = match details(entry.report) {
+        Some(details) => details,
+        None => {
+            println!();
+            return;
+        }
+    };
+    let details = match (entry.direction(), entry.subcommand_id()) {
+        (Direction::Input, Some(_)) => details.green(),
+        (Direction::Input, None) => details.normal(),
+        (Direction::Output, Some(_)) => details.red(),
+        (Direction::Output, None) => details.yellow(),
+    };
+    println!(" {}", details);
+}
+
+fn entry_json(entry: &TraceEntry) -> serde_json::Value {
+    let (direction, report) = match entry.report {
+        TraceReport::Input(report) => ("input", format!("{:?}", report.id())),
+        TraceReport::Output(report) => ("output", format!("{:?}", report.id())),
+    };
+    json!({
+        "timestamp": entry.timestamp.as_secs_f64(),
+        "direction": direction,
+        "report": report,
+        "subcommand": entry.subcommand_id().map(|id| format!("{:?}", id)),
+        "mcu_report": entry.mcu_report_id().map(|id| format!("{:?}", id)),
+        "details": details(entry.report),
+        "data": hex::encode(entry.as_bytes()),
+    })
+}
+
+#[derive(Default)]
+struct Summary {
+    input_reports: BTreeMap<String, usize>,
+    output_reports: BTreeMap<String, usize>,
+    subcommands: BTreeMap<String, SubcommandStats>,
+}
+
+#[derive(Default)]
+struct SubcommandStats {
+    requests: usize,
+    replies: usize,
+    /// First request without a reply yet, the next ones are retries.
+    pending: Option<Duration>,
+    /// Delays between the requests and their reply.
+    latencies: Vec<Duration>,
+}
+
+impl Summary {
+    fn add(&mut self, entry: &TraceEntry) {
+        match entry.report {
+            TraceReport::Input(report) => {
+                *self
+                    .input_reports
+                    .entry(format!("{:?}", report.id()))
+                    .or_default() += 1
+            }
+            TraceReport::Output(report) => {
+                *self
+                    .output_reports
+                    .entry(format!("{:?}", report.id()))
+                    .or_default() += 1
+            }
+        }
+
+        if let Some(id) = entry.subcommand_id() {
+            let stats = self.subcommands.entry(format!("{:?}", id)).or_default();
+            match entry.direction() {
+                Direction::Output => {
+                    stats.requests += 1;
+                    stats.pending.get_or_insert(entry.timestamp);
+                }
+                Direction::Input => {
+                    stats.replies += 1;
+                    if let Some(request) = stats.pending.take() {
+                        stats
+                            .latencies
+                            .push(entry.timestamp.checked_sub(request).unwrap_or_default());
+                    }
+                }
+            }
+        }
+    }
+
+    fn print(&self) {
+        for (title, counts) in &[
+            ("Input reports", &self.input_reports),
+            ("Output reports", &self.output_reports),
+        ] {
+            println!("{}", title.bold());
+            for (id, count) in counts.iter() {
+                println!("{:>8}  {}", count, id);
+            }
+        }
+
+        println!(
+            "{}",
+            format!(
+                "{:>8} {:>8} {:>8} {:>8} {:>8}  Subcommands",
+                "requests", "replies", "min ms", "mean ms", "max ms"
+            )
+            .bold()
+        );
+        for (id, stats) in &self.subcommands {
+            let latencies = match stats.latencies() {
+                Some((min, mean, max)) => format!("{:>8.1} {:>8.1} {:>8.1}", min, mean, max),
+                None => format!("{:>8} {:>8} {:>8}", "-", "-", "-"),
+            };
+            println!(
+                "{:>8} {:>8} {}  {}",
+                stats.requests, stats.replies, latencies, id
+            );
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let subcommands: BTreeMap<_, _> = self
+            .subcommands
+            .iter()
+            .map(|(id, stats)| {
+                let latency = stats
+                    .latencies()
+                    .map(|(min, mean, max)| json!({ "min": min, "mean": mean, "max": max }));
+                (
+                    id,
+                    json!({
+                        "requests": stats.requests,
+                        "replies": stats.replies,
+                        "latency_ms": latency,
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "input_reports": self.input_reports,
+            "output_reports": self.output_reports,
+            "subcommands": subcommands,
+        })
+    }
+}
+
+impl SubcommandStats {
+    /// Minimum, mean and maximum latencies in milliseconds.
+    fn latencies(&self) -> Option<(f64, f64, f64)> {
+        let ms: Vec<f64> = self
+            .latencies
+            .iter()
+            .map(|latency| latency.as_secs_f64() * 1000.)
+            .collect();
+        if ms.is_empty() {
+            return None;
+        }
+        Some((
+            ms.iter().cloned().fold(f64::INFINITY, f64::min),
+            ms.iter().sum::<f64>() / ms.len() as f64,
+            ms.iter().cloned().fold(0., f64::max),
+        ))
+    }
+}
@@ -0,0 +1,114 @@
+//! File format of `joytk dump`.
+//!
+//! The flash image is preceded by a header identifying the controller and
+//! the CRC32 of each region, to detect corrupted or mismatched dumps before
+//! writing anything back.
+
+use anyhow::{Context, Result};
+use joycon::joycon_sys::{
+    input::{FirmwareVersion, MACAddress, WhichController},
+    spi::SPI_FLASH_SIZE,
+    RawId,
+};
+use std::{
+    io::{Read, Write},
+    ops::Range,
+};
+
+const MAGIC: &[u8; 8] = b"JOYTKDMP";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 19;
+const REGION_HEADER_SIZE: usize = 12;
+
+/// Areas of the flash, each with its own checksum.
+pub const REGIONS: &[(&str, Range<u32>)] = &[
+    ("bootloader", 0x0..0x2000),
+    ("pairing", 0x2000..0x5000),
+    ("shipment", 0x5000..0x6000),
+    ("factory", 0x6000..0x8000),
+    ("user", 0x8000..0x10000),
+    ("firmware", 0x10000..SPI_FLASH_SIZE),
+];
+
+pub struct FlashDump {
+    pub device_type: WhichController,
+    pub mac_address: MACAddress,
+    pub firmware_version: FirmwareVersion,
+    pub flash: Vec<u8>,
+}
+
+impl FlashDump {
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION, self.device_type as u8])?;
+        out.write_all(&self.mac_address.0)?;
+        out.write_all(&self.firmware_version.0)?;
+        out.write_all(&[REGIONS.len() as u8])?;
+        for (_, range) in REGIONS {
+            out.write_all(&range.start.to_le_bytes())?;
+            out.write_all(&range.end.to_le_bytes())?;
+            out.write_all(&crc32fast::hash(self.region(range)).to_le_bytes())?;
+        }
+        out.write_all(&self.flash)?;
+        Ok(())
+    }
+
+    pub fn read(mut input: impl Read) -> Result<FlashDump> {
+        let mut header = [0; HEADER_SIZE];
+        input.read_exact(&mut header).context("truncated header")?;
+        if &header[..8] != MAGIC {
+            anyhow::bail!("not a joytk dump");
+        }
+        if header[8] != VERSION {
+            anyhow::bail!("unsupported dump version {}", header[8]);
+        }
+        let device_type = RawId::new(header[9])
+            .try_into()
+            .with_context(|| format!("unknown controller type {}", header[9]))?;
+        let mut mac_address = [0; 6];
+        mac_address.copy_from_slice(&header[10..16]);
+        let firmware_version = [header[16], header[17]];
+
+        let mut regions = vec![0; header[18] as usize * REGION_HEADER_SIZE];
+        input
+            .read_exact(&mut regions)
+            .context("truncated region list")?;
+        let mut flash = Vec::with_capacity(SPI_FLASH_SIZE as usize);
+        input.read_to_end(&mut flash)?;
+        if flash.len() != SPI_FLASH_SIZE as usize {
+            anyhow::bail!(
+                "wrong flash size 0x{:x}, expected 0x{:x}",
+                flash.len(),
+                SPI_FLASH_SIZE
+            );
+        }
+        let dump = FlashDump {
+            device_type,
+            mac_address: MACAddress(mac_address),
+            firmware_version: FirmwareVersion(firmware_version),
+            flash,
+        };
+
+        for region in regions.chunks(REGION_HEADER_SIZE) {
+            let word = |i: usize| {
+                u32::from_le_bytes([region[i], region[i + 1], region[i + 2], region[i + 3]])
+            };
+            let range = word(0)..word(4);
+            if range.start > range.end || range.end > SPI_FLASH_SIZE {
+                anyhow::bail!("invalid region 0x{:x}..0x{:x}", range.start, range.end);
+            }
+            if crc32fast::hash(dump.region(&range)) != word(8) {
+                anyhow::bail!(
+                    "checksum mismatch in region 0x{:x}..0x{:x}",
+                    range.start,
+                    range.end
+                );
+            }
+        }
+        Ok(dump)
+    }
+
+    pub fn region(&self, range: &Range<u32>) -> &[u8] {
+        &self.flash[range.start as usize..range.end as usize]
+    }
+}
@@ -1,39 +1,44 @@
 use anyhow::{Context, Result};
-use cgmath::{Deg, Euler, One, Quaternion, Vector3};
+use cgmath::{Deg, Euler, Vector3};
 use clap::Parser;
 use colored::Colorize;
 use joycon::{
-    hidapi::HidApi,
+    joycon_emulator::{Emulator, Flash},
     joycon_sys::{
         accessory::AccessoryCommand,
-        input::{BatteryLevel, InputReportEnum, Stick, UseSPIColors, WhichController},
+        input::{BatteryLevel, Stick, UseSPIColors, WhichController},
         light::{self, PlayerLight},
-        mcu::ir::Resolution,
-        output::OutputReportEnum,
         spi::{
-            ControllerColor, SPIRange, SensorCalibration, SticksCalibration, UserSensorCalibration,
-            UserSticksCalibration,
+            ControllerColor, FirstPairing, FlashImage, HorizontalOffsets, LeftStickCalibration,
+            LeftStickParameters, Pairing, PairingKeys, RightStickCalibration, RightStickParameters,
+            SPIReadResult, SPIWriteRequest, SecondPairing, SensorCalibration, SerialNumber,
+            Shipment, SticksCalibration, UserSensorCalibration, UserStickCalibration,
+            UserSticksCalibration, SPI, SPI_FLASH_SIZE, SPI_MAX_CHUNK,
         },
-        InputReport, OutputReport, HID_IDS, NINTENDO_VENDOR_ID,
     },
-    JoyCon,
+    DeviceSource, HidapiSource, JoyCon, SPIBackup,
 };
 use std::{
     convert::TryFrom,
-    fs::OpenOptions,
-    io::{BufRead, Write},
+    fmt::Debug,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
     time::Duration,
 };
 use std::{thread::sleep, time::Instant};
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 mod camera;
+mod decode;
+mod dump;
 #[cfg(feature = "interface")]
 mod interface;
 mod opts;
 #[cfg(target_os = "linux")]
 mod relay;
 
+use dump::FlashDump;
 use opts::*;
 
 fn main() -> Result<()> {
@@ -52,8 +57,11 @@ fn main() -> Result<()> {
 
     let opts = Opts::parse();
 
-    if let SubCommand::Decode = opts.subcmd {
-        return decode();
+    if let SubCommand::Decode(ref args) = opts.subcmd {
+        return decode::decode(args);
+    }
+    if let SubCommand::Inspect(ref args) = opts.subcmd {
+        return inspect(args);
     }
 
     #[cfg(feature = "interface")]
@@ -61,14 +69,24 @@ fn main() -> Result<()> {
         return interface::run();
     }
 
-    let api = HidApi::new()?;
+    if let Some(controller) = opts.emulate {
+        return emulate(controller, &opts);
+    }
+
+    let mut source = HidapiSource::new()?;
     loop {
-        if let Some(device_info) = api
-            .device_list()
-            .find(|x| x.vendor_id() == NINTENDO_VENDOR_ID && HID_IDS.contains(&x.product_id()))
-        {
-            let device = device_info
-                .open_device(&api)
+        let devices = source.scan()?;
+        if let Some(device_info) = devices.first() {
+            if devices.len() > 1 {
+                eprintln!(
+                    "{} controllers connected, using the {} {}",
+                    devices.len(),
+                    device_info.device_type,
+                    device_info.key()
+                );
+            }
+            let device = source
+                .open(device_info)
                 .with_context(|| format!("error opening the HID device {:?}", device_info))?;
 
             if let SubCommand::Relay(ref r) = opts.subcmd {
@@ -81,7 +99,7 @@ fn main() -> Result<()> {
                     anyhow::bail!("relaying only works on linux");
                 }
             } else {
-                let joycon = JoyCon::new(device, device_info.clone())?;
+                let joycon = JoyCon::new(device, device_info.device_type)?;
 
                 hid_main(joycon, &opts).context("error running the command")?;
             }
@@ -97,6 +115,23 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn emulate(controller: EmulatedController, opts: &Opts) -> Result<()> {
+    let device_type = match controller {
+        EmulatedController::Left => WhichController::LeftJoyCon,
+        EmulatedController::Right => WhichController::RightJoyCon,
+        EmulatedController::Pro => WhichController::ProController,
+    };
+    let flash = match opts.emulator_flash {
+        Some(ref path) => Flash::open(path, device_type)?,
+        None => Flash::factory(device_type),
+    };
+    if let SubCommand::Relay(_) = opts.subcmd {
+        anyhow::bail!("relaying needs a real controller");
+    }
+    let joycon = JoyCon::new(Emulator::with_flash(device_type, flash), device_type)?;
+    hid_main(joycon, opts).context("error running the command")
+}
+
 fn hid_main(mut joycon: JoyCon, opts: &Opts) -> Result<()> {
     joycon.set_home_light(light::HomeLight::new(
         0x8,
@@ -105,6 +140,10 @@ fn hid_main(mut joycon: JoyCon, opts: &Opts) -> Result<()> {
         &[(0xf, 0xf, 0), (0x2, 0xf, 0)],
     ))?;
 
+    if let Some(ref dir) = opts.backup_dir {
+        joycon.set_spi_backup(Some(SPIBackup::new(dir)));
+    }
+
     let battery_level = joycon.tick()?.info.battery_level();
 
     joycon.set_player_light(light::PlayerLights::new(
@@ -129,10 +168,11 @@ fn hid_main(mut joycon: JoyCon, opts: &Opts) -> Result<()> {
             SetE::Color(ref arg) => set_color(&mut joycon, arg)?,
         },
         SubCommand::Monitor => monitor(&mut joycon)?,
-        SubCommand::Dump => dump(&mut joycon)?,
-        SubCommand::Restore => restore(&mut joycon)?,
+        SubCommand::Dump(ref args) => dump(&mut joycon, args)?,
+        SubCommand::Restore(ref args) => restore(&mut joycon, args)?,
+        SubCommand::Undo => undo(&mut joycon, opts)?,
         SubCommand::Ringcon(ref cmd) => ringcon(&mut joycon, cmd)?,
-        SubCommand::Decode | SubCommand::Relay(_) => unreachable!(),
+        SubCommand::Decode(_) | SubCommand::Relay(_) | SubCommand::Inspect(_) => unreachable!(),
         SubCommand::PulseRate => pulse_rate(&mut joycon)?,
         #[cfg(feature = "interface")]
         SubCommand::Tui => unreachable!(),
@@ -162,37 +202,312 @@ fn pulse_rate(joycon: &mut JoyCon) -> Result<()> {
 
 fn reset_calibration(joycon: &mut JoyCon) -> Result<()> {
     joycon.write_spi(UserSensorCalibration::reset())?;
-    // TODO: reset sticks
+    let device_type = joycon.device_type();
+    if device_type != WhichController::RightJoyCon {
+        write_verified(
+            joycon,
+            UserStickCalibration::<LeftStickCalibration>::reset(),
+        )?;
+    }
+    if device_type != WhichController::LeftJoyCon {
+        write_verified(
+            joycon,
+            UserStickCalibration::<RightStickCalibration>::reset(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `value` to the SPI flash and checks it by reading it back.
+fn write_verified<S>(joycon: &mut JoyCon, value: S) -> Result<()>
+where
+    S: SPI + Into<SPIWriteRequest> + TryFrom<SPIReadResult> + Copy + Debug + PartialEq,
+{
+    if !joycon.write_spi(value)? {
+        anyhow::bail!("writing {:?} failed", value);
+    }
+    let written: S = joycon.read_spi()?;
+    if written != value {
+        anyhow::bail!(
+            "verification of {:?} failed, read back {:?}",
+            value,
+            written
+        );
+    }
+    Ok(())
+}
+
+fn dump(joycon: &mut JoyCon, args: &Dump) -> Result<()> {
+    let dev_info = joycon.get_dev_info()?;
+    let flash =
+        joycon.read_spi_region_with_progress(0, SPI_FLASH_SIZE as usize, print_progress())?;
+    let dump = FlashDump {
+        device_type: dev_info
+            .which_controller
+            .try_into()
+            .context("unknown controller type")?,
+        mac_address: dev_info.mac_address,
+        firmware_version: dev_info.firmware_version,
+        flash,
+    };
+    let mut out = BufWriter::new(
+        File::create(&args.output)
+            .with_context(|| format!("error creating {}", args.output.display()))?,
+    );
+    dump.write(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn restore(joycon: &mut JoyCon, args: &Restore) -> Result<()> {
+    let input = File::open(&args.input)
+        .with_context(|| format!("error opening {}", args.input.display()))?;
+    let dump = FlashDump::read(BufReader::new(input))
+        .with_context(|| format!("invalid dump {}", args.input.display()))?;
+
+    let dev_info = joycon.get_dev_info()?;
+    if dev_info.which_controller != dump.device_type {
+        anyhow::bail!(
+            "the dump is for a {}, not a {}",
+            dump.device_type,
+            dev_info.which_controller
+        );
+    }
+    let same_controller = dev_info.mac_address.0 == dump.mac_address.0;
+    let same_firmware = dev_info.firmware_version.0 == dump.firmware_version.0;
+    let ranges: Vec<_> = if args.unsafe_factory || args.unsafe_firmware {
+        if !same_controller {
+            anyhow::bail!(
+                "the dump comes from another controller ({}), refusing to restore its factory data",
+                dump.mac_address
+            );
+        }
+        if !same_firmware {
+            anyhow::bail!(
+                "the dump was made with firmware version {}, not {}, refusing to restore its factory data",
+                dump.firmware_version,
+                dev_info.firmware_version
+            );
+        }
+        dump::REGIONS
+            .iter()
+            .filter(|(name, _)| args.unsafe_firmware || !["bootloader", "firmware"].contains(name))
+            .map(|(_, range)| range.clone())
+            .collect()
+    } else {
+        if !same_controller {
+            println!(
+                "The dump comes from another controller ({})",
+                dump.mac_address
+            );
+        }
+        if !same_firmware {
+            println!(
+                "The dump was made with firmware version {}, the controller has {}",
+                dump.firmware_version, dev_info.firmware_version
+            );
+        }
+        let mut ranges = vec![
+            UserSticksCalibration::range(),
+            UserSensorCalibration::range(),
+        ];
+        if args.allow_factory_writes {
+            ranges.push(ControllerColor::range());
+        } else {
+            println!("Not restoring the colors, they need --allow-factory-writes");
+        }
+        ranges
+            .iter()
+            .map(|range| range.offset()..range.offset() + range.size() as u32)
+            .collect()
+    };
+
+    joycon.allow_factory_writes(
+        args.allow_factory_writes || args.unsafe_factory || args.unsafe_firmware,
+    );
+    let chunk_size = SPI_MAX_CHUNK as usize;
+    let mut written = 0;
+    for range in &ranges {
+        let expected = dump.region(range);
+        let current =
+            joycon.read_spi_region_with_progress(range.start, expected.len(), print_progress())?;
+        for (i, (old, new)) in current
+            .chunks(chunk_size)
+            .zip(expected.chunks(chunk_size))
+            .enumerate()
+        {
+            if old != new {
+                joycon.write_spi_region(range.start + (i * chunk_size) as u32, new)?;
+                written += 1;
+            }
+        }
+    }
+
+    println!("{} chunks written, verifying...", written);
+    for range in &ranges {
+        let expected = dump.region(range);
+        let current =
+            joycon.read_spi_region_with_progress(range.start, expected.len(), print_progress())?;
+        if current != expected {
+            anyhow::bail!(
+                "verification of 0x{:x}..0x{:x} failed",
+                range.start,
+                range.end
+            );
+        }
+    }
+    println!("Restore done");
+    Ok(())
+}
+
+fn inspect(args: &Inspect) -> Result<()> {
+    let data = load_flash(&args.dump)?;
+    let image = FlashImage::new(&data).unwrap();
+    let other_data = match args.diff {
+        Some(ref path) => load_flash(path)?,
+        None => {
+            for (name, value) in flash_fields(image) {
+                println!("{}: {}", name, value);
+            }
+            for issue in image.issues() {
+                println!("{}", issue.to_string().red());
+            }
+            return Ok(());
+        }
+    };
+    let other = FlashImage::new(&other_data).unwrap();
+
+    for ((name, a), (_, b)) in flash_fields(image).into_iter().zip(flash_fields(other)) {
+        if a != b {
+            println!("{}:", name);
+            println!("  {}", a.red());
+            println!("  {}", b.green());
+        }
+    }
+    for issue in image.issues() {
+        println!("{}: {}", args.dump.display(), issue.to_string().red());
+    }
+    for issue in other.issues() {
+        println!(
+            "{}: {}",
+            args.diff.as_ref().unwrap().display(),
+            issue.to_string().red()
+        );
+    }
+    let changed = data.iter().zip(&other_data).filter(|(a, b)| a != b).count();
+    println!("{} bytes differ in total", changed);
+    Ok(())
+}
+
+/// Reads a flash image, either raw or from the `dump` subcommand.
+fn load_flash(path: &Path) -> Result<Vec<u8>> {
+    let data = std::fs::read(path).with_context(|| format!("error reading {}", path.display()))?;
+    if data.len() == SPI_FLASH_SIZE as usize {
+        return Ok(data);
+    }
+    let dump =
+        FlashDump::read(&data[..]).with_context(|| format!("invalid dump {}", path.display()))?;
+    println!(
+        "{}: {}, MAC {}, firmware version {}",
+        path.display(),
+        dump.device_type,
+        dump.mac_address,
+        dump.firmware_version
+    );
+    Ok(dump.flash)
+}
+
+/// Human-readable content of a flash image.
+fn flash_fields(image: FlashImage) -> Vec<(&'static str, String)> {
+    let pairing = [
+        (
+            image.read::<Pairing<FirstPairing>>().is_paired(),
+            image.read::<PairingKeys<FirstPairing>>().host_address(),
+        ),
+        (
+            image.read::<Pairing<SecondPairing>>().is_paired(),
+            image.read::<PairingKeys<SecondPairing>>().host_address(),
+        ),
+    ]
+    .iter()
+    .filter(|(paired, _)| *paired)
+    .map(|(_, host)| host.to_string())
+    .collect::<Vec<_>>();
+    let pairing = if pairing.is_empty() {
+        "none".to_string()
+    } else {
+        pairing.join(", ")
+    };
+    vec![
+        (
+            "serial number",
+            format!("{:?}", image.read::<SerialNumber>()),
+        ),
+        ("shipment", image.read::<Shipment>().enabled().to_string()),
+        ("paired with", pairing),
+        ("colors", format!("{:?}", image.read::<ControllerColor>())),
+        (
+            "factory sensors",
+            format!("{:?}", image.read::<SensorCalibration>()),
+        ),
+        (
+            "factory sticks",
+            format!("{:?}", image.read::<SticksCalibration>()),
+        ),
+        (
+            "left stick parameters",
+            format!("{:?}", image.read::<LeftStickParameters>().0),
+        ),
+        (
+            "right stick parameters",
+            format!("{:?}", image.read::<RightStickParameters>().0),
+        ),
+        (
+            "horizontal offsets",
+            format!("{:?}", image.read::<HorizontalOffsets>()),
+        ),
+        (
+            "user sensors",
+            format!("{:?}", image.read::<UserSensorCalibration>().calib()),
+        ),
+        (
+            "user left stick",
+            format!(
+                "{:?}",
+                image.read::<UserStickCalibration<LeftStickCalibration>>()
+            ),
+        ),
+        (
+            "user right stick",
+            format!(
+                "{:?}",
+                image.read::<UserStickCalibration<RightStickCalibration>>()
+            ),
+        ),
+    ]
+}
+
+fn undo(joycon: &mut JoyCon, opts: &Opts) -> Result<()> {
+    if opts.backup_dir.is_none() {
+        anyhow::bail!("undo needs the backup directory, see --backup-dir");
+    }
+    match joycon.undo_spi_write()? {
+        Some(range) => println!("Restored 0x{:x}..0x{:x}", range.start, range.end),
+        None => println!("No backup left"),
+    }
     Ok(())
 }
 
-fn dump(joycon: &mut JoyCon) -> Result<()> {
-    let mut out = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open("/tmp/out.raw")?;
-    let mut offset = 0;
-    let size = 0x1D;
-    // TODO: why value
+/// Progress callback for long SPI accesses, printing every percent.
+fn print_progress() -> impl FnMut(usize, usize) {
     let mut last_percent = 0;
-    while offset + (size as u32) < 0x80000 {
-        let percent = offset * 100 / 0x80000;
+    move |done, total| {
+        let percent = done * 100 / total;
         if last_percent != percent {
             println!("{}%", percent);
             last_percent = percent;
         }
-        let slice = joycon.read_spi_raw(unsafe { SPIRange::new(offset, size) })?;
-        out.write(&slice)?;
-        offset += size as u32;
     }
-    let last_size = (0x80000 - offset) as u8;
-    let slice = joycon.read_spi_raw(unsafe { SPIRange::new(offset, last_size) })?;
-    out.write(&slice)?;
-    Ok(())
-}
-
-fn restore(_joycon: &mut JoyCon) -> Result<()> {
-    unimplemented!()
 }
 
 fn calibrate_gyro(joycon: &mut JoyCon) -> Result<()> {
@@ -273,9 +588,26 @@ fn calibrate_sticks(joycon: &mut JoyCon) -> Result<()> {
         r_y_max = r_y_max.max(right_stick.y());
     }
 
-    dbg!((l_x_min, left_neutral.x(), l_x_max));
-    dbg!((l_y_min, left_neutral.y(), l_y_max));
-    // TODO: write calibration
+    let device_type = joycon.device_type();
+    if device_type != WhichController::RightJoyCon {
+        let calib = LeftStickCalibration::new(
+            (l_x_min, l_y_min),
+            (left_neutral.x(), left_neutral.y()),
+            (l_x_max, l_y_max),
+        );
+        println!("Writing left stick calibration {:x?}", calib);
+        write_verified(joycon, UserStickCalibration::from(calib))?;
+    }
+    if device_type != WhichController::LeftJoyCon {
+        let calib = RightStickCalibration::new(
+            (r_x_min, r_y_min),
+            (right_neutral.x(), right_neutral.y()),
+            (r_x_max, r_y_max),
+        );
+        println!("Writing right stick calibration {:x?}", calib);
+        write_verified(joycon, UserStickCalibration::from(calib))?;
+    }
+    joycon.load_calibration()?;
 
     Ok(())
 }
@@ -292,6 +624,20 @@ fn get(joycon: &mut JoyCon) -> Result<()> {
         "{}, MAC {}, firmware version {}",
         dev_info.which_controller, dev_info.mac_address, dev_info.firmware_version
     );
+    let serial: SerialNumber = joycon.read_spi()?;
+    println!("Serial number: {}", serial.as_str().unwrap_or("none"));
+    let pairing: Pairing<FirstPairing> = joycon.read_spi()?;
+    if pairing.is_paired() {
+        let keys: PairingKeys<FirstPairing> = joycon.read_spi()?;
+        println!("Paired with {}", keys.host_address());
+        let pairing: Pairing<SecondPairing> = joycon.read_spi()?;
+        if pairing.is_paired() {
+            let keys: PairingKeys<SecondPairing> = joycon.read_spi()?;
+            println!("Also paired with {}", keys.host_address());
+        }
+    } else {
+        println!("Not paired");
+    }
     println!();
 
     println!("Controller color:");
@@ -383,8 +729,14 @@ fn get(joycon: &mut JoyCon) -> Result<()> {
 }
 
 fn set_color(joycon: &mut JoyCon, arg: &SetColor) -> Result<()> {
+    if !arg.allow_factory_writes {
+        anyhow::bail!(
+            "the colors are in the factory area, writing them needs --allow-factory-writes"
+        );
+    }
     let dev_info = joycon.get_dev_info()?;
     let is_pro_controller = dev_info.which_controller == WhichController::ProController;
+    joycon.allow_factory_writes(true);
 
     let mut colors = ControllerColor {
         body: arg.body.parse()?,
@@ -411,27 +763,16 @@ fn set_color(joycon: &mut JoyCon, arg: &SetColor) -> Result<()> {
 fn monitor(joycon: &mut JoyCon) -> Result<()> {
     joycon.enable_imu()?;
     joycon.load_calibration()?;
-    let mut orientation = Quaternion::one();
     let mut now = Instant::now();
     loop {
         let report = joycon.tick()?;
-        let mut last_acc = Vector3::unit_x();
-        let mut last_rot = Vector3::unit_x();
-        for frame in &report.imu.unwrap() {
-            orientation = orientation
-                * Quaternion::from(Euler::new(
-                    Deg(frame.gyro.y * 0.005),
-                    Deg(frame.gyro.z * 0.005),
-                    Deg(frame.gyro.x * 0.005),
-                ));
-            last_acc = frame.accel;
-            last_rot = frame.gyro;
-        }
+        // Newest first
+        let last = report.imu.unwrap()[0];
         if now.elapsed() > Duration::from_millis(100) {
             now = Instant::now();
             println!("Clicked: {}", report.buttons);
 
-            let euler_rot = Euler::from(orientation);
+            let euler_rot = Euler::from(last.orientation);
             let pitch = Deg::from(euler_rot.x);
             let yaw = Deg::from(euler_rot.y);
             let roll = Deg::from(euler_rot.z);
@@ -439,59 +780,11 @@ fn monitor(joycon: &mut JoyCon) -> Result<()> {
                 "Rotation: pitch {:?}, yaw {:?}, roll {:?}",
                 pitch, yaw, roll
             );
-            println!("Rotation speed: {:#?}", last_rot);
-            println!("Acceleration: {:?}", last_acc);
-        }
-    }
-}
-
-fn decode() -> anyhow::Result<()> {
-    let stdin = std::io::stdin();
-    let mut image = joycon::Image::new();
-    image.change_resolution(Resolution::R320x240);
-    for line in stdin.lock().lines() {
-        let line = line?;
-        let fragments: Vec<&str> = line.split(" ").collect();
-        let side = fragments[0];
-        let time = fragments[1];
-        let hex = hex::decode(&fragments[2][2..])?;
-        if side == ">" {
-            let mut report = InputReport::new();
-            let raw_report = report.as_bytes_mut();
-            let len = raw_report.len().min(hex.len());
-            raw_report[..len].copy_from_slice(&hex[..len]);
-            match InputReportEnum::try_from(report) {
-                Ok(InputReportEnum::StandardAndSubcmd((_, subcmd))) => {
-                    println!("{} {}", time.blue(), format!("{:?}", subcmd).green());
-                }
-                Ok(InputReportEnum::StandardFullMCU((_, _, mcu))) => {
-                    println!("{} {:?}", time.blue(), mcu);
-                    image.handle(&mcu);
-                    if let Some(img) = image.last_image.take() {
-                        img.save("/tmp/out.png")?;
-                        dbg!("new image");
-                    }
-                }
-                _ => {}
-            }
-        } else {
-            let mut report = OutputReport::new();
-            let raw_report = report.as_bytes_mut();
-            let len = raw_report.len().min(hex.len());
-            raw_report[..len].copy_from_slice(&hex[..len]);
-            match OutputReportEnum::try_from(report) {
-                Ok(OutputReportEnum::RumbleAndSubcmd(subcmd)) => {
-                    println!("{} {}", time.blue(), format!("{:?}", subcmd).red());
-                }
-                Ok(OutputReportEnum::RequestMCUData(mcu)) => {
-                    println!("{} {}", time.blue(), format!("{:?}", mcu).yellow());
-                }
-                _ => {}
-            }
+            println!("Rotation speed: {:#?}", last.gyro);
+            println!("Acceleration: {:?}", last.accel);
+            println!("Linear acceleration: {:?}", last.linear_accel);
         }
     }
-
-    Ok(())
 }
 
 fn ringcon(joycon: &mut JoyCon, cmd: &Ringcon) -> anyhow::Result<()> {
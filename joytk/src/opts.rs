@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
+use joycon::joycon_sys::trace::parse_timestamp;
 
 /// Access every feature of the Nintendo Switch controllers
 ///
@@ -22,6 +23,24 @@ pub struct Opts {
     /// Wait for a controller to connect
     #[clap(short, long)]
     pub wait: bool,
+    /// Run against a virtual controller instead of a real one
+    #[clap(long, arg_enum)]
+    pub emulate: Option<EmulatedController>,
+    /// Flash image used by the virtual controller, created if missing
+    #[clap(long, requires = "emulate")]
+    pub emulator_flash: Option<PathBuf>,
+    /// Save the memory of the controller to this directory before each write
+    ///
+    /// See the `undo` subcommand to restore the last backup.
+    #[clap(long)]
+    pub backup_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+pub enum EmulatedController {
+    Left,
+    Right,
+    Pro,
 }
 
 #[derive(Parser)]
@@ -40,17 +59,27 @@ pub enum SubCommand {
     #[cfg(feature = "interface")]
     Tui,
     /// Dump the memory of the controller to a binary file
-    Dump,
+    Dump(Dump),
     /// Restore the memory of the controller from a dump file
-    Restore,
+    ///
+    /// Only the chunks that differ from the dump are written, and everything
+    /// is read back afterwards.
+    Restore(Restore),
+    /// Undo the last write to the memory of the controller
+    ///
+    /// Needs the backups from `--backup-dir`.
+    Undo,
+    /// Show the content of a dump without a controller
+    Inspect(Inspect),
     /// Decode raw reports exchanged between the controller and the Switch
     ///
     /// See the `relay` subcommand to record new traces.
     ///
     /// See the `trace/` folder for recorded dumps, and
     /// [relay_joycon.py](https://github.com/Yamakaky/joycontrol/blob/capture-text-file/scripts/relay_joycon.py)
-    /// for capturing new dumps.
-    Decode,
+    /// for capturing new dumps. Bluetooth captures in the btsnoop or pcap
+    /// format, for example from Android or Wireshark, can also be decoded.
+    Decode(Decode),
     /// Relay the bluetooth trafic between a controller and the Switch
     ///
     /// Important commands are decoded and shown, and a full log can be recorded.
@@ -101,6 +130,94 @@ pub struct SetColor {
     pub left_grip: Option<String>,
     /// Color of the right grip (Pro Controller only)
     pub right_grip: Option<String>,
+    /// Confirm writing to the factory area of the flash, where the colors are
+    #[clap(long)]
+    pub allow_factory_writes: bool,
+}
+
+#[derive(Parser)]
+pub struct Dump {
+    /// Location of the dump to write
+    pub output: PathBuf,
+}
+
+#[derive(Parser)]
+pub struct Restore {
+    /// Location of the dump to read
+    pub input: PathBuf,
+    /// Also restore the colors, which are in the factory area of the flash
+    #[clap(long)]
+    pub allow_factory_writes: bool,
+    /// Also restore the factory data, pairing and shipment flag
+    ///
+    /// By default, only the user calibration is restored, and the colors with
+    /// --allow-factory-writes. This is dangerous and can brick the controller.
+    #[clap(long)]
+    pub unsafe_factory: bool,
+    /// Also restore the bootloader and the firmware, implies --unsafe-factory
+    ///
+    /// This is even more dangerous than --unsafe-factory.
+    #[clap(long)]
+    pub unsafe_firmware: bool,
+}
+
+#[derive(Parser)]
+pub struct Inspect {
+    /// Dump from the `dump` subcommand, or raw flash image
+    pub dump: PathBuf,
+    /// Only show the differences with another dump
+    #[clap(long)]
+    pub diff: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct Decode {
+    #[clap(long, arg_enum, default_value = "text")]
+    pub format: DecodeFormat,
+    /// Only show the reports in this direction
+    #[clap(long, arg_enum)]
+    pub direction: Option<TraceDirection>,
+    /// Only show the input reports with these IDs, in hex
+    #[clap(long, parse(try_from_str = parse_hex), use_delimiter = true)]
+    pub input_report: Vec<u8>,
+    /// Only show the output reports with these IDs, in hex
+    #[clap(long, parse(try_from_str = parse_hex), use_delimiter = true)]
+    pub output_report: Vec<u8>,
+    /// Only show the requests and replies of these subcommand IDs, in hex
+    #[clap(long, parse(try_from_str = parse_hex), use_delimiter = true)]
+    pub subcommand: Vec<u8>,
+    /// Only show the MCU reports with these IDs, in hex
+    #[clap(long, parse(try_from_str = parse_hex), use_delimiter = true)]
+    pub mcu_report: Vec<u8>,
+    /// Only show the reports from this time, in seconds or `h:mm:ss.micros`
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub since: Option<Duration>,
+    /// Only show the reports before this time, in seconds or `h:mm:ss.micros`
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub until: Option<Duration>,
+    /// Save each image of the infrared camera as a PNG file in this directory
+    #[clap(long)]
+    pub images_dir: Option<PathBuf>,
+    /// Count the reports of each type and the delay of the subcommand replies,
+    /// instead of showing each report
+    #[clap(long)]
+    pub summary: bool,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+pub enum DecodeFormat {
+    /// Colored, only the subcommands and MCU reports
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+pub enum TraceDirection {
+    /// From the controller
+    Input,
+    /// To the controller
+    Output,
 }
 
 #[derive(Parser)]
@@ -132,6 +249,14 @@ pub struct Relay {
     pub verbose: bool,
 }
 
+fn parse_hex(input: &str) -> Result<u8, std::num::ParseIntError> {
+    u8::from_str_radix(input.trim_start_matches("0x"), 16)
+}
+
+fn parse_time(input: &str) -> Result<Duration, String> {
+    parse_timestamp(input).ok_or_else(|| format!("invalid time {}", input))
+}
+
 fn is_mac(input: &str) -> Result<(), String> {
     let mut i = 0;
     for x in input.split(":").map(|x| u8::from_str_radix(x, 16)) {
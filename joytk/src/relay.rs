@@ -1,10 +1,14 @@
 use anyhow::Context;
 use bluetooth_sys::*;
 use joycon::{
-    hidapi::HidDevice,
     joycon_sys::{
-        output::SubcommandRequestEnum, InputReport, InputReportId::StandardFull, OutputReport,
+        output::SubcommandRequestEnum,
+        trace::{Direction, TraceEntry, TraceWriter},
+        InputReport,
+        InputReportId::StandardFull,
+        OutputReport,
     },
+    transport::Transport,
 };
 use socket2::{SockAddr, Socket};
 use std::{
@@ -12,7 +16,6 @@ use std::{
     ffi::CString,
     fs::OpenOptions,
     intrinsics::transmute,
-    io::Write,
     mem::{size_of_val, zeroed, MaybeUninit},
     thread::sleep,
     time::{Duration, Instant},
@@ -20,7 +23,7 @@ use std::{
 
 use crate::opts::Relay;
 
-pub fn relay(device: HidDevice, opts: &Relay) -> anyhow::Result<()> {
+pub fn relay(mut device: Box<dyn Transport>, opts: &Relay) -> anyhow::Result<()> {
     let mut output = opts
         .output
         .as_ref()
@@ -31,6 +34,7 @@ pub fn relay(device: HidDevice, opts: &Relay) -> anyhow::Result<()> {
                 .truncate(true)
                 .open(path)
                 .context("opening the log file")
+                .map(TraceWriter::new)
         })
         .transpose()?;
     let (mut _client_ctl, mut client_itr) = connect_switch(&opts.address)?;
@@ -65,7 +69,11 @@ pub fn relay(device: HidDevice, opts: &Relay) -> anyhow::Result<()> {
                 }
 
                 if let Some(ref mut out) = output {
-                    writeln!(out, "> {:0>9.4} {}", elapsed, hex::encode(&buf[1..len + 1]))?;
+                    out.write(&TraceEntry::from_bytes(
+                        start.elapsed(),
+                        Direction::Input,
+                        &buf[1..len + 1],
+                    )?)?;
                 }
 
                 if let Err(e) = client_itr.send(&buf[..len + 1]) {
@@ -104,7 +112,11 @@ pub fn relay(device: HidDevice, opts: &Relay) -> anyhow::Result<()> {
                     }
 
                     if let Some(ref mut out) = output {
-                        writeln!(out, "< {:0>9.4} {}", elapsed, hex::encode(&buf[1..len + 1]))?;
+                        out.write(&TraceEntry::from_bytes(
+                            start.elapsed(),
+                            Direction::Output,
+                            &buf[1..len],
+                        )?)?;
                     }
 
                     device.write(&buf[1..len]).context("joycon send")?;
@@ -1,3 +1,17 @@
+//! Wireshark dissector for the HID reports of the Nintendo Switch controllers
+//!
+//! The reports are decoded by `joycon-sys`, so the dissector always agrees
+//! with the library. Copy the built library to the plugin folder of
+//! Wireshark, for example `~/.local/lib/wireshark/plugins/3.6/epan/`, then:
+//!
+//! ```text
+//! tshark -r capture.pcap -Y 'joycon.subcmd == 0x10' -O joycon
+//! ```
+//!
+//! The decoding itself is `joycon_sys::dissect`, tested with the workspace.
+//! This crate only maps its fields to the Wireshark API, and has only been
+//! run against stubbed `epan` functions, not a real Wireshark or tshark.
+
 #![allow(
     non_upper_case_globals,
     non_camel_case_types,
@@ -5,14 +19,24 @@
     improper_ctypes
 )]
 
+use joycon_sys::{
+    accessory::{AccessoryCommandId, AccessoryType, RingconItemId},
+    dissect::{self, Field, Item, ItemKind},
+    hci::HID_INTERRUPT_PSM,
+    mcu::{ir::IRRequestId, MCUCommandId, MCUMode, MCUReportId, MCURequestId, MCUSubCommandId},
+    output::OutputReportId,
+    InputReportId, RawId, SubcommandId,
+};
 use std::{
     convert::TryInto,
     ffi::{c_void, CString},
-    os::raw::{c_int, c_uint},
+    fmt::Debug,
+    os::raw::{c_char, c_int, c_uint},
+    ptr::{self, addr_of_mut},
 };
 
 #[no_mangle]
-pub static plugin_version: &[u8; 6] = b"0.0.0\0";
+pub static plugin_version: &[u8; 6] = b"0.1.0\0";
 #[no_mangle]
 pub static plugin_want_major: c_uint = WIRESHARK_VERSION_MAJOR;
 #[no_mangle]
@@ -20,47 +44,341 @@ pub static plugin_want_minor: c_uint = WIRESHARK_VERSION_MINOR;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
+/// Kept by Wireshark, so it must live forever.
+const PROTOCOL_NAME: &[u8] = b"JoyCon\0";
+
 static mut proto: c_int = -1;
+static mut ett_joycon: c_int = -1;
+static mut ett_details: c_int = -1;
+
+/// IDs of the header fields, set by Wireshark on registration.
+struct Fields {
+    input_report: c_int,
+    output_report: c_int,
+    subcmd: c_int,
+    subcmd_ack: c_int,
+    spi_offset: c_int,
+    spi_size: c_int,
+    spi_data: c_int,
+    spi_status: c_int,
+    mcu_request: c_int,
+    mcu_report: c_int,
+    mcu_command: c_int,
+    mcu_subcmd: c_int,
+    mcu_mode: c_int,
+    ir_request: c_int,
+    accessory_command: c_int,
+    accessory_type: c_int,
+    accessory_item: c_int,
+    details: c_int,
+}
+
+static mut fields: Fields = Fields {
+    input_report: -1,
+    output_report: -1,
+    subcmd: -1,
+    subcmd_ack: -1,
+    spi_offset: -1,
+    spi_size: -1,
+    spi_data: -1,
+    spi_status: -1,
+    mcu_request: -1,
+    mcu_report: -1,
+    mcu_command: -1,
+    mcu_subcmd: -1,
+    mcu_mode: -1,
+    ir_request: -1,
+    accessory_command: -1,
+    accessory_type: -1,
+    accessory_item: -1,
+    details: -1,
+};
+
+/// String kept until the end of the program, for the registration tables.
+fn leak_str(s: &str) -> *const c_char {
+    CString::new(s).unwrap().into_raw()
+}
+
+/// Names of the values of an ID from `joycon-sys`.
+fn value_strings<Id: Debug>(parse: impl Fn(u8) -> Option<Id>) -> *const c_void {
+    let mut strings: Vec<value_string> = (0..=u8::MAX)
+        .filter_map(|raw| {
+            parse(raw).map(|id| value_string {
+                value: raw.into(),
+                strptr: leak_str(&format!("{:?}", id)),
+            })
+        })
+        .collect();
+    strings.push(value_string {
+        value: 0,
+        strptr: ptr::null(),
+    });
+    Box::leak(strings.into_boxed_slice()).as_ptr() as *const c_void
+}
+
+fn field(
+    id: *mut c_int,
+    name: &str,
+    abbrev: &str,
+    type_: ftenum,
+    display: field_display_e,
+    strings: *const c_void,
+) -> hf_register_info {
+    // Same as HFILL for the other fields.
+    let mut hfinfo: header_field_info = unsafe { std::mem::zeroed() };
+    hfinfo.name = leak_str(name);
+    hfinfo.abbrev = leak_str(abbrev);
+    hfinfo.type_ = type_;
+    hfinfo.display = display as c_int;
+    hfinfo.strings = strings;
+    hfinfo.id = -1;
+    hfinfo.same_name_prev_id = -1;
+    hf_register_info { p_id: id, hfinfo }
+}
+
+/// Field with the name of each value of `Id`.
+fn id_field<Id: Debug>(
+    id: *mut c_int,
+    name: &str,
+    abbrev: &str,
+    parse: impl Fn(u8) -> Option<Id>,
+) -> hf_register_info {
+    field(
+        id,
+        name,
+        abbrev,
+        ftenum_FT_UINT8,
+        field_display_e_BASE_HEX,
+        value_strings(parse),
+    )
+}
 
-unsafe extern "C" fn dissect_foo(
+unsafe extern "C" fn proto_register_joycon() {
+    proto = proto_register_protocol(
+        leak_str("Nintendo Switch controller"),
+        PROTOCOL_NAME.as_ptr() as *const c_char,
+        leak_str("joycon"),
+    );
+
+    let f = addr_of_mut!(fields);
+    let hf = vec![
+        id_field(
+            addr_of_mut!((*f).input_report),
+            "Input report",
+            "joycon.input_report",
+            |id| RawId::<InputReportId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).output_report),
+            "Output report",
+            "joycon.output_report",
+            |id| RawId::<OutputReportId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).subcmd),
+            "Subcommand",
+            "joycon.subcmd",
+            |id| RawId::<SubcommandId>::new(id).try_into(),
+        ),
+        field(
+            addr_of_mut!((*f).subcmd_ack),
+            "Subcommand acknowledgement",
+            "joycon.subcmd.ack",
+            ftenum_FT_UINT8,
+            field_display_e_BASE_HEX,
+            ptr::null(),
+        ),
+        field(
+            addr_of_mut!((*f).spi_offset),
+            "SPI offset",
+            "joycon.spi.offset",
+            ftenum_FT_UINT32,
+            field_display_e_BASE_HEX,
+            ptr::null(),
+        ),
+        field(
+            addr_of_mut!((*f).spi_size),
+            "SPI size",
+            "joycon.spi.size",
+            ftenum_FT_UINT8,
+            field_display_e_BASE_DEC,
+            ptr::null(),
+        ),
+        field(
+            addr_of_mut!((*f).spi_data),
+            "SPI data",
+            "joycon.spi.data",
+            ftenum_FT_BYTES,
+            field_display_e_BASE_NONE,
+            ptr::null(),
+        ),
+        field(
+            addr_of_mut!((*f).spi_status),
+            "SPI write status",
+            "joycon.spi.status",
+            ftenum_FT_UINT8,
+            field_display_e_BASE_HEX,
+            ptr::null(),
+        ),
+        id_field(
+            addr_of_mut!((*f).mcu_request),
+            "MCU request",
+            "joycon.mcu.request",
+            |id| RawId::<MCURequestId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).mcu_report),
+            "MCU report",
+            "joycon.mcu.report",
+            |id| RawId::<MCUReportId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).mcu_command),
+            "MCU command",
+            "joycon.mcu.command",
+            |id| RawId::<MCUCommandId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).mcu_subcmd),
+            "MCU subcommand",
+            "joycon.mcu.subcmd",
+            |id| RawId::<MCUSubCommandId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).mcu_mode),
+            "MCU mode",
+            "joycon.mcu.mode",
+            |id| RawId::<MCUMode>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).ir_request),
+            "IR request",
+            "joycon.ir.request",
+            |id| RawId::<IRRequestId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).accessory_command),
+            "Accessory command",
+            "joycon.accessory.command",
+            |id| RawId::<AccessoryCommandId>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).accessory_type),
+            "Accessory type",
+            "joycon.accessory.type",
+            |id| RawId::<AccessoryType>::new(id).try_into(),
+        ),
+        id_field(
+            addr_of_mut!((*f).accessory_item),
+            "Accessory item",
+            "joycon.accessory.item",
+            |id| RawId::<RingconItemId>::new(id).try_into(),
+        ),
+        field(
+            addr_of_mut!((*f).details),
+            "Details",
+            "joycon.details",
+            ftenum_FT_STRING,
+            field_display_e_BASE_NONE,
+            ptr::null(),
+        ),
+    ];
+    let hf = Box::leak(hf.into_boxed_slice());
+    proto_register_field_array(proto, hf.as_mut_ptr(), hf.len() as c_int);
+
+    let ett = Box::leak(Box::new([
+        addr_of_mut!(ett_joycon),
+        addr_of_mut!(ett_details),
+    ]));
+    proto_register_subtree_array(ett.as_ptr(), ett.len() as c_int);
+}
+
+unsafe extern "C" fn proto_reg_handoff_joycon() {
+    let handle = create_dissector_handle(Some(dissect_joycon), proto);
+    dissector_add_uint(leak_str("btl2cap.psm"), HID_INTERRUPT_PSM.into(), handle);
+}
+
+#[no_mangle]
+pub extern "C" fn plugin_register() {
+    // Kept by Wireshark.
+    let plugin = Box::leak(Box::new(proto_plugin {
+        register_protoinfo: Some(proto_register_joycon),
+        register_handoff: Some(proto_reg_handoff_joycon),
+    }));
+    unsafe { proto_register_plugin(plugin) };
+}
+
+unsafe extern "C" fn dissect_joycon(
     tvb: *mut tvbuff,
     packet_info: *mut _packet_info,
-    _proto_tree: *mut _proto_node,
+    tree: *mut _proto_node,
     _data: *mut c_void,
-) -> i32 {
-    dbg!("prout");
-    let name = CString::new("FOO").unwrap();
+) -> c_int {
+    let len = tvb_captured_length(tvb) as usize;
+    if len == 0 {
+        return 0;
+    }
+    let data = std::slice::from_raw_parts(tvb_get_ptr(tvb, 0, len as c_int), len);
+    let packet = match dissect::dissect(data) {
+        Some(packet) => packet,
+        None => return 0,
+    };
+
+    let cinfo = (*packet_info).cinfo;
     col_set_str(
-        (*packet_info).cinfo,
+        cinfo,
         COL_PROTOCOL.try_into().unwrap(),
-        name.as_ptr(),
+        PROTOCOL_NAME.as_ptr() as *const c_char,
     );
-    col_clear((*packet_info).cinfo, COL_INFO.try_into().unwrap());
-    tvb_captured_length(tvb).try_into().unwrap()
+    let item = proto_tree_add_item(tree, proto, tvb, 0, -1, ENC_NA);
+    add_items(tvb, proto_item_add_subtree(item, ett_joycon), &packet.items);
+    let info = CString::new(packet.info).unwrap();
+    // Copied by Wireshark.
+    col_add_str(cinfo, COL_INFO.try_into().unwrap(), info.as_ptr());
+    len as c_int
 }
 
-unsafe extern "C" fn proto_register_foo() {
-    dbg!("pat");
-    let name = CString::new("FOO Protocol").unwrap();
-    let short_name = CString::new("FOO").unwrap();
-    let filter_name = CString::new("foo").unwrap();
-    proto = proto_register_protocol(name.as_ptr(), short_name.as_ptr(), filter_name.as_ptr());
+/// Adds the decoded items to the protocol tree.
+unsafe fn add_items(tvb: *mut tvbuff, tree: *mut proto_tree, items: &[Item]) {
+    for item in items {
+        let (start, len) = (item.start as c_int, item.len as c_int);
+        match item.kind {
+            ItemKind::Field(field) => {
+                let (hf, encoding) = header_field(field);
+                proto_tree_add_item(tree, hf, tvb, start, len, encoding);
+            }
+            ItemKind::Details {
+                ref text,
+                ref items,
+            } => {
+                let text = CString::new(text.as_str()).unwrap();
+                let node =
+                    proto_tree_add_string(tree, fields.details, tvb, start, len, text.as_ptr());
+                add_items(tvb, proto_item_add_subtree(node, ett_details), items);
+            }
+        }
+    }
 }
 
-unsafe extern "C" fn proto_reg_handoff_foo() {
-    dbg!("fdsfds");
-    let handle = create_dissector_handle(Some(dissect_foo), proto);
-    let psm = CString::new("btl2cap.psm").unwrap();
-    dissector_add_uint(psm.as_ptr(), 17, handle);
-    dissector_add_uint(psm.as_ptr(), 19, handle);
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn plugin_register() {
-    let plugin_foo = proto_plugin {
-        register_protoinfo: Some(proto_register_foo),
-        register_handoff: Some(proto_reg_handoff_foo),
-    };
-    proto_register_plugin(&plugin_foo);
-    dbg!("sfds");
+/// ID and encoding of a field.
+unsafe fn header_field(field: Field) -> (c_int, c_uint) {
+    match field {
+        Field::InputReport => (fields.input_report, ENC_NA),
+        Field::OutputReport => (fields.output_report, ENC_NA),
+        Field::Subcmd => (fields.subcmd, ENC_NA),
+        Field::SubcmdAck => (fields.subcmd_ack, ENC_NA),
+        Field::SPIOffset => (fields.spi_offset, ENC_LITTLE_ENDIAN),
+        Field::SPISize => (fields.spi_size, ENC_NA),
+        Field::SPIData => (fields.spi_data, ENC_NA),
+        Field::SPIStatus => (fields.spi_status, ENC_NA),
+        Field::MCURequest => (fields.mcu_request, ENC_NA),
+        Field::MCUReport => (fields.mcu_report, ENC_NA),
+        Field::MCUCommand => (fields.mcu_command, ENC_NA),
+        Field::MCUSubcmd => (fields.mcu_subcmd, ENC_NA),
+        Field::MCUMode => (fields.mcu_mode, ENC_NA),
+        Field::IRRequest => (fields.ir_request, ENC_NA),
+        Field::AccessoryCommand => (fields.accessory_command, ENC_NA),
+        Field::AccessoryType => (fields.accessory_type, ENC_NA),
+        Field::AccessoryItem => (fields.accessory_item, ENC_NA),
+    }
 }